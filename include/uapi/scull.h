@@ -0,0 +1,361 @@
+/* SPDX-License-Identifier: GPL-2.0 WITH Linux-syscall-note */
+/*
+ * Userspace interface of the Rust scull driver: ioctl numbers, their
+ * constants and argument structs.
+ *
+ * scull.rs defines the same values for the driver. The rust_scull_uapi
+ * KUnit suite checks every value and struct layout below against it, so a
+ * change on either side must be made on both.
+ */
+#ifndef _UAPI_SCULL_H
+#define _UAPI_SCULL_H
+
+#include <linux/ioctl.h>
+#include <linux/types.h>
+
+#define SCULL_IOC_MAGIC 'k'
+
+/* Longest pattern SCULL_P_IOCWAITPATTERN accepts. */
+#define SCULL_PATTERN_MAX 16
+/* Size of the SCULL_IOCSLABEL buffer. */
+#define SCULL_LABEL_LEN 64
+
+/* A byte range passed to the range ioctls. */
+struct scull_range {
+	__u64 offset;
+	__u64 len;
+};
+
+/*
+ * Device statistics returned by SCULL_IOCGSTATS. The caller sets size to
+ * sizeof its struct; an older caller gets just the fields it knows about.
+ */
+struct scull_stats {
+	__u32 size;
+	__u32 reserved;
+	__u64 bytes_read;
+	__u64 bytes_written;
+	__u64 open_count;
+};
+
+/* Size of the first version of struct scull_stats. */
+#define SCULL_STATS_SIZE_VER0 32
+
+/* The process behind the last write, returned by SCULL_IOCGLASTWRITER. */
+struct scull_writer {
+	__s32 pid;
+	__u32 uid;
+};
+
+/* Argument of SCULL_IOCLOCK and SCULL_IOCUNLOCK. */
+struct scull_lock_req {
+	__u64 offset;
+	__u64 len;
+	__u32 flags;
+	__u32 reserved;
+};
+
+/* struct scull_lock_req flags. */
+#define SCULL_LOCK_EXCLUSIVE	1	/* exclusive rather than shared */
+#define SCULL_LOCK_NONBLOCK	2	/* fail with EAGAIN instead of waiting */
+
+/* struct scull_log_entry op values. */
+#define SCULL_LOG_READ	1
+#define SCULL_LOG_WRITE	2
+#define SCULL_LOG_IOCTL	3
+
+/*
+ * One operation log record. For ioctls offset holds the command and len
+ * the argument. result is the bytes transferred or ioctl return value, or a
+ * negative errno.
+ */
+struct scull_log_entry {
+	__u64 timestamp_ns;
+	__u64 offset;
+	__u64 len;
+	__s64 result;
+	__s32 pid;
+	__u32 op;
+};
+
+/* Argument of SCULL_IOCGETLOG: an array of count entries at buf. */
+struct scull_log_req {
+	__u64 buf;
+	__u64 count;
+};
+
+/* Argument of SCULL_IOCLIST: an array of count struct scull_dev_info at buf. */
+struct scull_list_req {
+	__u64 buf;
+	__u64 count;
+};
+
+/* One device in the SCULL_IOCLIST listing. name is NUL-padded. */
+struct scull_dev_info {
+	__u8 name[16];
+	__u64 size;
+	__u64 quantum;
+	__u64 qset;
+};
+
+/* A run of allocated data, in whole quanta clamped to the end of the data. */
+struct scull_extent {
+	__u64 logical_offset;
+	__u64 length;
+	__u32 flags;
+	__u32 reserved;
+};
+
+/* struct scull_extent flags: no allocated data follows this extent. */
+#define SCULL_EXTENT_LAST 1
+
+/*
+ * Argument of SCULL_IOCFIEMAP: an array of count extents at buf. On return
+ * mapped is the number of extents written and next the offset to pass as
+ * start in the next call, or the end of the data once it is all mapped.
+ */
+struct scull_fiemap {
+	__u64 start;
+	__u64 buf;
+	__u32 count;
+	__u32 mapped;
+	__u64 next;
+};
+
+/* struct scull_event flags: older records were dropped before this one. */
+#define SCULL_EVENT_OVERFLOW 1
+
+/*
+ * One record read from /dev/scull.events, emitted per successful write or
+ * reset. A reset is reported with offset and len both 0.
+ */
+struct scull_event {
+	__u64 offset;
+	__u64 len;
+	__u64 generation;
+	__u64 timestamp_ns;
+	__s32 pid;
+	__u32 flags;
+};
+
+/* Argument of SCULL_IOCPEEK: copy len bytes at offset to the buffer buf. */
+struct scull_peek {
+	__u64 offset;
+	__u64 buf;
+	__u64 len;
+};
+
+/* Argument of SCULL_IOCSGEOMETRY and result of SCULL_IOCGGEOMETRY. */
+struct scull_geometry {
+	__u64 quantum;
+	__u64 qset;
+	__u32 flags;
+	__u32 reserved;
+};
+
+/* struct scull_geometry flags: empty the device first, as SCULL_IOCRESET. */
+#define SCULL_GEOMETRY_FORCE 1
+
+/* Argument of SCULL_P_IOCWAITPATTERN: the first len bytes of pattern. */
+struct scull_pattern {
+	__u8 pattern[SCULL_PATTERN_MAX];
+	__u32 len;
+	__u32 reserved;
+};
+
+/*
+ * Argument of SCULL_IOCREADEX: read up to len bytes at offset into buf. On
+ * return copied is the bytes copied, and more is 1 if data remains past
+ * offset + copied.
+ */
+struct scull_read_ex {
+	__u64 offset;
+	__u64 buf;
+	__u64 len;
+	__u64 copied;
+	__u32 more;
+	__u32 reserved;
+};
+
+/*
+ * Sizes of the driver's internal structures, returned by SCULL_IOCGSIZES.
+ * The values are build-dependent; only the layout is part of the ABI.
+ */
+struct scull_sizes {
+	__u64 qset;
+	__u64 qset_entry;
+	__u64 dirty_word;
+	__u64 dev_data;
+};
+
+/*
+ * Argument of SCULL_IOCCHECK. Each call checks a bounded number of qset
+ * nodes from node cursor on. On success cursor is where to resume, or 0
+ * once the whole chain has been checked. On a violation code names the
+ * check and item and s_pos locate it.
+ */
+struct scull_check {
+	__u64 cursor;
+	__u64 item;
+	__u64 s_pos;
+	__u32 code;
+	__u32 reserved;
+};
+
+/* SCULL_IOCCHECK codes. */
+#define SCULL_CHECK_NODE_COUNT		1
+#define SCULL_CHECK_QSET_LEN		2
+#define SCULL_CHECK_QUANTUM_LEN		3
+#define SCULL_CHECK_DIRTY_LEN		4
+#define SCULL_CHECK_DIRTY_PAST_SIZE	5
+#define SCULL_CHECK_SIZE		6
+#define SCULL_CHECK_PROTECTED		7
+#define SCULL_CHECK_LOCKS		8
+
+/*
+ * Result of SCULL_IOCGFILLSTATS. Sized like struct scull_stats.
+ */
+struct scull_fill_stats {
+	__u32 size;
+	__u32 reserved;
+	__u64 quanta;
+	__u64 full;
+	__u64 partial;
+	__u64 valid_bytes;
+	__u64 padding_bytes;
+};
+
+/* Size of the first version of struct scull_fill_stats. */
+#define SCULL_FILL_STATS_SIZE_VER0 48
+
+/*
+ * Argument of SCULL_C_IOCSMEMBERS. A member is 0 for /dev/scull or n for
+ * /dev/scull_dyn<n - 1>.
+ */
+struct scull_concat_members {
+	__u32 first;
+	__u32 second;
+};
+
+/* Result of SCULL_IOCGVERSION. features holds SCULL_FEAT_* bits. */
+struct scull_version {
+	__u32 major;
+	__u32 minor;
+	__u32 patch;
+	__u32 reserved;
+	__u64 features;
+};
+
+/* Version of the ioctl interface. */
+#define SCULL_VERSION_MAJOR	1
+#define SCULL_VERSION_MINOR	1
+#define SCULL_VERSION_PATCH	0
+
+/* struct scull_version features. */
+#define SCULL_FEAT_VERSIONS	(1ULL << 0)
+#define SCULL_FEAT_FORK		(1ULL << 1)
+#define SCULL_FEAT_MIGRATE	(1ULL << 2)
+#define SCULL_FEAT_LOG		(1ULL << 3)
+#define SCULL_FEAT_FIEMAP	(1ULL << 4)
+#define SCULL_FEAT_INJECT	(1ULL << 5)
+#define SCULL_FEAT_POISON	(1ULL << 6)
+#define SCULL_FEAT_PARANOID	(1ULL << 7)
+#define SCULL_FEAT_PARTITIONS	(1ULL << 8)
+#define SCULL_FEAT_COMPACT	(1ULL << 9)
+
+/* SCULL_IOCRESET argument: also discard write-protected ranges. */
+#define SCULL_RESET_FORCE	1
+/* SCULL_IOCSMODE bits. */
+#define SCULL_MODE_READ		1
+#define SCULL_MODE_WRITE	2
+/* SCULL_IOCSSENTINEL argument: no sentinel. */
+#define SCULL_SENTINEL_NONE	0x100
+/* SCULL_IOCSGFP arguments. */
+#define SCULL_GFP_KERNEL	0
+#define SCULL_GFP_ATOMIC	1
+/* SCULL_IOCSMAXGAP argument: writes may open holes of any size. */
+#define SCULL_GAP_UNLIMITED	(~(__u64)0)
+/* SCULL_IOCGBACKEND results. */
+#define SCULL_BACKEND_QSET	0
+#define SCULL_BACKEND_FLAT	1
+/* SCULL_IOCFORK arguments: the backend of the copy. */
+#define SCULL_FORK_SAME		0
+#define SCULL_FORK_QSET		1
+#define SCULL_FORK_FLAT		2
+
+/* /dev/scull and /dev/scull_dyn<N>; see scull.rs for each command. */
+#define SCULL_IOCRESET		_IO(SCULL_IOC_MAGIC, 0)
+#define SCULL_IOCSWRITEONCE	_IO(SCULL_IOC_MAGIC, 1)
+#define SCULL_IOCGWRITEONCE	_IO(SCULL_IOC_MAGIC, 2)
+#define SCULL_IOCSUIDQUOTA	_IO(SCULL_IOC_MAGIC, 3)
+#define SCULL_IOCPROTECT	_IOW(SCULL_IOC_MAGIC, 4, struct scull_range)
+#define SCULL_IOCUNPROTECT	_IOW(SCULL_IOC_MAGIC, 5, struct scull_range)
+#define SCULL_IOCSMAXNODES	_IO(SCULL_IOC_MAGIC, 6)
+#define SCULL_IOCPEEK		_IOW(SCULL_IOC_MAGIC, 7, struct scull_peek)
+#define SCULL_IOCSFILL		_IO(SCULL_IOC_MAGIC, 8)
+#define SCULL_IOCGFILL		_IO(SCULL_IOC_MAGIC, 9)
+#define SCULL_IOCSCHUNKED	_IO(SCULL_IOC_MAGIC, 10)
+#define SCULL_IOCSAPPENDONLY	_IO(SCULL_IOC_MAGIC, 11)
+#define SCULL_IOCGAPPENDONLY	_IO(SCULL_IOC_MAGIC, 12)
+#define SCULL_IOCGSTATS		_IOWR(SCULL_IOC_MAGIC, 13, __u8[SCULL_STATS_SIZE_VER0])
+#define SCULL_IOCCOMMIT		_IO(SCULL_IOC_MAGIC, 14)
+#define SCULL_IOCROLLBACK	_IO(SCULL_IOC_MAGIC, 15)
+#define SCULL_IOCDROPCACHE	_IO(SCULL_IOC_MAGIC, 16)
+#define SCULL_IOCGETLOG		_IOW(SCULL_IOC_MAGIC, 17, struct scull_log_req)
+#define SCULL_IOCCLEARLOG	_IO(SCULL_IOC_MAGIC, 18)
+#define SCULL_IOCSLOGGING	_IO(SCULL_IOC_MAGIC, 19)
+#define SCULL_IOCSMODE		_IO(SCULL_IOC_MAGIC, 20)
+#define SCULL_IOCGMODE		_IO(SCULL_IOC_MAGIC, 21)
+#define SCULL_IOCFORK		_IO(SCULL_IOC_MAGIC, 22)
+#define SCULL_IOCINJECT		_IO(SCULL_IOC_MAGIC, 23)	/* debug kernels */
+#define SCULL_IOCLOCK		_IOW(SCULL_IOC_MAGIC, 25, struct scull_lock_req)
+#define SCULL_IOCUNLOCK		_IOW(SCULL_IOC_MAGIC, 26, struct scull_lock_req)
+#define SCULL_IOCMIGRATE	_IO(SCULL_IOC_MAGIC, 27)
+#define SCULL_IOCGMIGRATE	_IO(SCULL_IOC_MAGIC, 28)
+#define SCULL_IOCSSENTINEL	_IO(SCULL_IOC_MAGIC, 29)
+#define SCULL_IOCGSENTINEL	_IO(SCULL_IOC_MAGIC, 30)
+#define SCULL_IOCFIEMAP		_IOWR(SCULL_IOC_MAGIC, 31, struct scull_fiemap)
+#define SCULL_IOCEXTEND		_IO(SCULL_IOC_MAGIC, 32)
+#define SCULL_IOCGLASTWRITER	_IOR(SCULL_IOC_MAGIC, 33, struct scull_writer)
+#define SCULL_IOCSALIGN		_IO(SCULL_IOC_MAGIC, 34)
+#define SCULL_IOCGALIGN		_IO(SCULL_IOC_MAGIC, 35)
+#define SCULL_IOCCOMPACT	_IO(SCULL_IOC_MAGIC, 36)
+#define SCULL_IOCGFOOTPRINT	_IOR(SCULL_IOC_MAGIC, 37, __u64)
+#define SCULL_IOCGGEN		_IOR(SCULL_IOC_MAGIC, 39, __u64)
+#define SCULL_IOCREADEX		_IOWR(SCULL_IOC_MAGIC, 40, struct scull_read_ex)
+#define SCULL_IOCSGEOMETRY	_IOW(SCULL_IOC_MAGIC, 41, struct scull_geometry)
+#define SCULL_IOCGGEOMETRY	_IOR(SCULL_IOC_MAGIC, 42, struct scull_geometry)
+#define SCULL_IOCREQUANTUM	_IOW(SCULL_IOC_MAGIC, 43, struct scull_geometry)
+#define SCULL_IOCGMAXNODES	_IO(SCULL_IOC_MAGIC, 45)
+#define SCULL_IOCGNODES		_IO(SCULL_IOC_MAGIC, 46)
+#define SCULL_IOCSGFP		_IO(SCULL_IOC_MAGIC, 47)
+#define SCULL_IOCGGFP		_IO(SCULL_IOC_MAGIC, 48)
+#define SCULL_IOCGSIZES		_IOR(SCULL_IOC_MAGIC, 49, struct scull_sizes)
+#define SCULL_IOCGFILLSTATS	_IOWR(SCULL_IOC_MAGIC, 51, __u8[SCULL_FILL_STATS_SIZE_VER0])
+#define SCULL_IOCSRECORD	_IO(SCULL_IOC_MAGIC, 52)
+#define SCULL_IOCGRECORD	_IO(SCULL_IOC_MAGIC, 53)
+#define SCULL_IOCSHUTDOWN	_IO(SCULL_IOC_MAGIC, 54)	/* also scullpipe */
+#define SCULL_IOCCHECK		_IOWR(SCULL_IOC_MAGIC, 55, struct scull_check)
+#define SCULL_IOCGCREATED	_IOR(SCULL_IOC_MAGIC, 56, __u64)
+#define SCULL_IOCSMAXGAP	_IOW(SCULL_IOC_MAGIC, 57, __u64)
+#define SCULL_IOCGMAXGAP	_IOR(SCULL_IOC_MAGIC, 58, __u64)
+#define SCULL_IOCLIST		_IOW(SCULL_IOC_MAGIC, 59, struct scull_list_req)
+#define SCULL_IOCINJECTCOPY	_IO(SCULL_IOC_MAGIC, 60)	/* debug kernels */
+#define SCULL_IOCGRUNNINGCRC	_IOR(SCULL_IOC_MAGIC, 61, __u64)
+#define SCULL_IOCDISCARDFRONT	_IO(SCULL_IOC_MAGIC, 62)
+#define SCULL_IOCSMAXOPENS	_IO(SCULL_IOC_MAGIC, 63)
+#define SCULL_IOCGMAXOPENS	_IO(SCULL_IOC_MAGIC, 64)
+#define SCULL_IOCSLABEL		_IOW(SCULL_IOC_MAGIC, 65, __u8[SCULL_LABEL_LEN])
+#define SCULL_IOCGLABEL		_IOR(SCULL_IOC_MAGIC, 66, __u8[SCULL_LABEL_LEN])
+#define SCULL_IOCGVERSION	_IOR(SCULL_IOC_MAGIC, 67, struct scull_version) /* also scullpipe */
+#define SCULL_IOCGBACKEND	_IO(SCULL_IOC_MAGIC, 68)
+
+/* /dev/scullpipe */
+#define SCULL_P_IOCSDEADLINE	_IO(SCULL_IOC_MAGIC, 24)
+#define SCULL_P_IOCSNORESTART	_IO(SCULL_IOC_MAGIC, 38)
+#define SCULL_P_IOCWAITPATTERN	_IOW(SCULL_IOC_MAGIC, 44, struct scull_pattern)
+
+/* /dev/scull.concat */
+#define SCULL_C_IOCSMEMBERS	_IOW(SCULL_IOC_MAGIC, 50, struct scull_concat_members)
+
+#endif /* _UAPI_SCULL_H */
@@ -5,15 +5,34 @@
 
 // #![no_std]
 
+// A minimal build (`make SCULL_MINIMAL=y`) compiles out the advanced ioctls,
+// leaving their helpers unused.
+#![cfg_attr(scull_minimal, allow(dead_code))]
 
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use kernel::{
-    alloc::{flags::GFP_KERNEL, KBox, KVec},
-    fs::file::File,
+    alloc::{
+        flags::{GFP_ATOMIC, GFP_KERNEL},
+        Flags, KBox, KVVec, KVec,
+    },
+    bindings, c_str, container_of,
+    cpu::{nr_cpu_ids, CpuId},
+    fs::{file, file::File, Kiocb},
+    ioctl::{_IO, _IOR, _IOW, _IOWR},
     iov::{IovIterDest, IovIterSource},
     miscdevice::{MiscDevice, MiscDeviceOptions, MiscDeviceRegistration},
-    new_mutex,
+    new_condvar, new_mutex, new_spinlock,
     prelude::*,
-    sync::{Arc, ArcBorrow, Mutex},
+    str::CStr,
+    sync::{
+        global_lock, static_lock_class, Arc, CondVar, CondVarTimeoutResult, LockClassKey, Mutex,
+        SpinLock,
+    },
+    time::{msecs_to_jiffies, Jiffies},
+    workqueue::{self, impl_has_work, new_work, Work, WorkItem},
+    transmute::{AsBytes, FromBytes},
+    types::ForeignOwnable,
+    uaccess::{UserPtr, UserSlice, UserSliceWriter},
 };
 
 module! {
@@ -22,361 +41,6857 @@ module! {
     authors: ["Alessandro Rubini, Jonathan Corbet (Ported to Rust)"],
     description: "Rust port of the Linux Device Drivers scull example",
     license: "Dual BSD/GPL",
+    params: {
+        quantum: usize {
+            default: 4000,
+            description: "Quantum size in bytes of new and reset devices",
+        },
+        qset: usize {
+            default: 1000,
+            description: "Quanta per qset node of new and reset devices",
+        },
+        max_nodes: usize {
+            default: 0,
+            description: "Maximum qset nodes per new device (0 = unlimited)",
+        },
+        keep_versions: usize {
+            default: 0,
+            description: "Number of committed versions retained per device (0 = disabled)",
+        },
+        append_only: u32 {
+            default: 0,
+            description: "Create the device in append-only mode (0 = off, 1 = on)",
+        },
+        uid_quota: u64 {
+            default: 0,
+            description: "Maximum bytes of quanta each uid may allocate (0 = unlimited)",
+        },
+        compact_interval_ms: u32 {
+            default: 0,
+            description: "Interval between background compactions in ms (0 = disabled)",
+        },
+        partitions: u32 {
+            default: 0,
+            description: "Number of /dev/scull0pM partitions of the device (0-8)",
+        },
+        partition_size: u64 {
+            default: 1048576,
+            description: "Size in bytes of each partition",
+        },
+        prealloc_head: u32 {
+            default: 0,
+            description: "Allocate the head qset node on open for writing (0 = off, 1 = on)",
+        },
+        paranoid: u32 {
+            default: 0,
+            description: "Check device invariants after every write and ioctl (0 = off, 1 = on)",
+        },
+        poison: u32 {
+            default: 0,
+            description: "Fill quanta and qset arrays with 0x6b as they are freed (debug kernels)",
+        },
+        stress_threads: u32 {
+            default: 0,
+            description: "Number of soak-test workers to run at load (0-32, 0 = none)",
+        },
+        stress_seconds: u32 {
+            default: 10,
+            description: "How long each soak-test worker runs, in seconds",
+        },
+        stress_mode: u32 {
+            default: 0,
+            description: "Soak-test mode (0 = a scratch device per worker, 1 = torture on one shared device)",
+        },
+        stress_readers: u32 {
+            default: 2,
+            description: "Number of verifying readers in torture mode (0-32)",
+        },
+        backend: u32 {
+            default: 0,
+            description: "Storage of new devices (0 = qset nodes, 1 = one flat buffer)",
+        },
+    },
 }
-const SCULL_QUANTUM_DEFAULT: usize = 4000;
-const SCULL_QSET_DEFAULT: usize = 1000;
 
-// --- Data Structures ---
+/// Returns the quantum and qset size a new or reset device starts with.
+///
+/// The parameters are read-only, so a device created later can never see
+/// different values than one created earlier, and existing devices keep
+/// whatever geometry they have. Validated once in `ScullModule::init`.
+fn default_geometry() -> (usize, usize) {
+    (
+        *module_parameters::quantum.value(),
+        *module_parameters::qset.value(),
+    )
+}
 
-/// Represents a "quantum" - a single block of data.
-type Quantum = KVec<u8>;
+// --- ioctl Commands ---
 
-/// Represents a "qset" - an array of quanta.
-type QSet = KVec<Option<Quantum>>;
+const SCULL_IOC_MAGIC: u32 = b'k' as u32;
 
-/// Represents a node in the linked list of qsets.
-struct ScullQset {
-    data: Option<QSet>,
-    next: Option<KBox<ScullQset>>,
+/// Empties the device.
+///
+/// A reset is linearizable against reads and writes: each of those runs
+/// entirely under the device lock, so it completes wholly before the reset
+/// (and its data is discarded) or starts after it (and sees an empty device).
+/// A write never lands partly in the old contents and partly in the new.
+const SCULL_IOCRESET: u32 = _IO(SCULL_IOC_MAGIC, 0);
+/// The bare command number `SCULL_IOCRESET` used to have, still accepted.
+const SCULL_IOCRESET_LEGACY: u32 = 0;
+/// Enables (arg != 0) or disables (arg == 0) write-once mode. Disabling it
+/// requires CAP_SYS_ADMIN, since it lifts the immutability of written data.
+const SCULL_IOCSWRITEONCE: u32 = _IO(SCULL_IOC_MAGIC, 1);
+/// Returns 1 if write-once mode is enabled, 0 otherwise.
+const SCULL_IOCGWRITEONCE: u32 = _IO(SCULL_IOC_MAGIC, 2);
+/// Sets the per-uid quota in bytes (0 = unlimited). Requires CAP_SYS_ADMIN.
+const SCULL_IOCSUIDQUOTA: u32 = _IO(SCULL_IOC_MAGIC, 3);
+/// Marks a `ScullRange` read-only.
+const SCULL_IOCPROTECT: u32 = _IOW::<ScullRange>(SCULL_IOC_MAGIC, 4);
+/// Removes write protection from a `ScullRange`. Requires CAP_SYS_ADMIN.
+const SCULL_IOCUNPROTECT: u32 = _IOW::<ScullRange>(SCULL_IOC_MAGIC, 5);
+
+/// Sets the maximum number of qset nodes (0 = unlimited). Needs
+/// CAP_SYS_ADMIN; lowering it below the current count only stops growth.
+const SCULL_IOCSMAXNODES: u32 = _IO(SCULL_IOC_MAGIC, 6);
+
+/// Copies bytes at an arbitrary offset without moving the file position.
+const SCULL_IOCPEEK: u32 = _IOW::<ScullPeek>(SCULL_IOC_MAGIC, 7);
+
+/// Sets the byte that holes read back as. Fails with EBUSY unless the device is empty.
+const SCULL_IOCSFILL: u32 = _IO(SCULL_IOC_MAGIC, 8);
+/// Returns the current fill byte.
+const SCULL_IOCGFILL: u32 = _IO(SCULL_IOC_MAGIC, 9);
+
+/// Enables (arg != 0) or disables (arg == 0) one-quantum-per-read mode.
+const SCULL_IOCSCHUNKED: u32 = _IO(SCULL_IOC_MAGIC, 10);
+
+/// Enables (arg != 0) or disables (arg == 0) append-only mode. Requires CAP_SYS_ADMIN.
+const SCULL_IOCSAPPENDONLY: u32 = _IO(SCULL_IOC_MAGIC, 11);
+/// Returns 1 if append-only mode is enabled, 0 otherwise.
+const SCULL_IOCGAPPENDONLY: u32 = _IO(SCULL_IOC_MAGIC, 12);
+
+/// Copies a `ScullStats` snapshot to userspace, see `write_user_sized`.
+const SCULL_IOCGSTATS: u32 = _IOWR::<[u8; SCULL_STATS_SIZE_VER0]>(SCULL_IOC_MAGIC, 13);
+
+/// Retains a copy of the current contents (see the `keep_versions` parameter).
+const SCULL_IOCCOMMIT: u32 = _IO(SCULL_IOC_MAGIC, 14);
+/// Swaps the live contents with retained version `arg` (0 = most recent).
+const SCULL_IOCROLLBACK: u32 = _IO(SCULL_IOC_MAGIC, 15);
+
+/// Frees all quanta and nodes while keeping the device size. Fails with
+/// EPERM in append-only or write-once mode or with protected ranges.
+const SCULL_IOCDROPCACHE: u32 = _IO(SCULL_IOC_MAGIC, 16);
+
+/// Copies the operation log, newest first, into a `ScullLogReq` buffer.
+const SCULL_IOCGETLOG: u32 = _IOW::<ScullLogReq>(SCULL_IOC_MAGIC, 17);
+/// Discards all operation log entries.
+const SCULL_IOCCLEARLOG: u32 = _IO(SCULL_IOC_MAGIC, 18);
+/// Enables (arg != 0) or disables (arg == 0) operation logging.
+const SCULL_IOCSLOGGING: u32 = _IO(SCULL_IOC_MAGIC, 19);
+
+/// Sets the device's `SCULL_MODE_*` bits. Requires CAP_SYS_ADMIN.
+const SCULL_IOCSMODE: u32 = _IO(SCULL_IOC_MAGIC, 20);
+/// Returns the device's `SCULL_MODE_*` bits.
+const SCULL_IOCGMODE: u32 = _IO(SCULL_IOC_MAGIC, 21);
+
+/// Logical permission bits, enforced independently of the file open flags.
+/// Without `SCULL_MODE_READ`, reads, `SCULL_IOCPEEK` and `SCULL_IOCREADEX`
+/// fail with EACCES; without `SCULL_MODE_WRITE`, so do writes and every
+/// ioctl that changes the contents or size.
+const SCULL_MODE_READ: usize = 1;
+const SCULL_MODE_WRITE: usize = 2;
+
+/// Copies the device into a new dynamic device, returning its index `N`
+/// (`/dev/scull_dynN`). `arg` is a `SCULL_FORK_*` value choosing the copy's
+/// storage backend; converting fails with EFBIG if the data does not fit.
+const SCULL_IOCFORK: u32 = _IO(SCULL_IOC_MAGIC, 22);
+
+/// Makes the next data-path allocation fail with ENOMEM (debug kernels only).
+#[cfg(CONFIG_DEBUG_KERNEL)]
+const SCULL_IOCINJECT: u32 = _IO(SCULL_IOC_MAGIC, 23);
+/// Makes the next `arg` user copies of read and write data move only half
+/// the bytes asked for, rounded down (debug kernels only).
+#[cfg(CONFIG_DEBUG_KERNEL)]
+const SCULL_IOCINJECTCOPY: u32 = _IO(SCULL_IOC_MAGIC, 60);
+
+/// scullpipe: sets this handle's read deadline in milliseconds (0 = none).
+const SCULL_P_IOCSDEADLINE: u32 = _IO(SCULL_IOC_MAGIC, 24);
+
+/// Acquires an advisory lock on a `ScullLockReq` range.
+const SCULL_IOCLOCK: u32 = _IOW::<ScullLockReq>(SCULL_IOC_MAGIC, 25);
+/// Releases this handle's advisory locks overlapping a `ScullLockReq` range.
+const SCULL_IOCUNLOCK: u32 = _IOW::<ScullLockReq>(SCULL_IOC_MAGIC, 26);
+
+/// `ScullLockReq::flags`: take the lock exclusively rather than shared.
+const SCULL_LOCK_EXCLUSIVE: u32 = 1;
+/// `ScullLockReq::flags`: fail with EAGAIN instead of waiting.
+const SCULL_LOCK_NONBLOCK: u32 = 2;
+
+/// Starts migrating the contents to `arg`-byte quanta in the background.
+const SCULL_IOCMIGRATE: u32 = _IO(SCULL_IOC_MAGIC, 27);
+/// Returns the percentage of the running migration completed (100 when idle).
+const SCULL_IOCGMIGRATE: u32 = _IO(SCULL_IOC_MAGIC, 28);
+
+/// Sets the end-of-data sentinel byte to `arg`, or disables it for
+/// `SCULL_SENTINEL_NONE`. Only allowed while the device is empty.
+const SCULL_IOCSSENTINEL: u32 = _IO(SCULL_IOC_MAGIC, 29);
+/// Returns the sentinel byte, or `SCULL_SENTINEL_NONE` if disabled.
+const SCULL_IOCGSENTINEL: u32 = _IO(SCULL_IOC_MAGIC, 30);
+
+/// `SCULL_IOCSSENTINEL` argument: no sentinel.
+const SCULL_SENTINEL_NONE: usize = 0x100;
+
+/// Maps allocated data from `ScullFiemap::start` into extent records,
+/// returning how many were written.
+const SCULL_IOCFIEMAP: u32 = _IOWR::<ScullFiemap>(SCULL_IOC_MAGIC, 31);
+
+/// `ScullExtent::flags`: no allocated data follows this extent.
+const SCULL_EXTENT_LAST: u32 = 1;
+
+/// Atomically grows the device to `arg` bytes if it is smaller, leaving a
+/// hole. Returns the resulting size.
+const SCULL_IOCEXTEND: u32 = _IO(SCULL_IOC_MAGIC, 32);
+/// Copies the `ScullWriter` of the last successful write to userspace.
+const SCULL_IOCGLASTWRITER: u32 = _IOR::<ScullWriter>(SCULL_IOC_MAGIC, 33);
+/// Requires reads and writes to be aligned to `arg` bytes, a power of two no
+/// larger than the quantum, or lifts the requirement if `arg == 0`.
+const SCULL_IOCSALIGN: u32 = _IO(SCULL_IOC_MAGIC, 34);
+/// Returns the required I/O alignment, 0 if none.
+const SCULL_IOCGALIGN: u32 = _IO(SCULL_IOC_MAGIC, 35);
+/// Frees quanta holding nothing but the fill byte, returning the bytes freed.
+const SCULL_IOCCOMPACT: u32 = _IO(SCULL_IOC_MAGIC, 36);
+/// Copies the kernel memory held by the device's data, as a `u64`.
+const SCULL_IOCGFOOTPRINT: u32 = _IOR::<u64>(SCULL_IOC_MAGIC, 37);
+/// scullpipe: makes this handle's interrupted waits fail with EINTR (arg != 0)
+/// instead of being restarted (arg == 0, the default).
+const SCULL_P_IOCSNORESTART: u32 = _IO(SCULL_IOC_MAGIC, 38);
+/// Copies the device's generation, a `u64` bumped by every change to the
+/// contents or size, to userspace.
+const SCULL_IOCGGEN: u32 = _IOR::<u64>(SCULL_IOC_MAGIC, 39);
+/// Reads like read(2) at `ScullReadEx::offset`, also reporting whether data
+/// remains past the bytes returned. Returns the number of bytes copied.
+const SCULL_IOCREADEX: u32 = _IOWR::<ScullReadEx>(SCULL_IOC_MAGIC, 40);
+/// Sets the quantum and qset size from a `ScullGeometry`. Fails with EBUSY
+/// while the device holds data unless `SCULL_GEOMETRY_FORCE` is given.
+const SCULL_IOCSGEOMETRY: u32 = _IOW::<ScullGeometry>(SCULL_IOC_MAGIC, 41);
+/// Copies the current `ScullGeometry` to userspace.
+const SCULL_IOCGGEOMETRY: u32 = _IOR::<ScullGeometry>(SCULL_IOC_MAGIC, 42);
+
+/// `ScullGeometry::flags`: empty the device first, as `SCULL_IOCRESET` would.
+const SCULL_GEOMETRY_FORCE: u32 = 1;
+
+/// Re-chunks the existing contents into the `ScullGeometry` given, keeping
+/// them intact. `flags` must be 0.
+const SCULL_IOCREQUANTUM: u32 = _IOW::<ScullGeometry>(SCULL_IOC_MAGIC, 43);
+/// scullpipe: waits until a `ScullPattern` appears in the buffered data and
+/// returns its distance from the read position, without consuming anything.
+const SCULL_P_IOCWAITPATTERN: u32 = _IOW::<ScullPattern>(SCULL_IOC_MAGIC, 44);
+/// Returns the maximum number of qset nodes (0 = unlimited).
+const SCULL_IOCGMAXNODES: u32 = _IO(SCULL_IOC_MAGIC, 45);
+/// Returns the number of qset nodes currently allocated.
+const SCULL_IOCGNODES: u32 = _IO(SCULL_IOC_MAGIC, 46);
+/// Selects the allocation flags for device storage: `SCULL_GFP_KERNEL` or
+/// `SCULL_GFP_ATOMIC`.
+///
+/// GFP_ATOMIC never sleeps or reclaims and may dip into the emergency
+/// reserves, so writes fail with ENOMEM much sooner under memory pressure.
+/// It does not make the I/O path atomic-safe: the device lock is a mutex and
+/// user copies may fault.
+const SCULL_IOCSGFP: u32 = _IO(SCULL_IOC_MAGIC, 47);
+/// Returns the current `SCULL_GFP_*` setting.
+const SCULL_IOCGGFP: u32 = _IO(SCULL_IOC_MAGIC, 48);
+
+/// Copies a `ScullSizes` snapshot of internal structure sizes to userspace.
+const SCULL_IOCGSIZES: u32 = _IOR::<ScullSizes>(SCULL_IOC_MAGIC, 49);
+/// scull.concat: selects the two member devices, see `ScullConcatMembers`.
+const SCULL_C_IOCSMEMBERS: u32 = _IOW::<ScullConcatMembers>(SCULL_IOC_MAGIC, 50);
+/// Copies a `ScullFillStats` summary of how full the allocated quanta are,
+/// see `write_user_sized`.
+const SCULL_IOCGFILLSTATS: u32 =
+    _IOWR::<[u8; SCULL_FILL_STATS_SIZE_VER0]>(SCULL_IOC_MAGIC, 51);
+/// Ends each read at the next multiple of `arg` bytes, or lifts the limit if
+/// `arg == 0`.
+const SCULL_IOCSRECORD: u32 = _IO(SCULL_IOC_MAGIC, 52);
+/// Returns the record size, 0 if none.
+const SCULL_IOCGRECORD: u32 = _IO(SCULL_IOC_MAGIC, 53);
+/// Makes every later open of the device fail with ESHUTDOWN until the module
+/// is reloaded, and every later read, write or ioctl on a handle already
+/// open fail with ENODEV. Operations already in progress complete; on
+/// scullpipe, blocked waits fail with ENODEV.
+const SCULL_IOCSHUTDOWN: u32 = _IO(SCULL_IOC_MAGIC, 54);
+/// Checks the device's internal consistency without changing it, see
+/// `ScullCheck`. Returns 0 or the `SCULL_CHECK_*` code of the first broken
+/// invariant.
+const SCULL_IOCCHECK: u32 = _IOWR::<ScullCheck>(SCULL_IOC_MAGIC, 55);
+/// Copies the device's creation time, a `u64` of monotonic nanoseconds as in
+/// `ScullEvent::timestamp_ns`, to userspace.
+const SCULL_IOCGCREATED: u32 = _IOR::<u64>(SCULL_IOC_MAGIC, 56);
+/// Makes writes starting more than a `u64` number of bytes past the end of
+/// the data fail with EINVAL, or lifts the limit with `SCULL_GAP_UNLIMITED`.
+const SCULL_IOCSMAXGAP: u32 = _IOW::<u64>(SCULL_IOC_MAGIC, 57);
+/// Copies the largest hole a write may open, as a `u64`, to userspace.
+const SCULL_IOCGMAXGAP: u32 = _IOR::<u64>(SCULL_IOC_MAGIC, 58);
+
+/// `SCULL_IOCSMAXGAP` argument: writes may open holes of any size.
+const SCULL_GAP_UNLIMITED: u64 = u64::MAX;
+
+/// `/dev/scull` only: copies a `ScullDevInfo` for it and each dynamic device
+/// to the array described by a `ScullListReq`, returning how many were
+/// copied.
+const SCULL_IOCLIST: u32 = _IOW::<ScullListReq>(SCULL_IOC_MAGIC, 59);
+/// Copies the running checksum of every byte written since the device was
+/// last emptied, a `u64`, to userspace. It is a 64-bit FNV-1a over the write
+/// stream in the order the bytes arrived, not over the contents: overwriting
+/// a byte adds the new value but does not take out the old one, so equal
+/// contents written differently have different checksums.
+const SCULL_IOCGRUNNINGCRC: u32 = _IOR::<u64>(SCULL_IOC_MAGIC, 61);
+/// Discards the first `arg` bytes, or all the data if there are fewer, and
+/// moves the rest down so that what was at offset `arg` is at offset 0.
+/// Refused like `SCULL_IOCRESET` without `SCULL_RESET_FORCE`. Returns the
+/// resulting size.
+const SCULL_IOCDISCARDFRONT: u32 = _IO(SCULL_IOC_MAGIC, 62);
+/// Sets the maximum number of handles open on the device at once
+/// (0 = unlimited); further opens fail with EBUSY. Needs CAP_SYS_ADMIN;
+/// lowering it below the current count only stops new opens.
+const SCULL_IOCSMAXOPENS: u32 = _IO(SCULL_IOC_MAGIC, 63);
+/// Returns the maximum number of open handles (0 = unlimited).
+const SCULL_IOCGMAXOPENS: u32 = _IO(SCULL_IOC_MAGIC, 64);
+/// Sets the device's label, free text for the user's own bookkeeping: up to
+/// `SCULL_LABEL_LEN` bytes of UTF-8, NUL-padded. Fails with EINVAL if the
+/// text is not UTF-8 or anything but padding follows a NUL.
+const SCULL_IOCSLABEL: u32 = _IOW::<[u8; SCULL_LABEL_LEN]>(SCULL_IOC_MAGIC, 65);
+/// Copies the device's label, NUL-padded, to userspace. Empty by default.
+const SCULL_IOCGLABEL: u32 = _IOR::<[u8; SCULL_LABEL_LEN]>(SCULL_IOC_MAGIC, 66);
+
+/// Size of the `SCULL_IOCSLABEL` buffer.
+const SCULL_LABEL_LEN: usize = 64;
+
+/// Copies a `ScullVersion` describing the loaded module to userspace. Also
+/// accepted by scullpipe.
+const SCULL_IOCGVERSION: u32 = _IOR::<ScullVersion>(SCULL_IOC_MAGIC, 67);
+/// Returns the device's storage backend, `SCULL_BACKEND_QSET` or
+/// `SCULL_BACKEND_FLAT`. It is fixed when the device is created.
+const SCULL_IOCGBACKEND: u32 = _IO(SCULL_IOC_MAGIC, 68);
+
+/// Version of the ioctl interface. The minor number grows when commands are
+/// added and the major number when existing ones change incompatibly.
+const SCULL_VERSION_MAJOR: u32 = 1;
+const SCULL_VERSION_MINOR: u32 = 1;
+const SCULL_VERSION_PATCH: u32 = 0;
+
+// `ScullVersion::features` bits. Features present in every build have none.
+/// Versions: `SCULL_IOCCOMMIT`, `SCULL_IOCROLLBACK` (`keep_versions` set).
+const SCULL_FEAT_VERSIONS: u64 = 1 << 0;
+/// `SCULL_IOCFORK`.
+const SCULL_FEAT_FORK: u64 = 1 << 1;
+/// `SCULL_IOCMIGRATE` and `SCULL_IOCGMIGRATE`.
+const SCULL_FEAT_MIGRATE: u64 = 1 << 2;
+/// The operation log: `SCULL_IOCGETLOG`, `SCULL_IOCCLEARLOG`,
+/// `SCULL_IOCSLOGGING`.
+const SCULL_FEAT_LOG: u64 = 1 << 3;
+/// `SCULL_IOCFIEMAP`.
+const SCULL_FEAT_FIEMAP: u64 = 1 << 4;
+/// `SCULL_IOCINJECT` and `SCULL_IOCINJECTCOPY` (debug kernels).
+const SCULL_FEAT_INJECT: u64 = 1 << 5;
+/// Freed storage is poisoned (`poison` set on a debug kernel).
+const SCULL_FEAT_POISON: u64 = 1 << 6;
+/// Invariants are checked after every write and ioctl (`paranoid` set).
+const SCULL_FEAT_PARANOID: u64 = 1 << 7;
+/// `/dev/scull0pM` partitions exist (`partitions` set).
+const SCULL_FEAT_PARTITIONS: u64 = 1 << 8;
+/// Background compaction runs (`compact_interval_ms` set).
+const SCULL_FEAT_COMPACT: u64 = 1 << 9;
+
+/// `SCULL_IOCSGFP` argument: allocate with GFP_KERNEL (the default).
+const SCULL_GFP_KERNEL: usize = 0;
+/// `SCULL_IOCSGFP` argument: allocate with GFP_ATOMIC.
+const SCULL_GFP_ATOMIC: usize = 1;
+
+/// `SCULL_IOCRESET` argument: also discard write-protected ranges.
+const SCULL_RESET_FORCE: usize = 1;
+
+/// `SCULL_IOCGBACKEND` result: data is kept in qset nodes of quanta.
+const SCULL_BACKEND_QSET: usize = 0;
+/// `SCULL_IOCGBACKEND` result: data is kept in one contiguous buffer.
+const SCULL_BACKEND_FLAT: usize = 1;
+
+/// `SCULL_IOCFORK` argument: give the copy the source's backend.
+const SCULL_FORK_SAME: usize = 0;
+/// `SCULL_IOCFORK` argument: give the copy the qset backend.
+const SCULL_FORK_QSET: usize = 1;
+/// `SCULL_IOCFORK` argument: give the copy the flat backend.
+const SCULL_FORK_FLAT: usize = 2;
+
+/// A byte range passed to the range ioctls.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullRange {
+    offset: u64,
+    len: u64,
 }
 
-/// Represents the data held by a single scull device.
-struct ScullDevData {
-    data: Option<KBox<ScullQset>>, // Head of the qset list
-    quantum: usize,
-    qset: usize,
+// SAFETY: `ScullRange` is `repr(C)` with only integer fields and no padding.
+unsafe impl FromBytes for ScullRange {}
+// SAFETY: `ScullRange` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullRange {}
+
+/// Device statistics returned by `SCULL_IOCGSTATS`. Extensible, see
+/// `SizedStruct`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullStats {
+    /// Set by the caller to the size of its struct.
+    size: u32,
+    reserved: u32,
+    bytes_read: u64,
+    bytes_written: u64,
+    open_count: u64,
+}
+
+// SAFETY: `ScullStats` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullStats {}
+
+/// Size of the first version of `ScullStats`.
+const SCULL_STATS_SIZE_VER0: usize = 32;
+
+impl SizedStruct for ScullStats {
+    const SIZE_VER0: usize = SCULL_STATS_SIZE_VER0;
+
+    fn set_size(&mut self, size: u32) {
+        self.size = size;
+    }
+}
+
+/// The process behind a write, returned by `SCULL_IOCGLASTWRITER`. Both
+/// fields are 0 until the device is first written.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullWriter {
+    pid: i32,
+    uid: u32,
+}
+
+// SAFETY: `ScullWriter` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullWriter {}
+
+/// Argument of `SCULL_IOCLOCK` and `SCULL_IOCUNLOCK`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullLockReq {
+    offset: u64,
+    len: u64,
+    flags: u32,
+    reserved: u32,
+}
+
+// SAFETY: `ScullLockReq` is `repr(C)` with only integer fields and no padding.
+unsafe impl FromBytes for ScullLockReq {}
+
+/// `ScullLogEntry::op` values.
+const SCULL_LOG_READ: u32 = 1;
+const SCULL_LOG_WRITE: u32 = 2;
+const SCULL_LOG_IOCTL: u32 = 3;
+
+/// One operation log record. For ioctls `offset` holds the command and `len`
+/// the argument.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullLogEntry {
+    timestamp_ns: u64,
+    offset: u64,
+    len: u64,
+    /// Bytes transferred or ioctl return value, or a negative errno.
+    result: i64,
+    pid: i32,
+    op: u32,
+}
+
+// SAFETY: `ScullLogEntry` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullLogEntry {}
+
+/// Argument of `SCULL_IOCGETLOG`: an array of `count` entries at `buf`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullLogReq {
+    buf: u64,
+    count: u64,
+}
+
+// SAFETY: `ScullLogReq` is `repr(C)` with only integer fields and no padding.
+unsafe impl FromBytes for ScullLogReq {}
+
+/// Argument of `SCULL_IOCLIST`: an array of `count` entries at `buf`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullListReq {
+    buf: u64,
+    count: u64,
+}
+
+// SAFETY: `ScullListReq` is `repr(C)` with only integer fields and no padding.
+unsafe impl FromBytes for ScullListReq {}
+
+/// One device in the `SCULL_IOCLIST` listing.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullDevInfo {
+    /// The device name, NUL-padded.
+    name: [u8; 16],
     size: u64,
+    quantum: u64,
+    qset: u64,
+}
+
+// SAFETY: `ScullDevInfo` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullDevInfo {}
+
+/// A run of allocated data, in whole quanta clamped to the end of the data.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullExtent {
+    logical_offset: u64,
+    length: u64,
+    flags: u32,
+    reserved: u32,
+}
+
+// SAFETY: `ScullExtent` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullExtent {}
+
+/// Argument of `SCULL_IOCFIEMAP`: an array of `count` extents at `buf`.
+///
+/// On return `next` is where to resume, or the end of the data once the
+/// whole device has been mapped. An extent may be split across calls.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullFiemap {
+    start: u64,
+    buf: u64,
+    count: u32,
+    /// Out: number of extents written.
+    mapped: u32,
+    /// Out: offset to pass as `start` in the next call.
+    next: u64,
+}
+
+// SAFETY: `ScullFiemap` is `repr(C)` with only integer fields and no padding.
+unsafe impl FromBytes for ScullFiemap {}
+// SAFETY: `ScullFiemap` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullFiemap {}
+
+/// `ScullEvent` flag: older records were dropped before this one.
+const SCULL_EVENT_OVERFLOW: u32 = 1;
+
+/// One record read from `/dev/scull.events`, emitted per successful write or
+/// reset. A reset is reported with `offset` and `len` both 0.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullEvent {
+    offset: u64,
+    len: u64,
+    /// Value of the device's mutation counter after this change. Counters are
+    /// assigned under the device lock, so they follow the order the changes
+    /// took effect in, even if records from racing writers arrive out of it.
+    /// Changes that emit no record (rollback, drop cache, extend) still use
+    /// up a value, so gaps are expected.
+    generation: u64,
+    timestamp_ns: u64,
+    pid: i32,
+    flags: u32,
+}
+
+// SAFETY: `ScullEvent` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullEvent {}
+
+/// Argument of `SCULL_IOCPEEK`: copy `len` bytes at `offset` to the user buffer `buf`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullPeek {
+    offset: u64,
+    buf: u64,
+    len: u64,
+}
+
+// SAFETY: `ScullPeek` is `repr(C)` with only integer fields and no padding.
+unsafe impl FromBytes for ScullPeek {}
+
+/// Argument of `SCULL_IOCSGEOMETRY` and result of `SCULL_IOCGGEOMETRY`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullGeometry {
+    quantum: u64,
+    qset: u64,
+    flags: u32,
+    reserved: u32,
+}
+
+// SAFETY: `ScullGeometry` is `repr(C)` with only integer fields and no padding.
+unsafe impl FromBytes for ScullGeometry {}
+// SAFETY: `ScullGeometry` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullGeometry {}
+
+/// Longest pattern `SCULL_P_IOCWAITPATTERN` accepts.
+const SCULL_PATTERN_MAX: usize = 16;
+
+/// Argument of `SCULL_P_IOCWAITPATTERN`: the first `len` bytes of `pattern`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullPattern {
+    pattern: [u8; SCULL_PATTERN_MAX],
+    len: u32,
+    reserved: u32,
+}
+
+// SAFETY: `ScullPattern` is `repr(C)` with only integer fields and no padding.
+unsafe impl FromBytes for ScullPattern {}
+
+/// Argument of `SCULL_IOCREADEX`: read up to `len` bytes at `offset` into `buf`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullReadEx {
+    offset: u64,
+    buf: u64,
+    len: u64,
+    /// Out: bytes copied.
+    copied: u64,
+    /// Out: 1 if data remains past `offset + copied`, else 0.
+    more: u32,
+    reserved: u32,
+}
+
+// SAFETY: `ScullReadEx` is `repr(C)` with only integer fields and no padding.
+unsafe impl FromBytes for ScullReadEx {}
+// SAFETY: `ScullReadEx` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullReadEx {}
+
+/// Sizes of the internal structures `SCULL_IOCGFOOTPRINT` counts, returned by
+/// `SCULL_IOCGSIZES`. They are build-dependent and not part of the ABI.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullSizes {
+    /// A list node, `ScullQset`.
+    qset: u64,
+    /// One slot of a node's qset array, `Option<Quantum>`.
+    qset_entry: u64,
+    /// One word of a quantum's dirty bitmap.
+    dirty_word: u64,
+    /// The per-device state, `ScullDevData`.
+    dev_data: u64,
+}
+
+// SAFETY: `ScullSizes` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullSizes {}
+
+/// Argument of `SCULL_IOCCHECK`.
+///
+/// Each call checks at most `SCULL_CHECK_MAX_NODES` qset nodes from node
+/// `cursor` on, plus the whole-device invariants when `cursor` is 0. On
+/// success `cursor` is where to resume, or 0 once the whole chain has been
+/// checked. On a violation `code` names the check and `item` and `s_pos`
+/// locate the node and quantum involved, where that applies.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullCheck {
+    cursor: u64,
+    item: u64,
+    s_pos: u64,
+    code: u32,
+    reserved: u32,
+}
+
+// SAFETY: `ScullCheck` is `repr(C)` with only integer fields and no padding.
+unsafe impl FromBytes for ScullCheck {}
+// SAFETY: `ScullCheck` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullCheck {}
+
+// `SCULL_IOCCHECK` codes.
+const SCULL_CHECK_NODE_COUNT: u32 = 1;
+const SCULL_CHECK_QSET_LEN: u32 = 2;
+const SCULL_CHECK_QUANTUM_LEN: u32 = 3;
+const SCULL_CHECK_DIRTY_LEN: u32 = 4;
+const SCULL_CHECK_DIRTY_PAST_SIZE: u32 = 5;
+const SCULL_CHECK_SIZE: u32 = 6;
+const SCULL_CHECK_PROTECTED: u32 = 7;
+const SCULL_CHECK_LOCKS: u32 = 8;
+
+/// Most qset nodes examined by one `SCULL_IOCCHECK` call, bounding lock hold
+/// time.
+const SCULL_CHECK_MAX_NODES: usize = 1024;
+
+/// A broken invariant: a `SCULL_CHECK_*` code and where it was found.
+#[derive(Clone, Copy)]
+struct Violation {
+    code: u32,
+    item: usize,
+    s_pos: usize,
+}
+
+impl Violation {
+    fn new(code: u32, item: usize, s_pos: usize) -> Self {
+        Violation { code, item, s_pos }
+    }
+
+    /// Describes the broken invariant, for the kernel log.
+    fn describe(&self) -> &'static str {
+        match self.code {
+            SCULL_CHECK_NODE_COUNT => "chain length differs from node_count",
+            SCULL_CHECK_QSET_LEN => "qset array length differs from qset",
+            SCULL_CHECK_QUANTUM_LEN => "quantum longer than the quantum size",
+            SCULL_CHECK_DIRTY_LEN => "dirty bitmap does not match its quantum",
+            SCULL_CHECK_DIRTY_PAST_SIZE => "written byte past size",
+            SCULL_CHECK_SIZE => "size beyond what the geometry can address",
+            SCULL_CHECK_PROTECTED => "protected ranges unsorted or overlapping",
+            SCULL_CHECK_LOCKS => "conflicting or empty range locks held",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Result of `SCULL_IOCGFILLSTATS`. A byte of an allocated quantum is valid
+/// if it lies below the device size and padding otherwise. Extensible, see
+/// `SizedStruct`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullFillStats {
+    /// Set by the caller to the size of its struct.
+    size: u32,
+    reserved: u32,
+    /// Allocated quanta.
+    quanta: u64,
+    /// Quanta lying wholly below the size.
+    full: u64,
+    /// Quanta straddling the size.
+    partial: u64,
+    valid_bytes: u64,
+    padding_bytes: u64,
+}
+
+// SAFETY: `ScullFillStats` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullFillStats {}
+
+/// Size of the first version of `ScullFillStats`.
+const SCULL_FILL_STATS_SIZE_VER0: usize = 48;
+
+impl SizedStruct for ScullFillStats {
+    const SIZE_VER0: usize = SCULL_FILL_STATS_SIZE_VER0;
+
+    fn set_size(&mut self, size: u32) {
+        self.size = size;
+    }
+}
+
+/// Argument of `SCULL_C_IOCSMEMBERS`. A member is 0 for `/dev/scull` or
+/// `n` for `scull_dyn<n - 1>`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullConcatMembers {
+    first: u32,
+    second: u32,
+}
+
+// SAFETY: `ScullConcatMembers` is `repr(C)` with only integer fields and no padding.
+unsafe impl FromBytes for ScullConcatMembers {}
+
+impl ScullSizes {
+    /// Returns the sizes for this build.
+    fn get() -> Self {
+        Self {
+            qset: size_of::<ScullQset>() as u64,
+            qset_entry: size_of::<Option<Quantum>>() as u64,
+            dirty_word: size_of::<u64>() as u64,
+            dev_data: size_of::<ScullDevData>() as u64,
+        }
+    }
+}
+
+/// Result of `SCULL_IOCGVERSION`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ScullVersion {
+    major: u32,
+    minor: u32,
+    patch: u32,
+    reserved: u32,
+    /// `SCULL_FEAT_*` bits for what this build and its parameters enable.
+    features: u64,
+}
+
+// SAFETY: `ScullVersion` is `repr(C)` with only integer fields and no padding.
+unsafe impl AsBytes for ScullVersion {}
+
+impl ScullVersion {
+    /// Returns the version and features of the loaded module.
+    fn get() -> Self {
+        let mut features = 0;
+        if cfg!(not(scull_minimal)) {
+            features |= SCULL_FEAT_FORK | SCULL_FEAT_MIGRATE | SCULL_FEAT_LOG | SCULL_FEAT_FIEMAP;
+            if *module_parameters::keep_versions.value() != 0 {
+                features |= SCULL_FEAT_VERSIONS;
+            }
+        }
+        if cfg!(CONFIG_DEBUG_KERNEL) {
+            features |= SCULL_FEAT_INJECT;
+        }
+        if poison_freed() {
+            features |= SCULL_FEAT_POISON;
+        }
+        if paranoid() {
+            features |= SCULL_FEAT_PARANOID;
+        }
+        if *module_parameters::partitions.value() != 0 {
+            features |= SCULL_FEAT_PARTITIONS;
+        }
+        if *module_parameters::compact_interval_ms.value() != 0 {
+            features |= SCULL_FEAT_COMPACT;
+        }
+        Self {
+            major: SCULL_VERSION_MAJOR,
+            minor: SCULL_VERSION_MINOR,
+            patch: SCULL_VERSION_PATCH,
+            reserved: 0,
+            features,
+        }
+    }
+}
+
+/// Copies a `T` in from the user pointer `arg`.
+fn read_user<T: FromBytes>(arg: usize) -> Result<T> {
+    UserSlice::new(UserPtr::from_addr(arg), size_of::<T>())
+        .reader()
+        .read()
+}
+
+/// Copies `val` out to the user pointer `arg`.
+fn write_user<T: AsBytes>(arg: usize, val: &T) -> Result {
+    UserSlice::new(UserPtr::from_addr(arg), size_of::<T>())
+        .writer()
+        .write(val)
+}
+
+/// A uapi struct that begins with a `u32` holding its size in bytes, as
+/// `openat2` and `clone3` do, so that fields can be appended without
+/// breaking callers built against an older version. The ioctl number
+/// encodes `SIZE_VER0`, which never changes. New fields only ever go at the
+/// end, and the struct stays free of implicit padding.
+trait SizedStruct: AsBytes {
+    /// Size of the first version of the struct.
+    const SIZE_VER0: usize;
+
+    fn set_size(&mut self, size: u32);
+}
+
+/// Copies `val` out to the `SizedStruct` at the user pointer `arg`, whose
+/// size field the caller has set. An older caller gets just the fields it
+/// knows about. A size below the first version, or above ours from a
+/// caller newer than this driver, fails with `EINVAL`.
+fn write_user_sized<T: SizedStruct>(arg: usize, mut val: T) -> Result {
+    let size: u32 = read_user(arg)?;
+    let len = sized_len::<T>(size)?;
+    val.set_size(size);
+    UserSlice::new(UserPtr::from_addr(arg), len)
+        .writer()
+        .write_slice(&val.as_bytes()[..len])
+}
+
+/// Returns how many bytes of a `T` to copy for a caller that sized its
+/// struct `size` bytes, see `write_user_sized`.
+fn sized_len<T: SizedStruct>(size: u32) -> Result<usize> {
+    let len = size as usize;
+    if len < T::SIZE_VER0 || len > size_of::<T>() {
+        return Err(ScullError::InvalidArgument.into());
+    }
+    Ok(len)
+}
+
+/// Writes `len` copies of `byte` to `writer`.
+fn write_fill(writer: &mut UserSliceWriter, byte: u8, mut len: usize) -> Result {
+    let pattern = [byte; 256];
+
+    while len > 0 {
+        let chunk = len.min(pattern.len());
+        writer.write_slice(&pattern[..chunk])?;
+        len -= chunk;
+    }
+    Ok(())
+}
+
+/// Copies `len` copies of `byte` into `iov`, returning how many were consumed.
+fn fill_iter(iov: &mut IovIterDest<'_>, byte: u8, mut len: usize) -> usize {
+    let pattern = [byte; 256];
+    let mut done = 0;
+
+    while len > 0 {
+        let chunk = len.min(pattern.len());
+        let copied = copy_to_iter(iov, &pattern[..chunk]);
+        done += copied;
+        if copied < chunk {
+            break;
+        }
+        len -= chunk;
+    }
+    done
+}
+
+/// `IovIterDest::copy_to_iter`, subject to `SCULL_IOCINJECTCOPY`.
+fn copy_to_iter(iov: &mut IovIterDest<'_>, bytes: &[u8]) -> usize {
+    iov.copy_to_iter(&bytes[..injected_copy_len(bytes.len())])
+}
+
+/// `IovIterSource::copy_from_iter`, subject to `SCULL_IOCINJECTCOPY`.
+fn copy_from_iter(iov: &mut IovIterSource<'_>, bytes: &mut [u8]) -> usize {
+    let len = injected_copy_len(bytes.len());
+    iov.copy_from_iter(&mut bytes[..len])
+}
+
+// --- Errors ---
+
+/// Scull-specific failures, each mapping to a kernel errno.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ScullError {
+    /// The device geometry has a zero quantum or qset.
+    QuantumZero,
+    /// The node limit would be exceeded.
+    TooManyNodes,
+    /// An offset or size lies beyond what the device can address.
+    OffsetOverflow,
+    /// The offset lies past the end of a partition, or no slot is free.
+    NoSpace,
+    /// A user copy faulted before moving anything.
+    BadAddress,
+    /// The writing uid's quota would be exceeded.
+    QuotaExceeded,
+    /// The device must be empty for this operation.
+    Busy,
+    /// The caller lacks the required capability.
+    NotPrivileged,
+    /// The target bytes are protected by write-once, append-only or a
+    /// protected range.
+    Protected,
+    /// The device's logical mode forbids the operation.
+    AccessDenied,
+    /// A malformed argument.
+    InvalidArgument,
+    /// The feature is disabled.
+    NotSupported,
+    /// The ioctl command is not recognised.
+    UnknownCommand,
+}
+
+impl From<ScullError> for Error {
+    fn from(err: ScullError) -> Error {
+        match err {
+            ScullError::QuantumZero => EFAULT,
+            ScullError::TooManyNodes | ScullError::OffsetOverflow => EFBIG,
+            ScullError::NoSpace => ENOSPC,
+            ScullError::BadAddress => EFAULT,
+            ScullError::QuotaExceeded => Error::from_errno(-(bindings::EDQUOT as i32)),
+            ScullError::Busy => EBUSY,
+            ScullError::NotPrivileged | ScullError::Protected => EPERM,
+            ScullError::AccessDenied => EACCES,
+            ScullError::InvalidArgument => EINVAL,
+            ScullError::NotSupported => EOPNOTSUPP,
+            ScullError::UnknownCommand => ENOTTY,
+        }
+    }
+}
+
+/// Set by the KUnit suites to run the privilege checks as a caller without
+/// CAP_SYS_ADMIN, since the suites themselves run as root.
+#[cfg(CONFIG_KUNIT)]
+static DENY_SYS_ADMIN: AtomicBool = AtomicBool::new(false);
+
+fn capable_sys_admin() -> bool {
+    #[cfg(CONFIG_KUNIT)]
+    if DENY_SYS_ADMIN.load(Ordering::Relaxed) {
+        return false;
+    }
+    // SAFETY: `capable` only inspects the credentials of the current task.
+    unsafe { bindings::capable(bindings::CAP_SYS_ADMIN as i32) }
+}
+
+/// Returns the monotonic clock in nanoseconds.
+fn now_ns() -> u64 {
+    // SAFETY: `ktime_get` has no preconditions.
+    unsafe { bindings::ktime_get() as u64 }
+}
+
+/// Starting value of `ScullDevData::running_sum`, the FNV-1a offset basis.
+const STREAM_SUM_INIT: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Folds `bytes` into the 64-bit FNV-1a checksum `sum`.
+fn stream_sum(sum: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(sum, |sum, &byte| {
+        (sum ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Converts an operation's outcome into the value recorded in the log.
+fn result_code<T: Copy + TryInto<i64>>(result: &Result<T>) -> i64 {
+    match result {
+        Ok(val) => (*val).try_into().unwrap_or(i64::MAX),
+        Err(e) => e.to_errno() as i64,
+    }
+}
+
+/// Returns true if the current task has been sent SIGKILL.
+fn fatal_signal_pending() -> bool {
+    let task = current!().as_ptr();
+    // SAFETY: `current` is valid for the duration of the call. Like the C
+    // `__fatal_signal_pending`, this is an unlocked read of its pending set.
+    let pending = unsafe { (*task).pending.signal.sig[0] };
+    pending & (1 << (bindings::SIGKILL - 1)) != 0
+}
+
+/// `FMODE_ATOMIC_POS`, which bindgen cannot see through its `__force` cast.
+const FMODE_ATOMIC_POS: u32 = 1 << 15;
+
+/// `IOCB_APPEND`, which bindgen cannot see through its `__force` cast.
+const IOCB_APPEND: i32 = 1 << 4;
+
+fn current_uid() -> u32 {
+    current!().euid().into_uid_in_current_ns()
+}
+
+/// Returns the current `f_flags` of the file the I/O was issued on.
+fn file_flags<T: ForeignOwnable>(kiocb: &Kiocb<'_, T>) -> u32 {
+    // SAFETY: The kiocb and its file are valid for the duration of the I/O call.
+    unsafe { (*(*kiocb.as_raw()).ki_filp).f_flags }
+}
+
+/// Returns true if the write appends: the file was opened with `O_APPEND`
+/// and the call did not pass `RWF_NOAPPEND`, or the call passed `RWF_APPEND`.
+fn is_append<T: ForeignOwnable>(kiocb: &Kiocb<'_, T>) -> bool {
+    // SAFETY: The kiocb is valid for the duration of the I/O call.
+    unsafe { (*kiocb.as_raw()).ki_flags & IOCB_APPEND != 0 }
+}
+
+/// Returns true if the I/O was issued on a file opened with `O_NONBLOCK`.
+fn is_nonblock<T: ForeignOwnable>(kiocb: &Kiocb<'_, T>) -> bool {
+    file_flags(kiocb) & file::flags::O_NONBLOCK != 0
+}
+
+// --- Size Conversions ---
+//
+// Offsets and sizes are `u64`, lengths of memory buffers `usize`. A sparse
+// device can outgrow `usize` on a 32-bit kernel, so narrowing goes through
+// these helpers rather than `as`.
+
+/// Clamps a byte count to `usize`, for counts that are bounded by a buffer
+/// length afterwards anyway.
+fn clamp_len(len: u64) -> usize {
+    len.try_into().unwrap_or(usize::MAX)
+}
+
+/// Converts a size into an ioctl return value, failing with EOVERFLOW if
+/// it does not fit.
+fn ret_size(size: u64) -> Result<isize> {
+    isize::try_from(size).map_err(|_| EOVERFLOW)
+}
+
+// --- Fault Injection ---
+
+/// Armed by `SCULL_IOCINJECT`: makes the next data-path allocation fail.
+#[cfg(CONFIG_DEBUG_KERNEL)]
+static FAIL_NEXT_ALLOC: AtomicBool = AtomicBool::new(false);
+
+/// Armed by `SCULL_IOCINJECTCOPY`: how many more user copies to cut short.
+#[cfg(CONFIG_DEBUG_KERNEL)]
+static SHORT_COPIES: AtomicU32 = AtomicU32::new(0);
+
+/// Returns how many of `len` bytes a user copy of read or write data may
+/// move: half of them while a short copy is armed, else all of them.
+fn injected_copy_len(len: usize) -> usize {
+    #[cfg(CONFIG_DEBUG_KERNEL)]
+    if SHORT_COPIES
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+        .is_ok()
+    {
+        return len / 2;
+    }
+    len
+}
+
+/// Fails with `ENOMEM` if an injected allocation failure is armed. Called
+/// before every allocation in `follow` and the write path.
+fn check_injected_fault() -> Result {
+    #[cfg(CONFIG_DEBUG_KERNEL)]
+    if FAIL_NEXT_ALLOC.swap(false, Ordering::Relaxed) {
+        return Err(ENOMEM);
+    }
+    Ok(())
+}
+
+/// The byte freed storage is overwritten with, as the slab allocator's own
+/// `POISON_FREE`.
+const SCULL_POISON: u8 = 0x6b;
+
+/// Returns true if freed storage is to be poisoned, see the `poison`
+/// parameter.
+fn poison_freed() -> bool {
+    cfg!(CONFIG_DEBUG_KERNEL) && *module_parameters::poison.value() != 0
+}
+
+/// Overwrites the unused capacity of `vec` with `SCULL_POISON`.
+fn poison_spare<T>(vec: &mut KVec<T>) {
+    let spare = vec.spare_capacity_mut();
+    // SAFETY: `spare` is valid for writes of `spare.len()` elements, and any
+    // bytes are a valid `MaybeUninit<T>`.
+    unsafe { core::ptr::write_bytes(spare.as_mut_ptr(), SCULL_POISON, spare.len()) };
+}
+
+// --- Paranoid Mode ---
+
+/// Set by the first invariant violation, so that only one is reported.
+static PARANOID_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if the `paranoid` parameter is set.
+fn paranoid() -> bool {
+    *module_parameters::paranoid.value() != 0
+}
+
+// --- Quota Accounting ---
+
+/// Bytes of quantum storage currently charged to each writing uid.
+struct UidQuota {
+    /// Per-uid cap in bytes; 0 means unlimited.
+    limit: u64,
+    usage: KVec<(u32, u64)>,
+}
+
+impl UidQuota {
+    /// Charges `bytes` to `uid`, failing with `EDQUOT` if it would exceed the limit.
+    fn charge(&mut self, uid: u32, bytes: u64) -> Result {
+        let idx = match self.usage.iter().position(|(u, _)| *u == uid) {
+            Some(idx) => idx,
+            None => {
+                self.usage.push((uid, 0), GFP_KERNEL)?;
+                self.usage.len() - 1
+            }
+        };
+
+        let used = &mut self.usage[idx].1;
+        if self.limit != 0 && *used + bytes > self.limit {
+            return Err(ScullError::QuotaExceeded.into());
+        }
+        *used += bytes;
+        Ok(())
+    }
+
+    /// Returns `bytes` previously charged to `uid`.
+    fn credit(&mut self, uid: u32, bytes: u64) {
+        if let Some(idx) = self.usage.iter().position(|(u, _)| *u == uid) {
+            let used = &mut self.usage[idx].1;
+            *used = used.saturating_sub(bytes);
+            if *used == 0 {
+                self.usage.swap_remove(idx);
+            }
+        }
+    }
+}
+
+/// Names of the partitions of `/dev/scull`; the index is the partition number.
+const SCULL_PART_NAMES: [&CStr; 8] = [
+    c_str!("scull0p0"),
+    c_str!("scull0p1"),
+    c_str!("scull0p2"),
+    c_str!("scull0p3"),
+    c_str!("scull0p4"),
+    c_str!("scull0p5"),
+    c_str!("scull0p6"),
+    c_str!("scull0p7"),
+];
+
+global_lock! {
+    // SAFETY: Initialized by `init_uid_quota` before any quantum is allocated.
+    unsafe(uninit) static UID_QUOTA: Mutex<UidQuota> = UidQuota {
+        limit: 0,
+        usage: KVec::new(),
+    };
 }
 
+/// Set once `UID_QUOTA` has been initialized.
+static UID_QUOTA_READY: AtomicBool = AtomicBool::new(false);
+
+/// Initializes `UID_QUOTA` on the first call. `ScullModule::init` calls this,
+/// and so do the KUnit suites, which may run before it.
+fn init_uid_quota() {
+    if UID_QUOTA_READY
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+    {
+        // SAFETY: Only the first caller gets here. The callers are the module
+        // init and the KUnit suites, which the module loader never runs
+        // concurrently.
+        unsafe { UID_QUOTA.init() };
+    }
+}
+
+/// Quanta currently allocated, in devices, versions and migration targets
+/// alike. Checked against 0 on unload.
+static LIVE_QUANTA: AtomicUsize = AtomicUsize::new(0);
+/// Qset nodes currently allocated, counted like `LIVE_QUANTA`.
+static LIVE_NODES: AtomicUsize = AtomicUsize::new(0);
+
+// --- Device Registry ---
+
+/// Names available to dynamically created devices; the index is the device's
+/// slot in `DYN_DEVICES`.
+const SCULL_DYN_NAMES: [&CStr; 8] = [
+    c_str!("scull_dyn0"),
+    c_str!("scull_dyn1"),
+    c_str!("scull_dyn2"),
+    c_str!("scull_dyn3"),
+    c_str!("scull_dyn4"),
+    c_str!("scull_dyn5"),
+    c_str!("scull_dyn6"),
+    c_str!("scull_dyn7"),
+];
+
+global_lock! {
+    // SAFETY: Initialized in `ScullModule::init` before any device is registered.
+    unsafe(uninit) static DYN_DEVICES: Mutex<KVec<Option<Pin<KBox<ScullDevice>>>>> = KVec::new();
+}
+
+// --- Data Structures ---
+
+/// Represents a "quantum" - a single block of data.
+///
+/// Allocated with kvmalloc, since the one quantum of a flat device grows
+/// with its data, far past what kmalloc can provide.
+struct Quantum {
+    buf: KVVec<u8>,
+    /// One bit per byte of `buf`, set once that byte has been written.
+    dirty: KVVec<u64>,
+    /// The uid charged for this quantum's storage.
+    owner: u32,
+}
+
+impl Quantum {
+    /// Allocates a quantum filled with `fill` using `gfp`, charging its size
+    /// to `owner`.
+    fn new(size: usize, fill: u8, owner: u32, gfp: Flags) -> Result<Self> {
+        let mut buf = KVVec::new();
+        buf.resize(size, fill, gfp)?;
+        let mut dirty = KVVec::new();
+        dirty.resize(size.div_ceil(64), 0, gfp)?;
+        UID_QUOTA.lock().charge(owner, size as u64)?;
+        LIVE_QUANTA.fetch_add(1, Ordering::Relaxed);
+        Ok(Quantum { buf, dirty, owner })
+    }
+
+    /// Returns the kernel memory held by the buffer and dirty bitmap.
+    fn footprint(&self) -> usize {
+        self.buf.capacity() + self.dirty.capacity() * size_of::<u64>()
+    }
+
+    /// Grows the quantum to `size` bytes, the new ones filled with `fill`,
+    /// charging the increase to its owner. Nothing changes on failure.
+    fn grow(&mut self, size: usize, fill: u8, gfp: Flags) -> Result {
+        let extra = size.saturating_sub(self.buf.len());
+        if extra == 0 {
+            return Ok(());
+        }
+        let dirty_len = size.div_ceil(64);
+        // Reserving at least doubles the capacity, so appends stay amortized
+        self.buf.reserve(extra, gfp)?;
+        self.dirty.reserve(dirty_len - self.dirty.len(), gfp)?;
+        UID_QUOTA.lock().charge(self.owner, extra as u64)?;
+        // Both fit in the capacity reserved above, so neither can fail
+        self.buf.resize(size, fill, gfp)?;
+        self.dirty.resize(dirty_len, 0, gfp)?;
+        Ok(())
+    }
+
+    /// Returns true if any byte in `start..end` has already been written.
+    fn is_dirty(&self, start: usize, end: usize) -> bool {
+        (start..end).any(|i| self.dirty[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    /// Records the bytes in `start..end` as written.
+    fn mark_dirty(&mut self, start: usize, end: usize) {
+        for i in start..end {
+            self.dirty[i / 64] |= 1 << (i % 64);
+        }
+    }
+}
+
+impl Quantum {
+    /// Makes a deep copy, charged to the same owner.
+    fn try_clone(&self) -> Result<Self> {
+        let mut copy = Quantum::new(self.buf.len(), 0, self.owner, GFP_KERNEL)?;
+        copy.buf.copy_from_slice(&self.buf);
+        copy.dirty.copy_from_slice(&self.dirty);
+        Ok(copy)
+    }
+}
+
+impl Drop for Quantum {
+    fn drop(&mut self) {
+        if poison_freed() {
+            self.buf.fill(SCULL_POISON);
+        }
+        UID_QUOTA.lock().credit(self.owner, self.buf.len() as u64);
+        LIVE_QUANTA.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A sorted list of non-overlapping `[start, end)` byte ranges.
+struct RangeList {
+    ranges: KVec<(u64, u64)>,
+}
+
+impl RangeList {
+    const fn new() -> Self {
+        RangeList {
+            ranges: KVec::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Returns true if any byte in `start..end` is covered.
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.ranges.iter().any(|&(s, e)| s < end && start < e)
+    }
+
+    /// Adds `start..end`, merging it with any overlapping or adjacent ranges.
+    fn insert(&mut self, mut start: u64, mut end: u64) -> Result {
+        let mut merged = KVec::with_capacity(self.ranges.len() + 1, GFP_KERNEL)?;
+        let mut placed = false;
+
+        for &(s, e) in self.ranges.iter() {
+            if e < start {
+                merged.push((s, e), GFP_KERNEL)?;
+            } else if end < s {
+                if !placed {
+                    merged.push((start, end), GFP_KERNEL)?;
+                    placed = true;
+                }
+                merged.push((s, e), GFP_KERNEL)?;
+            } else {
+                start = start.min(s);
+                end = end.max(e);
+            }
+        }
+        if !placed {
+            merged.push((start, end), GFP_KERNEL)?;
+        }
+
+        self.ranges = merged;
+        Ok(())
+    }
+
+    /// Removes `start..end`, which must lie within a single existing range.
+    fn remove(&mut self, start: u64, end: u64) -> Result {
+        let idx = self
+            .ranges
+            .iter()
+            .position(|&(s, e)| s <= start && end <= e)
+            .ok_or(ScullError::InvalidArgument)?;
+        let (s, e) = self.ranges[idx];
+
+        let mut split = KVec::with_capacity(self.ranges.len() + 1, GFP_KERNEL)?;
+        for (i, &range) in self.ranges.iter().enumerate() {
+            if i != idx {
+                split.push(range, GFP_KERNEL)?;
+                continue;
+            }
+            if s < start {
+                split.push((s, start), GFP_KERNEL)?;
+            }
+            if end < e {
+                split.push((end, e), GFP_KERNEL)?;
+            }
+        }
+
+        self.ranges = split;
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
+    /// Returns true if the ranges are non-empty, sorted, and neither overlap
+    /// nor touch, as `insert` leaves them.
+    fn is_canonical(&self) -> bool {
+        self.ranges.iter().all(|&(s, e)| s < e)
+            && self.ranges.windows(2).all(|pair| pair[0].1 < pair[1].0)
+    }
+}
+
+/// Represents a "qset" - an array of quanta.
+type QSet = KVec<Option<Quantum>>;
+
+/// Represents a node in the linked list of qsets.
+struct ScullQset {
+    data: Option<QSet>,
+    next: Option<KBox<ScullQset>>,
+}
+
+impl ScullQset {
+    fn new(data: Option<QSet>, next: Option<KBox<ScullQset>>) -> Self {
+        LIVE_NODES.fetch_add(1, Ordering::Relaxed);
+        ScullQset { data, next }
+    }
+}
+
+impl Drop for ScullQset {
+    fn drop(&mut self) {
+        LIVE_NODES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+const SCULL_LOG_ENTRIES: usize = 256;
+
+/// Most extents returned by one `SCULL_IOCFIEMAP` call.
+const SCULL_FIEMAP_MAX_EXTENTS: usize = 256;
+/// Most quanta examined by one `SCULL_IOCFIEMAP` call, bounding lock hold time.
+const SCULL_FIEMAP_MAX_QUANTA: usize = 4096;
+
+/// A fixed-size ring of recent operations, preallocated so that recording
+/// never allocates.
+struct OpLog {
+    entries: KVec<ScullLogEntry>,
+    /// Slot the next record goes into.
+    next: usize,
+    len: usize,
+}
+
+impl OpLog {
+    fn new() -> Result<Self> {
+        let mut entries = KVec::new();
+        entries.resize(SCULL_LOG_ENTRIES, ScullLogEntry::default(), GFP_KERNEL)?;
+        Ok(OpLog {
+            entries,
+            next: 0,
+            len: 0,
+        })
+    }
+
+    fn push(&mut self, entry: ScullLogEntry) {
+        self.entries[self.next] = entry;
+        self.next = (self.next + 1) % SCULL_LOG_ENTRIES;
+        self.len = (self.len + 1).min(SCULL_LOG_ENTRIES);
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Copies up to `out.len()` records into `out`, newest first.
+    fn copy_newest(&self, out: &mut [ScullLogEntry]) -> usize {
+        let n = out.len().min(self.len);
+        for (i, slot) in out[..n].iter_mut().enumerate() {
+            *slot = self.entries[(self.next + SCULL_LOG_ENTRIES - 1 - i) % SCULL_LOG_ENTRIES];
+        }
+        n
+    }
+}
+
+/// Records buffered per events reader before the oldest are dropped.
+const SCULL_EVENT_QUEUE: usize = 64;
+
+/// The pending events of one open `/dev/scull.events` handle.
+struct EventQueue {
+    /// Identifies the owning `EventsFile`.
+    owner: usize,
+    events: KVec<ScullEvent>,
+    /// Index of the oldest record.
+    head: usize,
+    len: usize,
+}
+
+impl EventQueue {
+    fn new(owner: usize) -> Result<Self> {
+        let mut events = KVec::new();
+        events.resize(SCULL_EVENT_QUEUE, ScullEvent::default(), GFP_KERNEL)?;
+        Ok(EventQueue {
+            owner,
+            events,
+            head: 0,
+            len: 0,
+        })
+    }
+
+    /// Queues `event`, dropping the oldest record if the queue is full and
+    /// flagging the record that now comes first.
+    fn push(&mut self, event: ScullEvent) {
+        if self.len == SCULL_EVENT_QUEUE {
+            self.head = (self.head + 1) % SCULL_EVENT_QUEUE;
+            self.len -= 1;
+            self.events[self.head].flags |= SCULL_EVENT_OVERFLOW;
+        }
+        self.events[(self.head + self.len) % SCULL_EVENT_QUEUE] = event;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<ScullEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % SCULL_EVENT_QUEUE;
+        self.len -= 1;
+        Some(event)
+    }
+}
+
+/// An advisory byte-range lock held by one open file.
+struct RangeLock {
+    /// Identifies the holding `ScullFile`.
+    owner: usize,
+    start: u64,
+    end: u64,
+    exclusive: bool,
+}
+
+impl RangeLock {
+    fn conflicts(&self, owner: usize, start: u64, end: u64, exclusive: bool) -> bool {
+        self.owner != owner
+            && self.start < end
+            && start < self.end
+            && (self.exclusive || exclusive)
+    }
+}
+
+/// Splits `offset` into its qset node index, quantum index within the node,
+/// and byte index within the quantum, for the given geometry. The caller
+/// must ensure `quantum * qset != 0` and that the node index fits in `usize`.
+///
+/// An offset on a quantum boundary maps to byte 0 of the quantum that
+/// starts there, never one past the end of the previous one. The same holds
+/// at node boundaries, where `s_pos` wraps to 0 and `item` moves on.
+fn split_offset(offset: u64, quantum: usize, qset: usize) -> (usize, usize, usize) {
+    let itemsize = (quantum * qset) as u64;
+
+    let item = (offset / itemsize) as usize;
+    let rest = offset % itemsize;
+    let s_pos = (rest / quantum as u64) as usize;
+    let q_pos = (rest % quantum as u64) as usize;
+    (item, s_pos, q_pos)
+}
+
+/// Returns how many of `remaining` bytes fit in the quantum from byte
+/// `q_pos` on, i.e. how much one step of an I/O loop moves.
+fn quantum_span(remaining: usize, quantum: usize, q_pos: usize) -> usize {
+    remaining.min(quantum - q_pos)
+}
+
+/// Frees a qset list iteratively, so long lists cannot overflow the stack.
+fn free_chain(mut current: Option<KBox<ScullQset>>) {
+    while let Some(mut qset_node) = current {
+        if let Some(mut data_array) = qset_node.data.take() {
+            // Quanta are dropped here
+            data_array.clear();
+            if poison_freed() {
+                poison_spare(&mut data_array);
+            }
+        }
+        current = qset_node.next.take();
+    }
+}
+
+/// Returns the kernel memory held by a qset list: the nodes, their qset
+/// arrays at full capacity, and each quantum's buffer and dirty bitmap.
+fn chain_footprint(head: Option<&ScullQset>) -> u64 {
+    let mut total = 0;
+    let mut current = head;
+    while let Some(node) = current {
+        total += size_of::<ScullQset>();
+        if let Some(data_array) = &node.data {
+            total += data_array.capacity() * size_of::<Option<Quantum>>();
+            for quantum_buf in data_array.iter().flatten() {
+                total += quantum_buf.footprint();
+            }
+        }
+        current = node.next.as_deref();
+    }
+    total as u64
+}
+
+/// Makes a deep copy of a qset list, preserving holes.
+fn copy_chain(head: Option<&ScullQset>) -> Result<Option<KBox<ScullQset>>> {
+    // Copy the qset arrays front to back, then link the nodes back to front
+    let mut arrays = KVec::new();
+    let mut current = head;
+    while let Some(node) = current {
+        let copy = match &node.data {
+            Some(data_array) => {
+                let mut copy = KVec::with_capacity(data_array.len(), GFP_KERNEL)?;
+                for quantum in data_array.iter() {
+                    let quantum = match quantum {
+                        Some(quantum) => Some(quantum.try_clone()?),
+                        None => None,
+                    };
+                    copy.push(quantum, GFP_KERNEL)?;
+                }
+                Some(copy)
+            }
+            None => None,
+        };
+        arrays.push(copy, GFP_KERNEL)?;
+        current = node.next.as_deref();
+    }
+
+    let mut copy = None;
+    while let Some(data) = arrays.pop() {
+        copy = Some(KBox::new(ScullQset::new(data, copy), GFP_KERNEL)?);
+    }
+    Ok(copy)
+}
+
+/// A retained copy of a device's contents, created by `SCULL_IOCCOMMIT`.
+struct Version {
+    data: Option<KBox<ScullQset>>,
+    buffer: Option<Quantum>,
+    quantum: usize,
+    qset: usize,
+    size: u64,
+    node_count: usize,
+}
+
+impl Drop for Version {
+    fn drop(&mut self) {
+        free_chain(self.data.take());
+    }
+}
+
+/// Bytes copied per step of a background migration.
+const SCULL_MIGRATE_STEP: u64 = 256 * 1024;
+
+/// A background migration to a new quantum size, see `SCULL_IOCMIGRATE`.
+struct Migration {
+    /// The contents rebuilt with the new geometry.
+    target: KBox<ScullDevData>,
+    /// Bytes copied so far.
+    copied: u64,
+}
+
+/// Represents the data held by a single scull device.
+struct ScullDevData {
+    data: Option<KBox<ScullQset>>, // Head of the qset list
+    /// Keeps the data in `buffer` instead of the qset list, see the
+    /// `backend` parameter. Fixed for the life of the device.
+    flat: bool,
+    /// The flat backend's storage: one quantum holding every byte up to the
+    /// last one stored, `None` until the first write. Growing it is charged
+    /// to whoever allocated it.
+    buffer: Option<Quantum>,
+    quantum: usize,
+    qset: usize,
+    size: u64,
+    /// Rejects writes that would overwrite already-written bytes.
+    write_once: bool,
+    /// Byte ranges that may not be modified.
+    protected: RangeList,
+    /// Number of allocated qset nodes.
+    node_count: usize,
+    /// Maximum number of qset nodes (0 = unlimited).
+    max_nodes: usize,
+    /// Value that unwritten bytes read back as.
+    fill_byte: u8,
+    /// End-of-data marker. When set, every write that reaches the end of the
+    /// data is followed by this byte, `size` counts it, and reads stop just
+    /// before it. The device therefore always ends in exactly one sentinel,
+    /// which the next append overwrites.
+    sentinel: Option<u8>,
+    /// Limits each read to a single quantum, like the original driver.
+    chunked: bool,
+    /// Reads never cross a multiple of this many bytes, 0 for no limit.
+    record_size: usize,
+    /// Only allows writes at the current end of the device.
+    append_only: bool,
+    /// Committed versions, oldest first, bounded by `keep_versions`.
+    versions: KVec<Version>,
+    /// `SCULL_MODE_*` bits.
+    mode: usize,
+    /// In-progress background migration; mutations fail with EBUSY meanwhile.
+    migration: Option<Migration>,
+    /// Who last wrote to the device.
+    last_writer: ScullWriter,
+    /// When the device was created, see `now_ns`.
+    created_at: u64,
+    /// Allocate nodes, qset arrays and quanta with GFP_ATOMIC instead of
+    /// GFP_KERNEL, see `SCULL_IOCSGFP`.
+    atomic_alloc: bool,
+    /// Required alignment of I/O offsets and lengths, 0 for none.
+    align: usize,
+    /// Largest hole a write may open past the end of the data.
+    max_gap: u64,
+    /// Checksum of the bytes written since the device was last emptied, see
+    /// `SCULL_IOCGRUNNINGCRC`.
+    running_sum: u64,
+    /// Set by `SCULL_IOCSLABEL`, NUL-padded.
+    label: [u8; SCULL_LABEL_LEN],
+}
+
+impl ScullDevData {
+    fn new() -> Self {
+        ScullDevData {
+            data: None,
+            flat: *module_parameters::backend.value() as usize == SCULL_BACKEND_FLAT,
+            buffer: None,
+            quantum: default_geometry().0,
+            qset: default_geometry().1,
+            size: 0,
+            write_once: false,
+            protected: RangeList::new(),
+            node_count: 0,
+            max_nodes: *module_parameters::max_nodes.value(),
+            fill_byte: 0,
+            sentinel: None,
+            chunked: false,
+            record_size: 0,
+            append_only: *module_parameters::append_only.value() != 0,
+            versions: KVec::new(),
+            mode: SCULL_MODE_READ | SCULL_MODE_WRITE,
+            migration: None,
+            last_writer: ScullWriter::default(),
+            created_at: now_ns(),
+            atomic_alloc: false,
+            align: 0,
+            max_gap: SCULL_GAP_UNLIMITED,
+            running_sum: STREAM_SUM_INIT,
+            label: [0; SCULL_LABEL_LEN],
+        }
+    }
+
+    /// Makes a deep copy of the contents and geometry, preserving holes,
+    /// kept in the flat backend if `flat` and in qset nodes otherwise.
+    fn fork(&self, flat: bool) -> Result<Self> {
+        let mut copy = ScullDevData::new();
+        copy.flat = flat;
+        copy.quantum = self.quantum;
+        copy.qset = self.qset;
+        copy.fill_byte = self.fill_byte;
+        copy.sentinel = self.sentinel;
+        copy.atomic_alloc = self.atomic_alloc;
+        if flat == self.flat {
+            copy.data = copy_chain(self.data.as_deref())?;
+            copy.buffer = self.buffer.as_ref().map(Quantum::try_clone).transpose()?;
+            copy.node_count = self.node_count;
+        } else {
+            if self.size > copy.max_size() {
+                return Err(ScullError::OffsetOverflow.into());
+            }
+            self.copy_into(&mut copy, 0, self.size)?;
+        }
+        copy.size = self.size;
+        Ok(copy)
+    }
+
+    /// Checks that the contents may be discarded, as `SCULL_IOCRESET` does.
+    /// Protected ranges are dropped only if `force_protected`.
+    fn check_resettable(&mut self, force_protected: bool) -> Result {
+        self.check_not_migrating()?;
+        if self.append_only {
+            return Err(ScullError::Protected.into());
+        }
+        if !self.protected.is_empty() {
+            if !force_protected {
+                return Err(ScullError::Protected.into());
+            }
+            self.protected.clear();
+        }
+        Ok(())
+    }
+
+    /// Changes the quantum and qset size. This is the one place geometry is
+    /// changed, since every offset computation depends on it: with data
+    /// present it fails with `EBUSY`, unless `SCULL_GEOMETRY_FORCE` asks for
+    /// the device to be emptied first. Returns true if it was emptied.
+    fn set_geometry(&mut self, geometry: &ScullGeometry) -> Result<bool> {
+        let quantum = usize::try_from(geometry.quantum).map_err(|_| ScullError::InvalidArgument)?;
+        let qset = usize::try_from(geometry.qset).map_err(|_| ScullError::InvalidArgument)?;
+        if quantum == 0 || qset == 0 || quantum.checked_mul(qset).is_none() {
+            return Err(ScullError::InvalidArgument.into());
+        }
+        if geometry.flags & !SCULL_GEOMETRY_FORCE != 0 || self.align > quantum {
+            return Err(ScullError::InvalidArgument.into());
+        }
+
+        self.check_not_migrating()?;
+        let trimmed = !self.is_empty();
+        if trimmed {
+            if geometry.flags & SCULL_GEOMETRY_FORCE == 0 {
+                return Err(ScullError::Busy.into());
+            }
+            self.check_resettable(false)?;
+            self.trim();
+        }
+
+        self.quantum = quantum;
+        self.qset = qset;
+        Ok(trimmed)
+    }
+
+    /// Fails with `EACCES` unless all of the `SCULL_MODE_*` bits in `bits`
+    /// are set, see `SCULL_IOCSMODE`.
+    fn check_mode(&self, bits: usize) -> Result {
+        if self.mode & bits != bits {
+            return Err(ScullError::AccessDenied.into());
+        }
+        Ok(())
+    }
+
+    /// Fails with `EBUSY` while a background migration is running.
+    fn check_not_migrating(&self) -> Result {
+        if self.migration.is_some() {
+            return Err(ScullError::Busy.into());
+        }
+        Ok(())
+    }
+
+    /// Starts migrating the contents to `quantum`-byte quanta.
+    fn start_migration(&mut self, quantum: usize) -> Result {
+        self.check_not_migrating()?;
+        if quantum == 0 || quantum.checked_mul(self.qset).is_none() || self.align > quantum {
+            return Err(ScullError::InvalidArgument.into());
+        }
+        // The flat layout does not depend on the geometry: nothing to copy
+        if self.flat {
+            self.quantum = quantum;
+            return Ok(());
+        }
+
+        let mut target = KBox::new(ScullDevData::new(), GFP_KERNEL)?;
+        target.flat = self.flat;
+        target.quantum = quantum;
+        target.qset = self.qset;
+        target.fill_byte = self.fill_byte;
+        target.max_nodes = self.max_nodes;
+        target.atomic_alloc = self.atomic_alloc;
+        if self.size > target.max_size() {
+            return Err(ScullError::OffsetOverflow.into());
+        }
+        self.migration = Some(Migration { target, copied: 0 });
+        Ok(())
+    }
+
+    /// Copies the next step of an in-progress migration, swapping the new
+    /// contents in once everything is copied. Returns true when more work
+    /// remains.
+    fn migrate_step(&mut self) -> Result<bool> {
+        let Some(mut migration) = self.migration.take() else {
+            return Ok(false);
+        };
+
+        let len = (self.size - migration.copied).min(SCULL_MIGRATE_STEP);
+        self.copy_into(&mut migration.target, migration.copied, len)?;
+        migration.copied += len;
+
+        if migration.copied < self.size {
+            self.migration = Some(migration);
+            return Ok(true);
+        }
+
+        self.adopt_chain(&mut migration.target);
+        Ok(false)
+    }
+
+    /// Replaces the chain, or the flat buffer, with the one `target` built,
+    /// taking its geometry.
+    fn adopt_chain(&mut self, target: &mut ScullDevData) {
+        free_chain(self.data.take());
+        self.data = target.data.take();
+        self.buffer = target.buffer.take();
+        self.quantum = target.quantum;
+        self.qset = target.qset;
+        self.node_count = target.node_count;
+    }
+
+    /// Re-chunks the contents into `quantum`-byte quanta and `qset`-quanta
+    /// nodes in one go, preserving holes that cover whole new quanta.
+    ///
+    /// Old and new chains coexist until the swap, so node limits are checked
+    /// up front and quota is charged as the copy goes. On any failure,
+    /// including a fatal signal, the new chain is freed and the contents
+    /// are left untouched. A flat device just takes the new geometry, which
+    /// its layout does not depend on.
+    fn requantum(&mut self, quantum: usize, qset: usize) -> Result {
+        self.check_not_migrating()?;
+        let itemsize = quantum.checked_mul(qset).ok_or(ScullError::InvalidArgument)?;
+        if itemsize == 0 || self.align > quantum {
+            return Err(ScullError::InvalidArgument.into());
+        }
+        if self.flat {
+            self.quantum = quantum;
+            self.qset = qset;
+            return Ok(());
+        }
+        let nodes = self.size.div_ceil(itemsize as u64);
+        if self.max_nodes != 0 && nodes > self.max_nodes as u64 {
+            return Err(ScullError::TooManyNodes.into());
+        }
+
+        let mut target = ScullDevData::new();
+        target.flat = self.flat;
+        target.quantum = quantum;
+        target.qset = qset;
+        target.fill_byte = self.fill_byte;
+        target.max_nodes = self.max_nodes;
+        target.atomic_alloc = self.atomic_alloc;
+        if self.size > target.max_size() {
+            return Err(ScullError::OffsetOverflow.into());
+        }
+
+        let mut copied = 0;
+        while copied < self.size {
+            if fatal_signal_pending() {
+                return Err(EINTR);
+            }
+            let len = (self.size - copied).min(SCULL_MIGRATE_STEP);
+            self.copy_into(&mut target, copied, len)?;
+            copied += len;
+        }
+
+        self.adopt_chain(&mut target);
+        Ok(())
+    }
+
+    /// Drops the first `len` bytes of data, see `SCULL_IOCDISCARDFRONT`, and
+    /// returns how many were dropped.
+    ///
+    /// Discarding whole qset nodes just unlinks them. Anything else copies
+    /// the remaining data into a new chain first, so like `requantum` a
+    /// failure leaves the contents untouched.
+    fn discard_front(&mut self, len: u64) -> Result<u64> {
+        self.check_resettable(false)?;
+        // The sentinel stays put at the end
+        let len = len.min(self.data_end());
+        self.check_aligned(0, len)?;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let itemsize = (self.quantum * self.qset) as u64;
+        if !self.flat && len % itemsize == 0 {
+            for _ in 0..len / itemsize {
+                let Some(mut head) = self.data.take() else {
+                    break;
+                };
+                self.data = head.next.take();
+                free_chain(Some(head));
+                self.node_count -= 1;
+            }
+        } else {
+            let mut target = ScullDevData::new();
+            target.flat = self.flat;
+            target.quantum = self.quantum;
+            target.qset = self.qset;
+            target.fill_byte = self.fill_byte;
+            target.max_nodes = self.max_nodes;
+            target.atomic_alloc = self.atomic_alloc;
+
+            let mut copied = 0;
+            while len + copied < self.size {
+                if fatal_signal_pending() {
+                    return Err(EINTR);
+                }
+                let step = (self.size - len - copied).min(SCULL_MIGRATE_STEP);
+                self.copy_range(&mut target, len + copied, copied, step)?;
+                copied += step;
+            }
+            self.adopt_chain(&mut target);
+        }
+
+        self.size -= len;
+        Ok(len)
+    }
+
+    /// Returns the percentage of the running migration completed, or 100 if
+    /// none is running.
+    fn migration_progress(&self) -> u64 {
+        match &self.migration {
+            Some(migration) if self.size > 0 => migration.copied * 100 / self.size,
+            Some(_) => 0,
+            None => 100,
+        }
+    }
+
+    /// Fails with `EINVAL` unless `offset` and `len` meet the required
+    /// alignment.
+    fn check_aligned(&self, offset: u64, len: u64) -> Result {
+        let align = self.align as u64;
+        if align != 0 && (offset % align != 0 || len % align != 0) {
+            return Err(ScullError::InvalidArgument.into());
+        }
+        Ok(())
+    }
+
+    /// Returns the offset just past the data, excluding any trailing sentinel.
+    fn data_end(&self) -> u64 {
+        match self.sentinel {
+            Some(_) if self.size > 0 => self.size - 1,
+            _ => self.size,
+        }
+    }
+
+    /// Allocates every quantum covering `offset..offset + len`.
+    fn reserve(&mut self, offset: u64, len: usize, owner: u32) -> Result {
+        let mut done = 0;
+        while done < len {
+            let (_, _, n) = self.segment_mut(offset + done as u64, len - done, owner)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the device holds no data and no allocated storage
+    /// beyond an unused head node.
+    fn is_empty(&self) -> bool {
+        self.size == 0
+            && self.buffer.is_none()
+            && match &self.data {
+                None => true,
+                Some(node) => node.data.is_none() && node.next.is_none(),
+            }
+    }
+
+    /// Allocates the head node ahead of the first write, see the
+    /// `prealloc_head` parameter. Like any other node it is freed by `trim`.
+    fn prealloc_head(&mut self) -> Result {
+        if !self.flat && self.data.is_none() && self.migration.is_none() {
+            self.follow(0)?;
+        }
+        Ok(())
+    }
+
+    /// Called when the last open handle on the device is closed.
+    fn last_close(&mut self) {
+        pr_debug!("rust_scull: last close, size={}\n", self.size);
+    }
+
+    /// Empties the device.
+    fn trim(&mut self) {
+        free_chain(self.data.take());
+        self.buffer = None;
+        self.versions.clear();
+
+        self.size = 0;
+        self.node_count = 0;
+        self.running_sum = STREAM_SUM_INIT;
+        (self.quantum, self.qset) = default_geometry();
+        if self.align > self.quantum {
+            self.align = 0;
+        }
+    }
+
+    /// Frees all stored data but keeps the logical size, so the whole device
+    /// reads back as the fill byte until it is written again.
+    fn drop_cache(&mut self) {
+        free_chain(self.data.take());
+        self.buffer = None;
+        self.node_count = 0;
+    }
+
+    /// Returns the kernel memory held by the contents, committed versions and
+    /// any migration target, including node and qset array overhead. Quota
+    /// accounting only counts quantum bytes; this is the full figure.
+    fn footprint(&self) -> u64 {
+        let mut total = chain_footprint(self.data.as_deref());
+        total += self.buffer.as_ref().map_or(0, |buffer| buffer.footprint() as u64);
+        total += (self.versions.capacity() * size_of::<Version>()) as u64;
+        for version in self.versions.iter() {
+            total += chain_footprint(version.data.as_deref());
+            total += version.buffer.as_ref().map_or(0, |buffer| buffer.footprint() as u64);
+        }
+        if let Some(migration) = &self.migration {
+            total += size_of::<ScullDevData>() as u64;
+            total += migration.target.footprint();
+        }
+        total
+    }
+
+    /// Checks at most `max_nodes` qset nodes from node `first` on, plus the
+    /// whole-device invariants and the flat buffer when `first` is 0.
+    /// Returns the node to resume from, or `None` once the end of the chain
+    /// has been checked. A flat device has no nodes.
+    fn check(
+        &self,
+        first: usize,
+        max_nodes: usize,
+    ) -> core::result::Result<Option<usize>, Violation> {
+        if first == 0 {
+            if self.size > self.max_size() {
+                return Err(Violation::new(SCULL_CHECK_SIZE, 0, 0));
+            }
+            if !self.protected.is_canonical() {
+                return Err(Violation::new(SCULL_CHECK_PROTECTED, 0, 0));
+            }
+            if let Some(buffer) = &self.buffer {
+                if !self.flat {
+                    return Err(Violation::new(SCULL_CHECK_QUANTUM_LEN, 0, 0));
+                }
+                let len = buffer.buf.len();
+                if buffer.dirty.len() != len.div_ceil(64) {
+                    return Err(Violation::new(SCULL_CHECK_DIRTY_LEN, 0, 0));
+                }
+                if buffer.is_dirty(clamp_len(self.size).min(len), len) {
+                    return Err(Violation::new(SCULL_CHECK_DIRTY_PAST_SIZE, 0, 0));
+                }
+            }
+        }
+
+        let quantum = self.quantum as u64;
+        let itemsize = quantum * self.qset as u64;
+
+        let mut node = self.data.as_deref();
+        for _ in 0..first {
+            node = node.and_then(|qset_node| qset_node.next.as_deref());
+        }
+
+        let mut item = first;
+        while let Some(qset_node) = node {
+            if item - first == max_nodes {
+                return Ok(Some(item));
+            }
+            // A chain longer than its count is either a miscount or a cycle
+            if item >= self.node_count {
+                return Err(Violation::new(SCULL_CHECK_NODE_COUNT, item, 0));
+            }
+            if let Some(data_array) = &qset_node.data {
+                if data_array.len() != self.qset {
+                    return Err(Violation::new(SCULL_CHECK_QSET_LEN, item, 0));
+                }
+                for (s_pos, quantum_opt) in data_array.iter().enumerate() {
+                    let Some(quantum_buf) = quantum_opt else {
+                        continue;
+                    };
+                    let len = quantum_buf.buf.len();
+                    if len > self.quantum {
+                        return Err(Violation::new(SCULL_CHECK_QUANTUM_LEN, item, s_pos));
+                    }
+                    if quantum_buf.dirty.len() != len.div_ceil(64) {
+                        return Err(Violation::new(SCULL_CHECK_DIRTY_LEN, item, s_pos));
+                    }
+                    let start = item as u64 * itemsize + s_pos as u64 * quantum;
+                    let valid = clamp_len(self.size.saturating_sub(start)).min(len);
+                    if quantum_buf.is_dirty(valid, len) {
+                        return Err(Violation::new(SCULL_CHECK_DIRTY_PAST_SIZE, item, s_pos));
+                    }
+                }
+            }
+            node = qset_node.next.as_deref();
+            item += 1;
+        }
+        if item != self.node_count.max(first) {
+            return Err(Violation::new(SCULL_CHECK_NODE_COUNT, item, 0));
+        }
+        Ok(None)
+    }
+
+    /// Logs the geometry and the allocated quanta of each node.
+    fn dump_layout(&self) {
+        pr_warn!(
+            "rust_scull: size={} quantum={} qset={} nodes={}\n",
+            self.size,
+            self.quantum,
+            self.qset,
+            self.node_count
+        );
+        if let Some(buffer) = &self.buffer {
+            pr_warn!(
+                "rust_scull:   flat buffer: {} bytes, {} reserved\n",
+                buffer.buf.len(),
+                buffer.buf.capacity()
+            );
+        }
+        let mut node = self.data.as_deref();
+        let mut item = 0;
+        while let Some(qset_node) = node {
+            match &qset_node.data {
+                Some(data_array) => {
+                    let quanta = data_array.iter().flatten().count();
+                    pr_warn!("rust_scull:   node {}: {} quanta\n", item, quanta);
+                }
+                None => pr_warn!("rust_scull:   node {}: no qset array\n", item),
+            }
+            // Bounded, in case the chain is what is broken
+            item += 1;
+            if item > self.node_count {
+                break;
+            }
+            node = qset_node.next.as_deref();
+        }
+    }
+
+    /// Counts the valid and padding bytes of each allocated quantum. The
+    /// flat buffer counts as one quantum of its current length.
+    fn fill_stats(&self) -> ScullFillStats {
+        let mut stats = ScullFillStats::default();
+        if let Some(buffer) = &self.buffer {
+            let len = buffer.buf.len() as u64;
+            let valid = self.size.min(len);
+            stats.quanta = 1;
+            match valid {
+                0 => {}
+                v if v == len => stats.full = 1,
+                _ => stats.partial = 1,
+            }
+            stats.valid_bytes = valid;
+            stats.padding_bytes = len - valid;
+        }
+        let quantum = self.quantum as u64;
+        let itemsize = quantum * self.qset as u64;
+
+        let mut node = self.data.as_deref();
+        let mut node_start = 0;
+        while let Some(qset_node) = node {
+            let data_array = qset_node.data.iter().flat_map(|data_array| data_array.iter());
+            for (s_pos, quantum_opt) in data_array.enumerate() {
+                if quantum_opt.is_none() {
+                    continue;
+                }
+                let start = node_start + s_pos as u64 * quantum;
+                let valid = self.size.saturating_sub(start).min(quantum);
+                stats.quanta += 1;
+                match valid {
+                    0 => {}
+                    v if v == quantum => stats.full += 1,
+                    _ => stats.partial += 1,
+                }
+                stats.valid_bytes += valid;
+                stats.padding_bytes += quantum - valid;
+            }
+            node = qset_node.next.as_deref();
+            node_start += itemsize;
+        }
+        stats
+    }
+
+    /// Frees every quantum that holds nothing but the fill byte, which a hole
+    /// reads back as anyway. Returns the number of bytes freed.
+    ///
+    /// Skipped in write-once mode, where freeing would forget which bytes
+    /// were written, and while a migration is copying the chain.
+    fn compact(&mut self) -> usize {
+        if self.write_once || self.migration.is_some() {
+            return 0;
+        }
+
+        let fill_byte = self.fill_byte;
+        let mut freed = 0;
+        let unused = self
+            .buffer
+            .as_ref()
+            .is_some_and(|buffer| buffer.buf.iter().all(|&b| b == fill_byte));
+        if unused {
+            freed += self.buffer.take().map_or(0, |buffer| buffer.buf.len());
+        }
+        let mut node = self.data.as_deref_mut();
+        while let Some(qset) = node {
+            for quantum_opt in qset.data.iter_mut().flatten() {
+                let unused = quantum_opt
+                    .as_ref()
+                    .is_some_and(|quantum_buf| quantum_buf.buf.iter().all(|&b| b == fill_byte));
+                if unused {
+                    // Dropping the quantum credits its owner's quota
+                    freed += quantum_opt.take().map_or(0, |quantum_buf| quantum_buf.buf.len());
+                }
+            }
+            node = qset.next.as_deref_mut();
+        }
+        freed
+    }
+
+    /// Retains a deep copy of the current contents, evicting the oldest
+    /// version once `keep_versions` are held.
+    fn commit(&mut self) -> Result {
+        let keep = *module_parameters::keep_versions.value();
+        if keep == 0 {
+            return Err(ScullError::NotSupported.into());
+        }
+
+        let version = Version {
+            data: copy_chain(self.data.as_deref())?,
+            buffer: self.buffer.as_ref().map(Quantum::try_clone).transpose()?,
+            quantum: self.quantum,
+            qset: self.qset,
+            size: self.size,
+            node_count: self.node_count,
+        };
+        while self.versions.len() >= keep {
+            self.versions.remove(0)?;
+        }
+        self.versions.push(version, GFP_KERNEL)?;
+        Ok(())
+    }
+
+    /// Swaps the live contents with a retained version; index 0 is the most
+    /// recent commit.
+    fn rollback(&mut self, index: usize) -> Result {
+        let len = self.versions.len();
+        if index >= len {
+            return Err(ScullError::InvalidArgument.into());
+        }
+
+        let version = &mut self.versions[len - 1 - index];
+        core::mem::swap(&mut self.data, &mut version.data);
+        core::mem::swap(&mut self.buffer, &mut version.buffer);
+        core::mem::swap(&mut self.quantum, &mut version.quantum);
+        core::mem::swap(&mut self.qset, &mut version.qset);
+        core::mem::swap(&mut self.size, &mut version.size);
+        core::mem::swap(&mut self.node_count, &mut version.node_count);
+        Ok(())
+    }
+
+    /// Returns the largest size the geometry can address.
+    ///
+    /// A node index must fit in `usize`, so on a 32-bit kernel a device
+    /// spans at most 2^32 nodes of `quantum * qset` bytes. On 64-bit kernels
+    /// the only limit is `i64::MAX`, the largest file offset. A flat device
+    /// is a single kvmalloc allocation, which cannot exceed `i32::MAX` bytes.
+    fn max_size(&self) -> u64 {
+        if self.flat {
+            return i32::MAX as u64;
+        }
+        let itemsize = (self.quantum * self.qset) as u64;
+        (usize::MAX as u64)
+            .saturating_add(1)
+            .saturating_mul(itemsize)
+            .min(i64::MAX as u64)
+    }
+
+    /// Splits `offset` as `split_offset` does for this device's geometry.
+    /// The caller must ensure `quantum * qset != 0` and `offset <=
+    /// max_size()`, which writes and geometry changes maintain for every
+    /// offset below `size`.
+    fn position(&self, offset: u64) -> (usize, usize, usize) {
+        split_offset(offset, self.quantum, self.qset)
+    }
+
+    /// Returns how many of the `count` bytes from `offset` on lie in one
+    /// piece of storage: the rest of the quantum with qset nodes, all of them
+    /// with the flat buffer. The caller must ensure `quantum * qset != 0`.
+    fn span(&self, offset: u64, count: usize) -> usize {
+        if self.flat {
+            return count;
+        }
+        let (_, _, q_pos) = self.position(offset);
+        quantum_span(count, self.quantum, q_pos)
+    }
+
+    /// Finds the storage holding `offset` without allocating. Returns the
+    /// quantum, or `None` for a hole, the index of `offset` within it, and
+    /// how many of the `count` bytes from `offset` on it covers. The caller
+    /// must ensure `quantum * qset != 0`.
+    fn segment(&self, offset: u64, count: usize) -> (Option<&Quantum>, usize, usize) {
+        if self.flat {
+            return match &self.buffer {
+                Some(buffer) if offset < buffer.buf.len() as u64 => {
+                    let pos = offset as usize;
+                    (Some(buffer), pos, count.min(buffer.buf.len() - pos))
+                }
+                // Everything past the last byte stored is one hole
+                _ => (None, 0, count),
+            };
+        }
+        let (item, s_pos, q_pos) = self.position(offset);
+        let len = quantum_span(count, self.quantum, q_pos);
+        (self.quantum_at(item, s_pos), q_pos, len)
+    }
+
+    /// Like `segment`, but allocates the storage as needed. The flat buffer
+    /// grows to cover all `count` bytes at once.
+    fn segment_mut(
+        &mut self,
+        offset: u64,
+        count: usize,
+        owner: u32,
+    ) -> Result<(&mut Quantum, usize, usize)> {
+        if self.flat {
+            // `max_size()` keeps every offset written to well within `usize`
+            let pos = offset as usize;
+            let end = pos + count;
+            let fill_byte = self.fill_byte;
+            let gfp = self.gfp();
+            match self.buffer.as_mut() {
+                Some(buffer) if end > buffer.buf.len() => {
+                    check_injected_fault()?;
+                    buffer.grow(end, fill_byte, gfp)?;
+                }
+                Some(_) => {}
+                None => {
+                    check_injected_fault()?;
+                    self.buffer = Some(Quantum::new(end, fill_byte, owner, gfp)?);
+                }
+            }
+            return Ok((self.buffer.as_mut().unwrap(), pos, count));
+        }
+        let (item, s_pos, q_pos) = self.position(offset);
+        let len = quantum_span(count, self.quantum, q_pos);
+        Ok((self.quantum_mut(item, s_pos, owner)?, q_pos, len))
+    }
+
+    /// Returns quantum `s_pos` of node `item`, if allocated. A missing node,
+    /// qset array or quantum all yield `None`, i.e. a hole.
+    fn quantum_at(&self, item: usize, s_pos: usize) -> Option<&Quantum> {
+        // Follow the list (read-only, no allocation)
+        let mut dptr = self.data.as_deref();
+        for _ in 0..item {
+            dptr = dptr.and_then(|node| node.next.as_deref());
+        }
+
+        dptr.and_then(|node| node.data.as_ref())
+            .and_then(|data_array| data_array.get(s_pos))
+            .and_then(|quantum_opt| quantum_opt.as_ref())
+    }
+
+    /// Fills `out` with the allocated extents from `start` on, examining at
+    /// most `SCULL_FIEMAP_MAX_QUANTA` quanta. Returns the number of extents
+    /// and the offset to resume from.
+    fn map_extents(&self, start: u64, out: &mut [ScullExtent]) -> (usize, u64) {
+        let end = self.data_end();
+        if self.quantum * self.qset == 0 || start >= end {
+            return (0, end);
+        }
+        if self.flat {
+            let stored = self.buffer.as_ref().map_or(0, |buffer| buffer.buf.len() as u64);
+            if start >= stored {
+                return (0, end);
+            }
+            let Some(extent) = out.first_mut() else {
+                return (0, start);
+            };
+            *extent = ScullExtent {
+                logical_offset: start,
+                length: stored.min(end) - start,
+                flags: SCULL_EXTENT_LAST,
+                ..Default::default()
+            };
+            return (1, end);
+        }
+
+        let quantum = self.quantum as u64;
+        let mut pos = start - start % quantum;
+        let (item, mut s_pos, _) = self.position(pos);
+        let mut node = self.data.as_deref();
+        for _ in 0..item {
+            node = node.and_then(|node| node.next.as_deref());
+        }
+
+        let mut n = 0;
+        let mut budget = SCULL_FIEMAP_MAX_QUANTA;
+        while pos < end && budget > 0 {
+            let allocated = node
+                .and_then(|node| node.data.as_ref())
+                .and_then(|data_array| data_array.get(s_pos))
+                .is_some_and(|quantum_opt| quantum_opt.is_some());
+
+            if allocated {
+                let ext_start = pos.max(start);
+                let ext_end = (pos + quantum).min(end);
+                match out[..n].last_mut() {
+                    Some(last) if last.logical_offset + last.length == ext_start => {
+                        last.length += ext_end - ext_start;
+                    }
+                    _ if n == out.len() => break,
+                    _ => {
+                        out[n] = ScullExtent {
+                            logical_offset: ext_start,
+                            length: ext_end - ext_start,
+                            ..Default::default()
+                        };
+                        n += 1;
+                    }
+                }
+            }
+
+            pos += quantum;
+            budget -= 1;
+            s_pos += 1;
+            if s_pos == self.qset {
+                s_pos = 0;
+                node = node.and_then(|node| node.next.as_deref());
+            }
+        }
+
+        if pos >= end {
+            if let Some(last) = out[..n].last_mut() {
+                last.flags |= SCULL_EXTENT_LAST;
+            }
+        }
+        (n, pos.clamp(start, end))
+    }
+
+    /// Finds the stored bytes at `offset` without allocating.
+    ///
+    /// Returns how many of the `count` requested bytes lie within the quantum
+    /// containing `offset`, along with those bytes, or `None` if that quantum
+    /// has not been allocated. The caller must ensure `quantum * qset != 0`.
+    fn lookup(&self, offset: u64, count: usize) -> (usize, Option<&[u8]>) {
+        let (quantum_buf, pos, len) = self.segment(offset, count);
+        (len, quantum_buf.map(|quantum_buf| &quantum_buf.buf[pos..pos + len]))
+    }
+
+    /// Copies `count` bytes at `offset` to `writer`, holes reading as the fill
+    /// byte. The caller must ensure `quantum * qset != 0`.
+    fn copy_to_user(&self, offset: u64, count: usize, writer: &mut UserSliceWriter) -> Result {
+        let mut done = 0;
+        while done < count {
+            let (len, slice) = self.lookup(offset + done as u64, count - done);
+            match slice {
+                Some(bytes) => writer.write_slice(bytes)?,
+                None => write_fill(writer, self.fill_byte, len)?,
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Returns true if any byte in `offset..offset + count` has been written.
+    fn is_written(&self, offset: u64, count: usize) -> bool {
+        let mut done = 0;
+        while done < count {
+            let (quantum_buf, pos, len) = self.segment(offset + done as u64, count - done);
+            if quantum_buf.is_some_and(|quantum_buf| quantum_buf.is_dirty(pos, pos + len)) {
+                return true;
+            }
+            done += len;
+        }
+        false
+    }
+
+    /// Returns the flags storage is allocated with.
+    fn gfp(&self) -> Flags {
+        if self.atomic_alloc {
+            GFP_ATOMIC
+        } else {
+            GFP_KERNEL
+        }
+    }
+
+    /// Returns quantum `s_pos` of node `item`, allocating the node, its qset
+    /// array and the quantum as needed.
+    fn quantum_mut(&mut self, item: usize, s_pos: usize, owner: u32) -> Result<&mut Quantum> {
+        let qset = self.qset;
+        let quantum = self.quantum;
+        let fill_byte = self.fill_byte;
+        let gfp = self.gfp();
+        let dptr = self.follow(item)?;
+
+        if dptr.data.is_none() {
+            check_injected_fault()?;
+            let mut qset_vec = KVec::new();
+            while qset_vec.len() < qset {
+                qset_vec.push(None, gfp)?;
+            }
+            dptr.data = Some(qset_vec);
+        }
+        let data_array = dptr.data.as_mut().unwrap();
+
+        if data_array[s_pos].is_none() {
+            check_injected_fault()?;
+            data_array[s_pos] = Some(Quantum::new(quantum, fill_byte, owner, gfp)?);
+        }
+        Ok(data_array[s_pos].as_mut().unwrap())
+    }
+
+    /// Copies `bytes` in at `offset`, growing the device as it goes. For
+    /// in-kernel callers: no mode, protection or alignment checks apply.
+    fn store(&mut self, offset: u64, bytes: &[u8], owner: u32) -> Result {
+        let mut done = 0;
+        while done < bytes.len() {
+            let (quantum_buf, pos, len) =
+                self.segment_mut(offset + done as u64, bytes.len() - done, owner)?;
+            quantum_buf.buf[pos..pos + len].copy_from_slice(&bytes[done..done + len]);
+            quantum_buf.mark_dirty(pos, pos + len);
+            done += len;
+            self.size = self.size.max(offset + done as u64);
+        }
+        Ok(())
+    }
+
+    /// Copies the stored bytes in `offset..offset + len` into `target`, which
+    /// may use a different geometry or backend. Holes are skipped, so they
+    /// stay holes wherever they cover whole quanta of `target`.
+    fn copy_into(&self, target: &mut ScullDevData, offset: u64, len: u64) -> Result {
+        self.copy_range(target, offset, offset, len)
+    }
+
+    /// Like `copy_into`, but the bytes from `from` land at `to` in `target`.
+    fn copy_range(&self, target: &mut ScullDevData, from: u64, to: u64, len: u64) -> Result {
+        let mut done = 0;
+        while done < len {
+            let (src, q_pos, n) = self.segment(from + done, clamp_len(len - done));
+            let n = target.span(to + done, n);
+
+            if let Some(src) = src {
+                let (dst, t_q_pos, _) = target.segment_mut(to + done, n, src.owner)?;
+                dst.buf[t_q_pos..t_q_pos + n].copy_from_slice(&src.buf[q_pos..q_pos + n]);
+                for i in 0..n {
+                    if src.is_dirty(q_pos + i, q_pos + i + 1) {
+                        dst.mark_dirty(t_q_pos + i, t_q_pos + i + 1);
+                    }
+                }
+            }
+            done += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Allocates an empty qset node, failing with `EFBIG` if the device's
+    /// node limit has been reached.
+    fn new_node(node_count: &mut usize, max_nodes: usize, gfp: Flags) -> Result<KBox<ScullQset>> {
+        if max_nodes != 0 && *node_count >= max_nodes {
+            return Err(ScullError::TooManyNodes.into());
+        }
+
+        check_injected_fault()?;
+        let node = KBox::new(ScullQset::new(None, None), gfp)?;
+        *node_count += 1;
+        Ok(node)
+    }
+
+    fn follow(&mut self, item: usize) -> Result<&mut ScullQset> {
+        let gfp = self.gfp();
+        let current = &mut self.data;
+
+        // Allocate first qset if needed
+        if current.is_none() {
+            *current = Some(Self::new_node(&mut self.node_count, self.max_nodes, gfp)?);
+        }
+
+        let mut current_node = current.as_mut().unwrap();
+
+        // Follow the list `item` times
+        for _ in 0..item {
+            if current_node.next.is_none() {
+                current_node.next =
+                    Some(Self::new_node(&mut self.node_count, self.max_nodes, gfp)?);
+            }
+            current_node = current_node.next.as_mut().unwrap();
+        }
+
+        Ok(&mut **current_node)
+    }
+}
+
+/// A counter with one slot per possible CPU, each on its own cache line, so
+/// that CPUs adding to it concurrently don't bounce a shared line between
+/// them. Reading it sums every slot.
+struct PerCpuCounter {
+    slots: KVec<CounterSlot>,
+}
+
+/// One CPU's share of a `PerCpuCounter`, a cache line on most architectures.
+#[repr(align(64))]
+#[derive(Default)]
+struct CounterSlot(AtomicU64);
+
+impl PerCpuCounter {
+    fn new() -> Result<Self> {
+        let cpus = nr_cpu_ids() as usize;
+        let mut slots = KVec::with_capacity(cpus, GFP_KERNEL)?;
+        for _ in 0..cpus {
+            slots.push(CounterSlot::default(), GFP_KERNEL)?;
+        }
+        Ok(PerCpuCounter { slots })
+    }
+
+    /// Adds `n` to the current CPU's slot.
+    fn add(&self, n: u64) {
+        // Being migrated before the add only means touching another CPU's
+        // line this once; the slot is atomic, so the total stays exact
+        let cpu = CpuId::current().as_usize();
+        self.slots[cpu].0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Returns the total. Slots cover every possible CPU and are never
+    /// cleared, so whatever a CPU counted before going offline is included.
+    fn sum(&self) -> u64 {
+        self.slots
+            .iter()
+            .fold(0, |sum, slot| sum.wrapping_add(slot.0.load(Ordering::Relaxed)))
+    }
+}
+
+// --- Device Implementation ---
+
+/// Returns the lockdep class of the `data` lock of device `index`: 0 for
+/// `/dev/scull`, 1 + slot for dynamic devices.
+///
+/// Each `static_lock_class!()` expands to its own static key, so every
+/// device gets a distinct class instead of all collapsing into one.
+fn data_lock_class(index: usize) -> Pin<&'static LockClassKey> {
+    match index {
+        0 => static_lock_class!(),
+        1 => static_lock_class!(),
+        2 => static_lock_class!(),
+        3 => static_lock_class!(),
+        4 => static_lock_class!(),
+        5 => static_lock_class!(),
+        6 => static_lock_class!(),
+        7 => static_lock_class!(),
+        _ => static_lock_class!(),
+    }
+}
+
+/// State shared by every handle opened on a device.
+///
+/// Lock order: `DYN_DEVICES`, then `data`, then any of `locks`, `log` and
+/// `watchers`. No path holds the `data` locks of two devices at once: fork,
+/// concat reads and compaction each take one at a time. One that needs to
+/// must take them in ascending device index order.
+#[pin_data]
+struct ScullDev {
+    #[pin]
+    data: Mutex<ScullDevData>,
+    #[pin]
+    log: SpinLock<OpLog>,
+    log_enabled: AtomicBool,
+    /// Advisory range locks, see `SCULL_IOCLOCK`.
+    #[pin]
+    locks: Mutex<KVec<RangeLock>>,
+    /// Lock requests wait here for conflicting locks to be released.
+    #[pin]
+    lock_wait: CondVar,
+    /// Runs background migrations one step at a time.
+    #[pin]
+    migrate_work: Work<ScullDev>,
+    /// Stops a running migration, e.g. on module unload.
+    migrate_abort: AtomicBool,
+    /// Set by `SCULL_IOCSHUTDOWN`; refuses new opens.
+    shutdown: AtomicBool,
+    /// Bumped under `data` on every change to the contents or size, see
+    /// `SCULL_IOCGGEN`. Every path that trims (`SCULL_IOCRESET`, a forced
+    /// `SCULL_IOCSGEOMETRY`) bumps it. Compaction and migration free or
+    /// move quanta without changing what reads return and do not, so a
+    /// matching generation does not mean a pointer into the data is still
+    /// valid; anything caching one must hold `data`.
+    generation: AtomicU64,
+    /// One queue per open `/dev/scull.events` handle.
+    #[pin]
+    watchers: Mutex<KVec<EventQueue>>,
+    /// Events readers wait here for new records.
+    #[pin]
+    event_wait: CondVar,
+    // Pure counters, updated without holding `data`. The byte counts are
+    // bumped on every read and write, so they are split per CPU.
+    bytes_read: PerCpuCounter,
+    bytes_written: PerCpuCounter,
+    open_count: AtomicUsize,
+    /// Cap on `open_count` (0 = unlimited), see `SCULL_IOCSMAXOPENS`.
+    max_opens: AtomicUsize,
+}
+
+impl ScullDev {
+    fn new(
+        data: ScullDevData,
+        name: &'static CStr,
+        class: Pin<&'static LockClassKey>,
+    ) -> impl PinInit<Self, Error> {
+        try_pin_init!(ScullDev {
+            data <- Mutex::new(data, name, class),
+            log <- new_spinlock!(OpLog::new()?, "ScullOpLog"),
+            log_enabled: AtomicBool::new(true),
+            locks <- new_mutex!(KVec::new(), "ScullDev::locks"),
+            lock_wait <- new_condvar!("ScullDev::lock_wait"),
+            migrate_work <- new_work!("ScullDev::migrate_work"),
+            migrate_abort: AtomicBool::new(false),
+            shutdown: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            watchers <- new_mutex!(KVec::new(), "ScullDev::watchers"),
+            event_wait <- new_condvar!("ScullDev::event_wait"),
+            bytes_read: PerCpuCounter::new()?,
+            bytes_written: PerCpuCounter::new()?,
+            open_count: AtomicUsize::new(0),
+            max_opens: AtomicUsize::new(0),
+        })
+    }
+
+    /// Queues the next migration step.
+    fn schedule_migration(this: Arc<ScullDev>) {
+        // Already queued is fine: the queued run picks up where we are
+        let _ = workqueue::system().enqueue(this);
+    }
+
+    /// Aborts any running migration and waits for its work item to finish.
+    ///
+    /// A running step re-queues itself only if the abort flag is still clear
+    /// when it finishes, so once `cancel_work_sync` returns no step is queued
+    /// or running.
+    fn stop_migration(&self) {
+        self.migrate_abort.store(true, Ordering::Relaxed);
+        // SAFETY: `migrate_work` was initialised by `new_work!` and is pinned
+        // inside this `ScullDev`.
+        if unsafe { bindings::cancel_work_sync(Work::raw_get(&self.migrate_work)) } {
+            // A queued step was cancelled before it ran, so drop the
+            // reference it owned
+            // SAFETY: Enqueueing an `Arc<ScullDev>` hands the queue one
+            // reference made by `Arc::into_raw`, and `self` is that `ScullDev`.
+            drop(unsafe { Arc::from_raw(core::ptr::from_ref(self)) });
+        }
+        self.data.lock().migration = None;
+    }
+
+    /// Serves `SCULL_IOCSHUTDOWN`, waking everything blocked in
+    /// `range_lock` or `read_events` so that it fails with `ENODEV` too.
+    fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        // A waiter checks the flag under the same lock it sleeps on, so once
+        // we have held that lock it is either asleep or will see the flag
+        drop(self.locks.lock());
+        self.lock_wait.notify_all();
+        drop(self.watchers.lock());
+        self.event_wait.notify_all();
+    }
+
+    /// Counts a new open handle, failing with `EBUSY` if `max_opens` are
+    /// already open.
+    fn count_open(&self) -> Result {
+        let max_opens = self.max_opens.load(Ordering::Relaxed);
+        self.open_count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (max_opens == 0 || n < max_opens).then_some(n + 1)
+            })
+            .map_err(|_| ScullError::Busy)?;
+        Ok(())
+    }
+
+    /// Undoes `count_open` for the file `id`, dropping its range locks.
+    /// Returns true if that was the last open handle.
+    fn release(&self, id: usize) -> bool {
+        self.range_unlock(id, 0, u64::MAX);
+
+        // Key "last close" off the open count rather than the Arc refcount, which
+        // also counts the reference held by the registration.
+        let last = self.open_count.fetch_sub(1, Ordering::AcqRel) == 1;
+        if last {
+            self.data.lock().last_close();
+        }
+        last
+    }
+
+    /// Fails with `ENODEV` once `SCULL_IOCSHUTDOWN` has been issued. Every
+    /// operation on an open handle starts with this.
+    fn check_live(&self) -> Result {
+        if self.shutdown.load(Ordering::Relaxed) {
+            return Err(ENODEV);
+        }
+        Ok(())
+    }
+
+    /// Bumps the mutation counter. Callers hold the `data` lock.
+    fn next_generation(&self) -> u64 {
+        self.generation.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Queues a record of a successful write or reset for every events reader.
+    fn emit_event(&self, offset: u64, len: u64, generation: u64) {
+        let event = ScullEvent {
+            offset,
+            len,
+            generation,
+            timestamp_ns: now_ns(),
+            pid: current!().pid(),
+            flags: 0,
+        };
+
+        let mut watchers = self.watchers.lock();
+        if watchers.is_empty() {
+            return;
+        }
+        for queue in watchers.iter_mut() {
+            queue.push(event);
+        }
+        drop(watchers);
+        self.event_wait.notify_all();
+    }
+
+    /// Copies whole pending records of `owner`'s queue into `iov`, blocking
+    /// until there is at least one unless `nonblock`.
+    fn read_events(
+        &self,
+        owner: usize,
+        iov: &mut IovIterDest<'_>,
+        nonblock: bool,
+    ) -> Result<usize> {
+        if iov.len() < size_of::<ScullEvent>() {
+            return Err(ScullError::InvalidArgument.into());
+        }
+
+        let mut watchers = self.watchers.lock();
+        loop {
+            let queue = watchers
+                .iter_mut()
+                .find(|queue| queue.owner == owner)
+                .ok_or(ScullError::InvalidArgument)?;
+
+            let mut done = 0;
+            while iov.len() >= size_of::<ScullEvent>() {
+                let Some(event) = queue.pop() else { break };
+                done += iov.copy_to_iter(event.as_bytes());
+            }
+            if done > 0 {
+                return Ok(done);
+            }
+
+            if nonblock {
+                return Err(EAGAIN);
+            }
+            self.check_live()?;
+            if self.event_wait.wait_interruptible(&mut watchers) {
+                return Err(ERESTARTSYS);
+            }
+        }
+    }
+
+    fn stats(&self) -> ScullStats {
+        ScullStats {
+            size: 0,
+            reserved: 0,
+            bytes_read: self.bytes_read.sum(),
+            bytes_written: self.bytes_written.sum(),
+            open_count: self.open_count.load(Ordering::Relaxed) as u64,
+        }
+    }
+
+    /// Checks the nodes `first..first + max_nodes` as `ScullDevData::check`
+    /// does, plus the range locks when `first` is 0.
+    fn check(
+        &self,
+        first: usize,
+        max_nodes: usize,
+    ) -> core::result::Result<Option<usize>, Violation> {
+        let inner = self.data.lock();
+        let next = inner.check(first, max_nodes)?;
+        if first == 0 {
+            let locks = self.locks.lock();
+            for (i, lock) in locks.iter().enumerate() {
+                let conflict = locks[i + 1..]
+                    .iter()
+                    .any(|other| other.conflicts(lock.owner, lock.start, lock.end, lock.exclusive));
+                if lock.start >= lock.end || conflict {
+                    return Err(Violation::new(SCULL_CHECK_LOCKS, 0, 0));
+                }
+            }
+        }
+        Ok(next)
+    }
+
+    /// With the `paranoid` parameter set, checks the device's invariants
+    /// after `op` and reports the first violation seen by the module.
+    fn paranoid_check(&self, op: &str) {
+        if !paranoid() || PARANOID_WARNED.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if let Err(violation) = self.check(0, usize::MAX) {
+            if !PARANOID_WARNED.swap(true, Ordering::Relaxed) {
+                pr_err!(
+                    "rust_scull: invariant broken after {}: {} (node {}, quantum {})\n",
+                    op,
+                    violation.describe(),
+                    violation.item,
+                    violation.s_pos
+                );
+                self.data.lock().dump_layout();
+                kernel::warn_on!(true);
+            }
+        }
+    }
+
+    /// Records an operation in the log unless logging is disabled.
+    fn log_op(&self, op: u32, offset: u64, len: u64, result: i64) {
+        if !self.log_enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let entry = ScullLogEntry {
+            timestamp_ns: now_ns(),
+            offset,
+            len,
+            result,
+            pid: current!().pid(),
+            op,
+        };
+        self.log.lock().push(entry);
+    }
+
+    /// Copies up to `req.count` log records to userspace, newest first.
+    fn get_log(&self, req: ScullLogReq) -> Result<usize> {
+        // Snapshot under the spinlock, copy out after dropping it
+        let mut snapshot = KVec::new();
+        let max = (req.count as usize).min(SCULL_LOG_ENTRIES);
+        snapshot.resize(max, ScullLogEntry::default(), GFP_KERNEL)?;
+        let n = self.log.lock().copy_newest(&mut snapshot);
+
+        let mut writer = UserSlice::new(
+            UserPtr::from_addr(req.buf as usize),
+            n * size_of::<ScullLogEntry>(),
+        )
+        .writer();
+        for entry in snapshot[..n].iter() {
+            writer.write(entry)?;
+        }
+        Ok(n)
+    }
+
+    /// Describes this device as `name` for `SCULL_IOCLIST`.
+    fn info(&self, name: &CStr) -> ScullDevInfo {
+        let mut info = ScullDevInfo::default();
+        let name = name.as_bytes();
+        // Leave room for the terminating NUL
+        let len = name.len().min(info.name.len() - 1);
+        info.name[..len].copy_from_slice(&name[..len]);
+
+        let inner = self.data.lock();
+        info.size = inner.size;
+        info.quantum = inner.quantum as u64;
+        info.qset = inner.qset as u64;
+        info
+    }
+
+    /// Copies up to `req.count` entries describing this device, which must be
+    /// `/dev/scull`, and each dynamic device to userspace.
+    fn list_devices(&self, req: ScullListReq) -> Result<usize> {
+        // Snapshot under the locks, copy out after dropping them
+        let mut entries = KVec::with_capacity(1 + SCULL_DYN_NAMES.len(), GFP_KERNEL)?;
+        entries.push(self.info(c_str!("scull")), GFP_KERNEL)?;
+        for (slot, dev) in DYN_DEVICES.lock().iter().enumerate() {
+            if let Some(dev) = dev {
+                entries.push(dev.dev.info(SCULL_DYN_NAMES[slot]), GFP_KERNEL)?;
+            }
+        }
+
+        let n = entries.len().min(clamp_len(req.count));
+        let mut writer = UserSlice::new(
+            UserPtr::from_addr(req.buf as usize),
+            n * size_of::<ScullDevInfo>(),
+        )
+        .writer();
+        for entry in entries[..n].iter() {
+            writer.write(entry)?;
+        }
+        Ok(n)
+    }
+
+    /// Serves `SCULL_IOCREADEX`, filling in the out fields of `req`.
+    fn read_ex(&self, req: &mut ScullReadEx) -> Result<usize> {
+        let inner = self.data.lock();
+
+        inner.check_mode(SCULL_MODE_READ)?;
+        if inner.quantum * inner.qset == 0 {
+            return Err(ScullError::QuantumZero.into());
+        }
+
+        let end = inner.data_end();
+        let count = match end.checked_sub(req.offset) {
+            Some(avail) => clamp_len(req.len.min(avail)),
+            None => 0,
+        };
+        inner.check_aligned(req.offset, req.len)?;
+
+        let mut writer = UserSlice::new(UserPtr::from_addr(req.buf as usize), count).writer();
+        inner.copy_to_user(req.offset, count, &mut writer)?;
+        drop(inner);
+
+        req.copied = count as u64;
+        req.more = (req.offset + req.copied < end) as u32;
+        self.bytes_read.add(count as u64);
+        Ok(count)
+    }
+
+    /// Maps extents for `SCULL_IOCFIEMAP`, updating `req` in place.
+    fn fiemap(&self, req: &mut ScullFiemap) -> Result<usize> {
+        // Allocate before taking the lock, copy out after dropping it
+        let mut extents = KVec::new();
+        let max = (req.count as usize).min(SCULL_FIEMAP_MAX_EXTENTS);
+        extents.resize(max, ScullExtent::default(), GFP_KERNEL)?;
+        let (n, next) = self.data.lock().map_extents(req.start, &mut extents);
+
+        let mut writer = UserSlice::new(
+            UserPtr::from_addr(req.buf as usize),
+            n * size_of::<ScullExtent>(),
+        )
+        .writer();
+        for extent in extents[..n].iter() {
+            writer.write(extent)?;
+        }
+
+        req.mapped = n as u32;
+        req.next = next;
+        Ok(n)
+    }
+
+    /// Acquires an advisory lock for `owner`, waiting for conflicting locks
+    /// held by other files unless the request is non-blocking.
+    ///
+    /// Locks are advisory: plain reads and writes ignore them. Two files that
+    /// each wait for a range the other holds deadlock, as with `F_SETLKW`.
+    fn range_lock(&self, owner: usize, req: ScullLockReq) -> Result {
+        let end = req.offset.checked_add(req.len).ok_or(ScullError::InvalidArgument)?;
+        if req.len == 0 {
+            return Err(ScullError::InvalidArgument.into());
+        }
+        let exclusive = req.flags & SCULL_LOCK_EXCLUSIVE != 0;
+
+        let mut locks = self.locks.lock();
+        while locks
+            .iter()
+            .any(|lock| lock.conflicts(owner, req.offset, end, exclusive))
+        {
+            self.check_live()?;
+            if req.flags & SCULL_LOCK_NONBLOCK != 0 {
+                return Err(EAGAIN);
+            }
+            if self.lock_wait.wait_interruptible(&mut locks) {
+                return Err(ERESTARTSYS);
+            }
+        }
+
+        locks.push(
+            RangeLock {
+                owner,
+                start: req.offset,
+                end,
+                exclusive,
+            },
+            GFP_KERNEL,
+        )?;
+        Ok(())
+    }
+
+    /// Releases every lock held by `owner` that overlaps `start..end`.
+    fn range_unlock(&self, owner: usize, start: u64, end: u64) {
+        self.locks
+            .lock()
+            .retain(|lock| lock.owner != owner || lock.end <= start || end <= lock.start);
+        self.lock_wait.notify_all();
+    }
+
+    /// Reads from `offset` into `iov`, returning the number of bytes copied.
+    fn read(&self, offset: u64, limit: u64, iov: &mut IovIterDest<'_>) -> Result<usize> {
+        let inner = self.data.lock();
+
+        inner.check_mode(SCULL_MODE_READ)?;
+        inner.check_aligned(offset, iov.len() as u64)?;
+
+        let itemsize = inner.quantum * inner.qset;
+
+        // Check for EOF; a trailing sentinel is never returned
+        let end = inner.data_end().min(limit);
+        if offset >= end {
+            return Ok(0);
+        }
+
+        if itemsize == 0 {
+            return Err(ScullError::QuantumZero.into());
+        }
+
+        // Calculate how much to read. The final quantum is allocated in full
+        // even when only partly written, so this clamp against `size` is what
+        // keeps its stale tail from being returned.
+        let mut count = (iov.len() as u64).min(end - offset) as usize;
+
+        // Stop short at a record boundary, so each read returns at most the
+        // rest of one record
+        if inner.record_size != 0 {
+            let record = inner.record_size as u64;
+            count = count.min(clamp_len(record - offset % record));
+        }
+
+        // Walk the quanta until the request is satisfied. Everything below
+        // `size` is readable, so a missing qset node, a node whose qset array
+        // was never allocated, or a missing quantum is a hole that reads back
+        // as the fill byte, not EOF.
+        let mut done = 0;
+        while done < count {
+            // Let `kill -9` end a long read between quanta
+            if done > 0 && fatal_signal_pending() {
+                break;
+            }
+
+            // Copy data to user space, counting only what was actually
+            // consumed; a fault or a short destination stops the read
+            let (len, copied) = match inner.lookup(offset + done as u64, count - done) {
+                (len, Some(slice)) => (len, copy_to_iter(iov, slice)),
+                (len, None) => (len, fill_iter(iov, inner.fill_byte, len)),
+            };
+            done += copied;
+            if copied < len {
+                break;
+            }
+
+            // Chunked mode returns at most one quantum per call
+            if inner.chunked {
+                break;
+            }
+        }
+        drop(inner);
+
+        // Nothing could be copied out at all: the destination faulted
+        if done == 0 && count > 0 {
+            return Err(ScullError::BadAddress.into());
+        }
+
+        self.bytes_read.add(done as u64);
+        Ok(done)
+    }
+
+    /// Writes `iov` at `*pos`, or at the end of the device if `append` is
+    /// set, returning the number of bytes copied. Nothing at or past `limit`
+    /// is written.
+    ///
+    /// The lock is held from choosing the offset until `size` is updated, so
+    /// an append spanning many quanta lands contiguously after whatever the
+    /// previous writer left. On success `*pos` is the end of the bytes
+    /// written, which for an append is unrelated to the position passed in.
+    fn write(
+        &self,
+        pos: &mut u64,
+        append: bool,
+        limit: u64,
+        iov: &mut IovIterSource<'_>,
+    ) -> Result<usize> {
+        let mut offset = *pos;
+        let mut inner = self.data.lock();
+
+        inner.check_mode(SCULL_MODE_WRITE)?;
+        inner.check_not_migrating()?;
+
+        // Appends are positioned at the end under the lock so that concurrent
+        // appenders cannot interleave
+        let end = inner.data_end();
+        if append {
+            offset = end;
+        }
+        if inner.append_only && offset != end {
+            return Err(ScullError::Protected.into());
+        }
+        // Most likely a stray seek; refuse rather than build a huge hole
+        if offset > end.saturating_add(inner.max_gap) {
+            return Err(ScullError::InvalidArgument.into());
+        }
+        inner.check_aligned(offset, iov.len() as u64)?;
+
+        let itemsize = inner.quantum * inner.qset;
+
+        if itemsize == 0 {
+            return Err(ScullError::QuantumZero.into());
+        }
+
+        if offset >= limit && iov.len() > 0 {
+            return Err(ScullError::NoSpace.into());
+        }
+        let count = iov.len().min(clamp_len(limit.saturating_sub(offset)));
+        // A zero-length write, even one past `limit`, neither grows the
+        // device nor counts as a change
+        if count == 0 {
+            *pos = offset;
+            return Ok(0);
+        }
+
+        // Writes reaching the end of the data are followed by the sentinel
+        let sentinel = inner
+            .sentinel
+            .filter(|_| count > 0 && offset + count as u64 >= end);
+        let extent = count + sentinel.is_some() as usize;
+        // Every later `offset + n` is bounded by this, and nothing has been
+        // allocated yet
+        match offset.checked_add(extent as u64) {
+            Some(new_end) if new_end <= inner.max_size() => {}
+            _ => return Err(ScullError::OffsetOverflow.into()),
+        }
+
+        // The whole write fails before anything is allocated if it touches a
+        // protected range
+        if inner.protected.overlaps(offset, offset + extent as u64) {
+            return Err(ScullError::Protected.into());
+        }
+
+        // In write-once mode only untouched bytes may be written. The
+        // previous sentinel is exempt, so that appends still work.
+        let checked = match inner.sentinel {
+            Some(_) if offset <= end => count.min(clamp_len(end - offset)),
+            _ => count,
+        };
+        if inner.write_once && inner.is_written(offset, checked) {
+            return Err(ScullError::Protected.into());
+        }
+
+        // Allocate up front so that the sentinel always has somewhere to go,
+        // even after a short copy
+        if sentinel.is_some() {
+            inner.reserve(offset, extent, current_uid())?;
+        }
+
+        // A write past the end turns the old sentinel into part of the gap
+        if sentinel.is_some() && offset > end && inner.size > end {
+            let fill_byte = inner.fill_byte;
+            let (quantum_buf, pos, _) = inner.segment_mut(end, 1, current_uid())?;
+            quantum_buf.buf[pos] = fill_byte;
+        }
+
+        // Fill one quantum per iteration, allocating as we go
+        let mut written_total = 0;
+        let mut running_sum = inner.running_sum;
+        while written_total < count {
+            // Let `kill -9` end a long write between quanta; what was written
+            // so far is kept and accounted below
+            if written_total > 0 && fatal_signal_pending() {
+                break;
+            }
+
+            // At a boundary `q_pos == 0`, so up to a whole quantum fits; the
+            // flat buffer takes everything in one go
+            let segment = inner.segment_mut(
+                offset + written_total as u64,
+                count - written_total,
+                current_uid(),
+            );
+            let (quantum_buf, q_pos, write_count) = match segment {
+                Ok(segment) => segment,
+                Err(e) if written_total == 0 => return Err(e),
+                // Report what was written before the allocation failed
+                Err(_) => break,
+            };
+
+            let slice_to_write = &mut quantum_buf.buf[q_pos..q_pos + write_count];
+            let copied = copy_from_iter(iov, slice_to_write);
+            running_sum = stream_sum(running_sum, &slice_to_write[..copied]);
+            quantum_buf.mark_dirty(q_pos, q_pos + copied);
+            written_total += copied;
+
+            // A short copy means the source faulted: stop here, counting only
+            // what arrived, rather than moving on to the next quantum
+            if copied < write_count {
+                break;
+            }
+        }
+
+        // Nothing could be copied in at all: the source faulted
+        if written_total == 0 {
+            return Err(ScullError::BadAddress.into());
+        }
+        // The sentinel is not part of the stream
+        inner.running_sum = running_sum;
+
+        let new_offset = offset + written_total as u64;
+        if let Some(byte) = sentinel.filter(|_| new_offset >= end) {
+            // Reserved above, so this cannot allocate
+            let (quantum_buf, pos, _) = inner.segment_mut(new_offset, 1, current_uid())?;
+            quantum_buf.buf[pos] = byte;
+            quantum_buf.mark_dirty(pos, pos + 1);
+            inner.size = new_offset + 1;
+        } else if inner.size < new_offset {
+            inner.size = new_offset;
+        }
+        let generation = if written_total > 0 {
+            inner.last_writer = ScullWriter {
+                pid: current!().pid(),
+                uid: current_uid(),
+            };
+            Some(self.next_generation())
+        } else {
+            None
+        };
+        drop(inner);
+
+        self.bytes_written.add(written_total as u64);
+        if let Some(generation) = generation {
+            self.emit_event(offset, written_total as u64, generation);
+        }
+        *pos = new_offset;
+        Ok(written_total)
+    }
+
+    fn ioctl(&self, file: &ScullFile, cmd: u32, arg: usize) -> Result<isize> {
+        if file.is_partition() && !partition_allows(cmd) {
+            return Err(ScullError::UnknownCommand.into());
+        }
+        match cmd {
+            SCULL_IOCLOCK => {
+                self.range_lock(file.id(), read_user(arg)?)?;
+                Ok(0)
+            }
+            SCULL_IOCUNLOCK => {
+                let req: ScullLockReq = read_user(arg)?;
+                let end = req.offset.checked_add(req.len).ok_or(ScullError::InvalidArgument)?;
+                self.range_unlock(file.id(), req.offset, end);
+                Ok(0)
+            }
+            SCULL_IOCRESET | SCULL_IOCRESET_LEGACY => {
+                let mut inner = self.data.lock();
+                inner.check_mode(SCULL_MODE_WRITE)?;
+                inner.check_resettable(arg & SCULL_RESET_FORCE != 0)?;
+                inner.trim();
+                let generation = self.next_generation();
+                drop(inner);
+
+                self.emit_event(0, 0, generation);
+                Ok(0)
+            }
+            SCULL_IOCSGEOMETRY => {
+                let geometry: ScullGeometry = read_user(arg)?;
+                let mut inner = self.data.lock();
+                inner.check_mode(SCULL_MODE_WRITE)?;
+                if inner.set_geometry(&geometry)? {
+                    let generation = self.next_generation();
+                    drop(inner);
+                    self.emit_event(0, 0, generation);
+                }
+                Ok(0)
+            }
+            SCULL_IOCREQUANTUM => {
+                let geometry: ScullGeometry = read_user(arg)?;
+                if geometry.flags != 0 {
+                    return Err(ScullError::InvalidArgument.into());
+                }
+                let quantum =
+                    usize::try_from(geometry.quantum).map_err(|_| ScullError::InvalidArgument)?;
+                let qset = usize::try_from(geometry.qset).map_err(|_| ScullError::InvalidArgument)?;
+
+                let mut inner = self.data.lock();
+                inner.check_mode(SCULL_MODE_WRITE)?;
+                inner.requantum(quantum, qset)?;
+                self.next_generation();
+                Ok(0)
+            }
+            SCULL_IOCGGEOMETRY => {
+                let inner = self.data.lock();
+                let geometry = ScullGeometry {
+                    quantum: inner.quantum as u64,
+                    qset: inner.qset as u64,
+                    ..Default::default()
+                };
+                drop(inner);
+                write_user(arg, &geometry)?;
+                Ok(0)
+            }
+            SCULL_IOCSMAXNODES => {
+                if !capable_sys_admin() {
+                    return Err(ScullError::NotPrivileged.into());
+                }
+                self.data.lock().max_nodes = arg;
+                Ok(0)
+            }
+            SCULL_IOCGMAXNODES => Ok(self.data.lock().max_nodes as isize),
+            SCULL_IOCGNODES => Ok(self.data.lock().node_count as isize),
+            SCULL_IOCGBACKEND => Ok(match self.data.lock().flat {
+                true => SCULL_BACKEND_FLAT,
+                false => SCULL_BACKEND_QSET,
+            } as isize),
+            SCULL_IOCSMAXOPENS => {
+                if !capable_sys_admin() {
+                    return Err(ScullError::NotPrivileged.into());
+                }
+                self.max_opens.store(arg, Ordering::Relaxed);
+                Ok(0)
+            }
+            SCULL_IOCGMAXOPENS => Ok(self.max_opens.load(Ordering::Relaxed) as isize),
+            SCULL_IOCSLABEL => {
+                let label: [u8; SCULL_LABEL_LEN] = read_user(arg)?;
+                let len = label.iter().position(|&b| b == 0).unwrap_or(SCULL_LABEL_LEN);
+                let (text, padding) = label.split_at(len);
+                if padding.iter().any(|&b| b != 0) || core::str::from_utf8(text).is_err() {
+                    return Err(ScullError::InvalidArgument.into());
+                }
+                self.data.lock().label = label;
+                Ok(0)
+            }
+            SCULL_IOCGVERSION => {
+                write_user(arg, &ScullVersion::get())?;
+                Ok(0)
+            }
+            SCULL_IOCGLABEL => {
+                let label = self.data.lock().label;
+                write_user(arg, &label)?;
+                Ok(0)
+            }
+            SCULL_IOCSGFP => {
+                let atomic_alloc = match arg {
+                    SCULL_GFP_KERNEL => false,
+                    SCULL_GFP_ATOMIC => true,
+                    _ => return Err(ScullError::InvalidArgument.into()),
+                };
+                self.data.lock().atomic_alloc = atomic_alloc;
+                Ok(0)
+            }
+            SCULL_IOCGGFP => Ok(match self.data.lock().atomic_alloc {
+                true => SCULL_GFP_ATOMIC,
+                false => SCULL_GFP_KERNEL,
+            } as isize),
+            SCULL_IOCSFILL => {
+                let byte = u8::try_from(arg).map_err(|_| ScullError::InvalidArgument)?;
+                let mut inner = self.data.lock();
+                if !inner.is_empty() {
+                    return Err(ScullError::Busy.into());
+                }
+                inner.fill_byte = byte;
+                Ok(0)
+            }
+            SCULL_IOCGFILL => Ok(self.data.lock().fill_byte as isize),
+            SCULL_IOCSSENTINEL => {
+                let sentinel = match arg {
+                    SCULL_SENTINEL_NONE => None,
+                    _ => Some(u8::try_from(arg).map_err(|_| ScullError::InvalidArgument)?),
+                };
+                let mut inner = self.data.lock();
+                if !inner.is_empty() {
+                    return Err(ScullError::Busy.into());
+                }
+                inner.sentinel = sentinel;
+                Ok(0)
+            }
+            SCULL_IOCGSENTINEL => Ok(match self.data.lock().sentinel {
+                Some(byte) => byte as isize,
+                None => SCULL_SENTINEL_NONE as isize,
+            }),
+            SCULL_IOCSALIGN => {
+                let mut inner = self.data.lock();
+                if arg != 0 && (!arg.is_power_of_two() || arg > inner.quantum) {
+                    return Err(ScullError::InvalidArgument.into());
+                }
+                inner.align = arg;
+                Ok(0)
+            }
+            SCULL_IOCGALIGN => Ok(self.data.lock().align as isize),
+            SCULL_IOCSRECORD => {
+                isize::try_from(arg).map_err(|_| ScullError::InvalidArgument)?;
+                self.data.lock().record_size = arg;
+                Ok(0)
+            }
+            SCULL_IOCGRECORD => Ok(self.data.lock().record_size as isize),
+            SCULL_IOCSHUTDOWN => {
+                if !capable_sys_admin() {
+                    return Err(ScullError::NotPrivileged.into());
+                }
+                self.shutdown();
+                Ok(0)
+            }
+            SCULL_IOCCHECK => {
+                let mut req: ScullCheck = read_user(arg)?;
+                let first = usize::try_from(req.cursor).map_err(|_| ScullError::InvalidArgument)?;
+                let code = match self.check(first, SCULL_CHECK_MAX_NODES) {
+                    Ok(next) => {
+                        req.cursor = next.unwrap_or(0) as u64;
+                        0
+                    }
+                    Err(violation) => {
+                        req.item = violation.item as u64;
+                        req.s_pos = violation.s_pos as u64;
+                        violation.code
+                    }
+                };
+                req.code = code;
+                write_user(arg, &req)?;
+                Ok(code as isize)
+            }
+            SCULL_IOCSCHUNKED => {
+                self.data.lock().chunked = arg != 0;
+                Ok(0)
+            }
+            SCULL_IOCSAPPENDONLY => {
+                if !capable_sys_admin() {
+                    return Err(ScullError::NotPrivileged.into());
+                }
+                self.data.lock().append_only = arg != 0;
+                Ok(0)
+            }
+            SCULL_IOCGAPPENDONLY => Ok(self.data.lock().append_only as isize),
+            #[cfg(not(scull_minimal))]
+            SCULL_IOCCOMMIT => {
+                self.data.lock().commit()?;
+                Ok(0)
+            }
+            #[cfg(not(scull_minimal))]
+            SCULL_IOCROLLBACK => {
+                let mut inner = self.data.lock();
+                inner.check_mode(SCULL_MODE_WRITE)?;
+                inner.check_not_migrating()?;
+                if inner.append_only || !inner.protected.is_empty() {
+                    return Err(ScullError::Protected.into());
+                }
+                inner.rollback(arg)?;
+                self.next_generation();
+                Ok(0)
+            }
+            SCULL_IOCDROPCACHE => {
+                let mut inner = self.data.lock();
+                inner.check_mode(SCULL_MODE_WRITE)?;
+                inner.check_not_migrating()?;
+                // Freed quanta take their dirty bitmaps with them, which
+                // would let write-once data be written again
+                if inner.append_only || inner.write_once || !inner.protected.is_empty() {
+                    return Err(ScullError::Protected.into());
+                }
+                inner.drop_cache();
+                self.next_generation();
+                Ok(0)
+            }
+            SCULL_IOCCOMPACT => Ok(self.data.lock().compact() as isize),
+            SCULL_IOCGGEN => {
+                write_user(arg, &self.generation.load(Ordering::Relaxed))?;
+                Ok(0)
+            }
+            SCULL_IOCGCREATED => {
+                let created_at = self.data.lock().created_at;
+                write_user(arg, &created_at)?;
+                Ok(0)
+            }
+            SCULL_IOCGRUNNINGCRC => {
+                let running_sum = self.data.lock().running_sum;
+                write_user(arg, &running_sum)?;
+                Ok(0)
+            }
+            SCULL_IOCSMAXGAP => {
+                let max_gap: u64 = read_user(arg)?;
+                self.data.lock().max_gap = max_gap;
+                Ok(0)
+            }
+            SCULL_IOCGMAXGAP => {
+                let max_gap = self.data.lock().max_gap;
+                write_user(arg, &max_gap)?;
+                Ok(0)
+            }
+            SCULL_IOCGFOOTPRINT => {
+                let footprint = self.data.lock().footprint();
+                write_user(arg, &footprint)?;
+                Ok(0)
+            }
+            SCULL_IOCSMODE => {
+                if !capable_sys_admin() {
+                    return Err(ScullError::NotPrivileged.into());
+                }
+                if arg & !(SCULL_MODE_READ | SCULL_MODE_WRITE) != 0 {
+                    return Err(ScullError::InvalidArgument.into());
+                }
+                self.data.lock().mode = arg;
+                Ok(0)
+            }
+            SCULL_IOCGMODE => Ok(self.data.lock().mode as isize),
+            #[cfg(not(scull_minimal))]
+            SCULL_IOCFORK => {
+                let flat = match arg {
+                    SCULL_FORK_SAME => self.data.lock().flat,
+                    SCULL_FORK_QSET => false,
+                    SCULL_FORK_FLAT => true,
+                    _ => return Err(ScullError::InvalidArgument.into()),
+                };
+                Ok(fork_device(self, flat)? as isize)
+            }
+            #[cfg(not(scull_minimal))]
+            SCULL_IOCMIGRATE => {
+                let mut inner = self.data.lock();
+                inner.check_mode(SCULL_MODE_WRITE)?;
+                inner.start_migration(arg)?;
+                drop(inner);
+                ScullDev::schedule_migration(file.dev.clone());
+                Ok(0)
+            }
+            #[cfg(not(scull_minimal))]
+            SCULL_IOCGMIGRATE => Ok(self.data.lock().migration_progress() as isize),
+            SCULL_IOCEXTEND => {
+                let target = isize::try_from(arg).map_err(|_| ScullError::InvalidArgument)?;
+                let mut inner = self.data.lock();
+                inner.check_mode(SCULL_MODE_WRITE)?;
+                inner.check_not_migrating()?;
+                // The sentinel would have to be allocated at the new end
+                if inner.sentinel.is_some() {
+                    return Err(ScullError::NotSupported.into());
+                }
+                if target as u64 > inner.max_size() {
+                    return Err(ScullError::OffsetOverflow.into());
+                }
+                if inner.size < target as u64 {
+                    inner.size = target as u64;
+                    self.next_generation();
+                }
+                ret_size(inner.size)
+            }
+            SCULL_IOCDISCARDFRONT => {
+                let len = isize::try_from(arg).map_err(|_| ScullError::InvalidArgument)?;
+                let mut inner = self.data.lock();
+                inner.check_mode(SCULL_MODE_WRITE)?;
+                if inner.discard_front(len as u64)? > 0 {
+                    let generation = self.next_generation();
+                    let size = inner.size;
+                    drop(inner);
+                    self.emit_event(0, 0, generation);
+                    return ret_size(size);
+                }
+                ret_size(inner.size)
+            }
+            #[cfg(CONFIG_DEBUG_KERNEL)]
+            SCULL_IOCINJECT => {
+                FAIL_NEXT_ALLOC.store(true, Ordering::Relaxed);
+                Ok(0)
+            }
+            #[cfg(CONFIG_DEBUG_KERNEL)]
+            SCULL_IOCINJECTCOPY => {
+                let count = u32::try_from(arg).map_err(|_| ScullError::InvalidArgument)?;
+                SHORT_COPIES.store(count, Ordering::Relaxed);
+                Ok(0)
+            }
+            #[cfg(not(scull_minimal))]
+            SCULL_IOCGETLOG => Ok(self.get_log(read_user(arg)?)? as isize),
+            #[cfg(not(scull_minimal))]
+            SCULL_IOCFIEMAP => {
+                let mut req: ScullFiemap = read_user(arg)?;
+                let n = self.fiemap(&mut req)?;
+                write_user(arg, &req)?;
+                Ok(n as isize)
+            }
+            #[cfg(not(scull_minimal))]
+            SCULL_IOCCLEARLOG => {
+                self.log.lock().clear();
+                Ok(0)
+            }
+            #[cfg(not(scull_minimal))]
+            SCULL_IOCSLOGGING => {
+                self.log_enabled.store(arg != 0, Ordering::Relaxed);
+                Ok(0)
+            }
+            SCULL_IOCGSTATS => {
+                write_user_sized(arg, self.stats())?;
+                Ok(0)
+            }
+            SCULL_IOCGFILLSTATS => {
+                let stats = self.data.lock().fill_stats();
+                write_user_sized(arg, stats)?;
+                Ok(0)
+            }
+            SCULL_IOCLIST => {
+                if file.index != 0 {
+                    return Err(ScullError::UnknownCommand.into());
+                }
+                Ok(self.list_devices(read_user(arg)?)? as isize)
+            }
+            SCULL_IOCGSIZES => {
+                write_user(arg, &ScullSizes::get())?;
+                Ok(0)
+            }
+            SCULL_IOCGLASTWRITER => {
+                let writer = self.data.lock().last_writer;
+                write_user(arg, &writer)?;
+                Ok(0)
+            }
+            SCULL_IOCPEEK => {
+                let req: ScullPeek = read_user(arg)?;
+                let inner = self.data.lock();
+                inner.check_mode(SCULL_MODE_READ)?;
+
+                // Like read(), stop short of any trailing sentinel
+                let end = inner.data_end();
+                if req.offset >= end {
+                    return Ok(0);
+                }
+                if inner.quantum * inner.qset == 0 {
+                    return Err(ScullError::QuantumZero.into());
+                }
+
+                let total = clamp_len(req.len.min(end - req.offset));
+                let mut writer =
+                    UserSlice::new(UserPtr::from_addr(req.buf as usize), total).writer();
+                inner.copy_to_user(req.offset, total, &mut writer)?;
+                Ok(total as isize)
+            }
+            SCULL_IOCREADEX => {
+                let mut req: ScullReadEx = read_user(arg)?;
+                let copied = self.read_ex(&mut req)?;
+                write_user(arg, &req)?;
+                Ok(copied as isize)
+            }
+            SCULL_IOCPROTECT | SCULL_IOCUNPROTECT => {
+                // Anyone may protect data, but only an admin may expose it again
+                if cmd == SCULL_IOCUNPROTECT && !capable_sys_admin() {
+                    return Err(ScullError::NotPrivileged.into());
+                }
+                let range: ScullRange = read_user(arg)?;
+                let end = range.offset.checked_add(range.len).ok_or(ScullError::InvalidArgument)?;
+                if range.len == 0 {
+                    return Err(ScullError::InvalidArgument.into());
+                }
+
+                let mut inner = self.data.lock();
+                if cmd == SCULL_IOCPROTECT {
+                    inner.protected.insert(range.offset, end)?;
+                } else {
+                    inner.protected.remove(range.offset, end)?;
+                }
+                Ok(0)
+            }
+            SCULL_IOCSWRITEONCE => {
+                if arg == 0 && !capable_sys_admin() {
+                    return Err(ScullError::NotPrivileged.into());
+                }
+                self.data.lock().write_once = arg != 0;
+                Ok(0)
+            }
+            SCULL_IOCGWRITEONCE => Ok(self.data.lock().write_once as isize),
+            SCULL_IOCSUIDQUOTA => {
+                if !capable_sys_admin() {
+                    return Err(ScullError::NotPrivileged.into());
+                }
+                UID_QUOTA.lock().limit = arg as u64;
+                Ok(0)
+            }
+            _ => Err(ScullError::UnknownCommand.into()),
+        }
+    }
+}
+
+impl_has_work! {
+    impl HasWork<Self> for ScullDev { self.migrate_work }
+}
+
+impl WorkItem for ScullDev {
+    type Pointer = Arc<ScullDev>;
+
+    fn run(this: Arc<ScullDev>) {
+        let mut inner = this.data.lock();
+        if this.migrate_abort.load(Ordering::Relaxed) {
+            inner.migration = None;
+            return;
+        }
+
+        match inner.migrate_step() {
+            // `stop_migration` may have set the flag during the step
+            Ok(true) if this.migrate_abort.load(Ordering::Relaxed) => {
+                inner.migration = None;
+            }
+            Ok(true) => {
+                drop(inner);
+                ScullDev::schedule_migration(this);
+            }
+            Ok(false) => {}
+            Err(e) => {
+                pr_warn!("rust_scull: migration failed: {:?}\n", e);
+                inner.migration = None;
+            }
+        }
+    }
+}
+
+/// A registered scull device: the misc registration plus the state shared by
+/// every handle opened on it.
+///
+/// A partition is a window `base..limit` onto another device's data; file
+/// offsets are translated by `base`. Whole devices use `0..u64::MAX`.
+#[pin_data]
+struct ScullDevice {
+    dev: Arc<ScullDev>,
+    /// 0 for `/dev/scull` and its partitions, `slot + 1` for a dynamic device.
+    index: usize,
+    base: u64,
+    limit: u64,
+    #[pin]
+    misc: MiscDeviceRegistration<RustScull>,
+}
+
+impl ScullDevice {
+    /// Registers a misc device named `name` serving `data`, with its lock in
+    /// the lockdep class of device `index` (see `data_lock_class`).
+    fn register(
+        name: &'static CStr,
+        index: usize,
+        data: ScullDevData,
+    ) -> Result<Pin<KBox<Self>>> {
+        let options = MiscDeviceOptions { name };
+        let dev = ScullDev::new(data, name, data_lock_class(index));
+
+        KBox::pin_init(
+            try_pin_init!(ScullDevice {
+                dev: Arc::pin_init(dev, GFP_KERNEL)?,
+                index,
+                base: 0,
+                limit: u64::MAX,
+                misc <- MiscDeviceRegistration::register(options),
+            }),
+            GFP_KERNEL,
+        )
+    }
+
+    /// Registers a misc device named `name` exposing `len` bytes of `dev`
+    /// starting at `base`.
+    fn partition(
+        name: &'static CStr,
+        dev: Arc<ScullDev>,
+        base: u64,
+        len: u64,
+    ) -> Result<Pin<KBox<Self>>> {
+        let options = MiscDeviceOptions { name };
+
+        KBox::pin_init(
+            try_pin_init!(ScullDevice {
+                dev,
+                index: 0,
+                base,
+                limit: base + len,
+                misc <- MiscDeviceRegistration::register(options),
+            }),
+            GFP_KERNEL,
+        )
+    }
+}
+
+/// Copies `source` into a new dynamic device using the flat backend if
+/// `flat`, returning its slot index.
+fn fork_device(source: &ScullDev, flat: bool) -> Result<usize> {
+    let mut devices = DYN_DEVICES.lock();
+    let slot = match devices.iter().position(|dev| dev.is_none()) {
+        Some(slot) => slot,
+        None if devices.len() < SCULL_DYN_NAMES.len() => {
+            devices.push(None, GFP_KERNEL)?;
+            devices.len() - 1
+        }
+        None => return Err(ScullError::NoSpace.into()),
+    };
+
+    // The copy is taken under the source lock, so it is consistent
+    let data = source.data.lock().fork(flat)?;
+    devices[slot] = Some(ScullDevice::register(SCULL_DYN_NAMES[slot], slot + 1, data)?);
+    Ok(slot)
+}
+
+/// Per-open state.
+struct ScullFile {
+    dev: Arc<ScullDev>,
+    /// See `ScullDevice::index`.
+    index: usize,
+    /// Window of the device this file was opened on, see `ScullDevice`.
+    base: u64,
+    limit: u64,
+}
+
+impl ScullFile {
+    /// Identifies this open file, e.g. as the owner of range locks.
+    fn id(&self) -> usize {
+        core::ptr::from_ref(self) as usize
+    }
+
+    /// Translates a file position into an offset in the shared data, failing
+    /// with EINVAL if it is negative or overflows.
+    fn translate(&self, pos: i64) -> Result<u64> {
+        let pos = u64::try_from(pos).map_err(|_| ScullError::InvalidArgument)?;
+        Ok(self.base.checked_add(pos).ok_or(ScullError::InvalidArgument)?)
+    }
+
+    /// Returns true if this file was opened on a partition.
+    fn is_partition(&self) -> bool {
+        self.limit != u64::MAX
+    }
+}
+
+/// Returns true if `cmd` may be issued on a partition.
+///
+/// A partition only translates the offsets of read and write. Everything
+/// else would act on the data it shares with `/dev/scull` and the other
+/// partitions, so only commands that neither change the device nor take
+/// offsets into it are allowed; the rest fail with ENOTTY.
+fn partition_allows(cmd: u32) -> bool {
+    matches!(
+        cmd,
+        SCULL_IOCGGEOMETRY
+            | SCULL_IOCGMAXNODES
+            | SCULL_IOCGNODES
+            | SCULL_IOCGBACKEND
+            | SCULL_IOCGMAXOPENS
+            | SCULL_IOCGVERSION
+            | SCULL_IOCGLABEL
+            | SCULL_IOCGGFP
+            | SCULL_IOCGFILL
+            | SCULL_IOCGSENTINEL
+            | SCULL_IOCGALIGN
+            | SCULL_IOCGRECORD
+            | SCULL_IOCGAPPENDONLY
+            | SCULL_IOCGGEN
+            | SCULL_IOCGCREATED
+            | SCULL_IOCGMAXGAP
+            | SCULL_IOCGFOOTPRINT
+            | SCULL_IOCGMODE
+            | SCULL_IOCGSTATS
+            | SCULL_IOCGFILLSTATS
+            | SCULL_IOCGSIZES
+            | SCULL_IOCGLASTWRITER
+            | SCULL_IOCGWRITEONCE
+            | SCULL_IOCCHECK
+    )
+}
+
+struct RustScull;
+
+#[vtable]
+impl MiscDevice for RustScull {
+    type Ptr = KBox<ScullFile>;
+
+    fn open(file: &File, misc: &MiscDeviceRegistration<Self>) -> Result<Self::Ptr> {
+        pr_debug!("rust_scull: open()\n");
+
+        // Have the VFS serialize position updates on a shared struct file,
+        // as it does for regular files
+        // SAFETY: `open` runs before the file is installed in any fd table, so
+        // nothing else can be accessing `f_mode`.
+        unsafe { (*file.as_ptr()).f_mode |= FMODE_ATOMIC_POS };
+
+        // SAFETY: Every `MiscDeviceRegistration<RustScull>` is the `misc` field of a
+        // pinned `ScullDevice`, which outlives the registration.
+        let scull = unsafe { &*container_of!(core::ptr::from_ref(misc), ScullDevice, misc) };
+        if scull.dev.shutdown.load(Ordering::Relaxed) {
+            return Err(Error::from_errno(-(bindings::ESHUTDOWN as i32)));
+        }
+
+        let scull_file = KBox::new(
+            ScullFile {
+                dev: scull.dev.clone(),
+                index: scull.index,
+                base: scull.base,
+                limit: scull.limit,
+            },
+            GFP_KERNEL,
+        )?;
+        let accmode = file.flags() & file::flags::O_ACCMODE;
+        if *module_parameters::prealloc_head.value() != 0 && accmode != file::flags::O_RDONLY {
+            scull_file.dev.data.lock().prealloc_head()?;
+        }
+        scull_file.dev.count_open()?;
+
+        Ok(scull_file)
+    }
+
+    fn release(device: Self::Ptr, _file: &File) {
+        pr_debug!("rust_scull: release()\n");
+
+        device.dev.release(device.id());
+    }
+
+    // The position is the struct file's own `f_pos`, never anything kept on
+    // the shared `ScullDev`, so separate opens of one device seek and read
+    // independently. It is sampled once and advanced by exactly the bytes
+    // moved. `open` sets FMODE_ATOMIC_POS, so the VFS serializes calls sharing one
+    // struct file and concurrent reads never return overlapping ranges.
+    fn read_iter(
+        mut kiocb: Kiocb<'_, Self::Ptr>,
+        iov: &mut IovIterDest<'_>,
+    ) -> Result<usize> {
+        let pos = kiocb.ki_pos();
+        let result = {
+            let scull = kiocb.file();
+            scull.dev.check_live()?;
+            let offset = scull.translate(pos)?;
+            let device = &scull.dev;
+            let len = iov.len() as u64;
+
+            let result = device.read(offset, scull.limit, iov);
+            device.log_op(SCULL_LOG_READ, offset, len, result_code(&result));
+            result
+        };
+
+        if let Ok(read) = result {
+            *kiocb.ki_pos_mut() = pos + read as i64;
+        }
+        result
+    }
+
+    fn write_iter(
+        mut kiocb: Kiocb<'_, Self::Ptr>,
+        iov: &mut IovIterSource<'_>,
+    ) -> Result<usize> {
+        let append = is_append(&kiocb);
+        let (result, end) = {
+            let scull = kiocb.file();
+            scull.dev.check_live()?;
+            let offset = scull.translate(kiocb.ki_pos())?;
+            let device = &scull.dev;
+            let len = iov.len() as u64;
+
+            // The end of the shared data is meaningless within a partition
+            if append && scull.is_partition() {
+                return Err(ScullError::InvalidArgument.into());
+            }
+
+            // Appends move `end` to wherever the data actually landed
+            let mut end = offset;
+            let result = device.write(&mut end, append, scull.limit, iov);
+            device.log_op(SCULL_LOG_WRITE, offset, len, result_code(&result));
+            device.paranoid_check("write");
+            (result, end - scull.base)
+        };
+
+        if result.is_ok() {
+            *kiocb.ki_pos_mut() = end as i64;
+        }
+        result
+    }
+
+    fn ioctl(
+        scull: &ScullFile,
+        _file: &File,
+        cmd: u32,
+        arg: usize,
+    ) -> Result<isize> {
+        pr_debug!("rust_scull: ioctl() cmd={}, arg={}\n", cmd, arg);
+
+        let device = &scull.dev;
+        device.check_live()?;
+        let result = device.ioctl(scull, cmd, arg);
+        device.log_op(SCULL_LOG_IOCTL, cmd as u64, arg as u64, result_code(&result));
+        device.paranoid_check("ioctl");
+        result
+    }
+}
+
+// --- Pipe Device ---
+
+const SCULL_P_BUFFER: usize = 4000;
+
+/// Ring buffer state of a scullpipe device.
+struct PipeInner {
+    buf: KVec<u8>,
+    /// Index of the next byte to read.
+    rp: usize,
+    /// Number of bytes stored.
+    len: usize,
+    readers: usize,
+    writers: usize,
+    /// Set by `SCULL_IOCSHUTDOWN` or on module unload; waiters give up with
+    /// ENODEV and opens with ESHUTDOWN.
+    dead: bool,
+}
+
+impl PipeInner {
+    fn new() -> Result<Self> {
+        let mut buf = KVec::new();
+        buf.resize(SCULL_P_BUFFER, 0, GFP_KERNEL)?;
+        Ok(PipeInner {
+            buf,
+            rp: 0,
+            len: 0,
+            readers: 0,
+            writers: 0,
+            dead: false,
+        })
+    }
+
+    fn space(&self) -> usize {
+        self.buf.len() - self.len
+    }
+
+    /// Moves as much buffered data as fits into `iov`.
+    fn read_into(&mut self, iov: &mut IovIterDest<'_>) -> usize {
+        let cap = self.buf.len();
+        let mut done = 0;
+
+        while self.len > 0 && iov.len() > 0 {
+            let n = self.len.min(cap - self.rp);
+            let copied = iov.copy_to_iter(&self.buf[self.rp..self.rp + n]);
+            self.rp = (self.rp + copied) % cap;
+            self.len -= copied;
+            done += copied;
+            if copied < n {
+                break;
+            }
+        }
+        done
+    }
+
+    /// Returns the distance from the read position to the first occurrence
+    /// of `pattern` in the buffered data.
+    fn find(&self, pattern: &[u8]) -> Option<usize> {
+        let cap = self.buf.len();
+        let last = self.len.checked_sub(pattern.len())?;
+        (0..=last).find(|&start| {
+            pattern
+                .iter()
+                .enumerate()
+                .all(|(i, &b)| self.buf[(self.rp + start + i) % cap] == b)
+        })
+    }
+
+    /// Moves as much of `iov` as there is space for into the buffer.
+    fn write_from(&mut self, iov: &mut IovIterSource<'_>) -> usize {
+        let cap = self.buf.len();
+        let mut done = 0;
+
+        while self.len < cap && iov.len() > 0 {
+            let wp = (self.rp + self.len) % cap;
+            let n = (cap - self.len).min(cap - wp);
+            let copied = iov.copy_from_iter(&mut self.buf[wp..wp + n]);
+            self.len += copied;
+            done += copied;
+            if copied < n {
+                break;
+            }
+        }
+        done
+    }
+}
+
+/// State shared by every handle opened on a scullpipe device.
+#[pin_data]
+struct ScullPipe {
+    #[pin]
+    inner: Mutex<PipeInner>,
+    /// Readers wait here for data.
+    #[pin]
+    inq: CondVar,
+    /// Writers wait here for space.
+    #[pin]
+    outq: CondVar,
+}
+
+impl ScullPipe {
+    fn new() -> impl PinInit<Self, Error> {
+        try_pin_init!(ScullPipe {
+            inner <- new_mutex!(PipeInner::new()?, "ScullPipe::inner"),
+            inq <- new_condvar!("ScullPipe::inq"),
+            outq <- new_condvar!("ScullPipe::outq"),
+        })
+    }
+
+    /// Reads into `iov`, blocking until data is available unless `nonblock`.
+    ///
+    /// Without a deadline the read returns as soon as any data has been
+    /// copied. With one, it keeps accumulating until `iov` is full or the
+    /// deadline passes, then returns whatever it has, possibly nothing.
+    ///
+    /// If every writer present during the wait closes while the buffer is
+    /// empty, the read ends there, returning 0 at end of file. A reader that
+    /// starts with no writer still waits for one, as scullpipe always has.
+    fn read(
+        &self,
+        iov: &mut IovIterDest<'_>,
+        nonblock: bool,
+        deadline: Option<Jiffies>,
+    ) -> Result<usize> {
+        let mut inner = self.inner.lock();
+        let mut timeout = deadline;
+        let mut done = 0;
+        let mut had_writer = false;
+
+        loop {
+            let copied = inner.read_into(iov);
+            if copied > 0 {
+                done += copied;
+                self.outq.notify_all();
+            }
+
+            if iov.len() == 0 || (done > 0 && timeout.is_none()) {
+                return Ok(done);
+            }
+            if inner.dead {
+                return if done > 0 { Ok(done) } else { Err(ENODEV) };
+            }
+            if inner.writers > 0 {
+                had_writer = true;
+            } else if had_writer {
+                return Ok(done);
+            }
+            if nonblock {
+                return if done > 0 { Ok(done) } else { Err(EAGAIN) };
+            }
+
+            match timeout {
+                None => {
+                    if self.inq.wait_interruptible(&mut inner) {
+                        return Err(ERESTARTSYS);
+                    }
+                }
+                Some(remaining) => {
+                    match self.inq.wait_interruptible_timeout(&mut inner, remaining) {
+                        CondVarTimeoutResult::Timeout => return Ok(done),
+                        CondVarTimeoutResult::Woken { jiffies } => timeout = Some(jiffies),
+                        CondVarTimeoutResult::Signal { .. } => {
+                            return if done > 0 { Ok(done) } else { Err(ERESTARTSYS) };
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits until `pattern` appears in the buffered data, rescanning after
+    /// every write, and returns its distance from the read position.
+    ///
+    /// Fails with `ENOBUFS` if the buffer fills up without a match, since no
+    /// more data can arrive until a reader drains it, and with `ETIMEDOUT`
+    /// once `deadline` passes.
+    fn wait_pattern(
+        &self,
+        pattern: &[u8],
+        nonblock: bool,
+        deadline: Option<Jiffies>,
+    ) -> Result<usize> {
+        let mut inner = self.inner.lock();
+        let mut timeout = deadline;
+
+        loop {
+            if let Some(offset) = inner.find(pattern) {
+                return Ok(offset);
+            }
+            if inner.dead {
+                return Err(ENODEV);
+            }
+            if inner.space() == 0 {
+                return Err(ENOBUFS);
+            }
+            if nonblock {
+                return Err(EAGAIN);
+            }
+
+            match timeout {
+                None => {
+                    if self.inq.wait_interruptible(&mut inner) {
+                        return Err(ERESTARTSYS);
+                    }
+                }
+                Some(remaining) => {
+                    match self.inq.wait_interruptible_timeout(&mut inner, remaining) {
+                        CondVarTimeoutResult::Timeout => return Err(ETIMEDOUT),
+                        CondVarTimeoutResult::Woken { jiffies } => timeout = Some(jiffies),
+                        CondVarTimeoutResult::Signal { .. } => return Err(ERESTARTSYS),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes from `iov`, blocking until there is space unless `nonblock`.
+    fn write(&self, iov: &mut IovIterSource<'_>, nonblock: bool) -> Result<usize> {
+        let mut inner = self.inner.lock();
+
+        while inner.space() == 0 {
+            if inner.dead {
+                return Err(ENODEV);
+            }
+            if nonblock {
+                return Err(EAGAIN);
+            }
+            if self.outq.wait_interruptible(&mut inner) {
+                return Err(ERESTARTSYS);
+            }
+        }
+
+        let done = inner.write_from(iov);
+        self.inq.notify_all();
+        Ok(done)
+    }
+
+    /// Fails every current and future blocking wait with ENODEV, and every
+    /// later open with ESHUTDOWN.
+    ///
+    /// Called by `SCULL_IOCSHUTDOWN` and on module unload. The misc
+    /// registration pins the module while a file is open, so on unload this
+    /// should find no waiters; it makes sure none can outlive unload if that
+    /// ever stops being true.
+    fn shutdown(&self) {
+        self.inner.lock().dead = true;
+        self.inq.notify_all();
+        self.outq.notify_all();
+    }
+
+    /// Counts a new open handle, failing with `ESHUTDOWN` once the pipe has
+    /// been shut down.
+    fn attach(&self, reader: bool, writer: bool) -> Result {
+        let mut inner = self.inner.lock();
+        if inner.dead {
+            return Err(Error::from_errno(-(bindings::ESHUTDOWN as i32)));
+        }
+        inner.readers += reader as usize;
+        inner.writers += writer as usize;
+        Ok(())
+    }
+
+    /// Undoes `attach`, waking every waiter to re-check the writer count.
+    fn detach(&self, reader: bool, writer: bool) {
+        let mut inner = self.inner.lock();
+        inner.readers -= reader as usize;
+        inner.writers -= writer as usize;
+        drop(inner);
+
+        self.inq.notify_all();
+        self.outq.notify_all();
+    }
+
+    /// Fails with `ENODEV` once the pipe has been shut down. Every operation
+    /// on an open handle starts with this.
+    fn check_live(&self) -> Result {
+        if self.inner.lock().dead {
+            return Err(ENODEV);
+        }
+        Ok(())
+    }
+}
+
+/// A registered scullpipe device.
+#[pin_data]
+struct ScullPipeDevice {
+    pipe: Arc<ScullPipe>,
+    #[pin]
+    misc: MiscDeviceRegistration<RustScullPipe>,
+}
+
+impl ScullPipeDevice {
+    fn register(name: &'static CStr) -> Result<Pin<KBox<Self>>> {
+        let options = MiscDeviceOptions { name };
+
+        KBox::pin_init(
+            try_pin_init!(ScullPipeDevice {
+                pipe: Arc::pin_init(ScullPipe::new(), GFP_KERNEL)?,
+                misc <- MiscDeviceRegistration::register(options),
+            }),
+            GFP_KERNEL,
+        )
+    }
+}
+
+/// Per-open scullpipe state.
+struct PipeFile {
+    pipe: Arc<ScullPipe>,
+    reader: bool,
+    writer: bool,
+    /// Read deadline in milliseconds, 0 for none.
+    deadline_ms: AtomicU32,
+    /// Report interrupted waits as EINTR rather than restarting them.
+    no_restart: AtomicBool,
+}
+
+impl PipeFile {
+    fn deadline(&self) -> Option<Jiffies> {
+        match self.deadline_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(msecs_to_jiffies(ms)),
+        }
+    }
+
+    /// Applies the restart policy to the result of a pipe operation. A wait
+    /// is only ever interrupted before any byte has moved; later signals
+    /// just end the call with the partial count.
+    fn restart_policy(&self, result: Result<usize>) -> Result<usize> {
+        match result {
+            Err(e) if e == ERESTARTSYS && self.no_restart.load(Ordering::Relaxed) => Err(EINTR),
+            result => result,
+        }
+    }
+}
+
+struct RustScullPipe;
+
+#[vtable]
+impl MiscDevice for RustScullPipe {
+    type Ptr = KBox<PipeFile>;
+
+    fn open(file: &File, misc: &MiscDeviceRegistration<Self>) -> Result<Self::Ptr> {
+        pr_debug!("rust_scull: pipe open()\n");
+
+        // SAFETY: Every `MiscDeviceRegistration<RustScullPipe>` is the `misc` field
+        // of a pinned `ScullPipeDevice`, which outlives the registration.
+        let dev = unsafe { &*container_of!(core::ptr::from_ref(misc), ScullPipeDevice, misc) };
+
+        let accmode = file.flags() & file::flags::O_ACCMODE;
+        let pipe_file = KBox::new(
+            PipeFile {
+                pipe: dev.pipe.clone(),
+                reader: accmode != file::flags::O_WRONLY,
+                writer: accmode != file::flags::O_RDONLY,
+                deadline_ms: AtomicU32::new(0),
+                no_restart: AtomicBool::new(false),
+            },
+            GFP_KERNEL,
+        )?;
+
+        pipe_file.pipe.attach(pipe_file.reader, pipe_file.writer)?;
+
+        Ok(pipe_file)
+    }
+
+    fn release(pipe_file: Self::Ptr, _file: &File) {
+        pr_debug!("rust_scull: pipe release()\n");
+
+        // MiscDevice has no flush hook to tell readers a writer is going
+        // away, so end of file is signalled here instead, at the last close
+        // of the struct file. A killed writer gets here from exit, so its
+        // readers still see end of file rather than waiting forever.
+        pipe_file.pipe.detach(pipe_file.reader, pipe_file.writer);
+    }
+
+    fn read_iter(kiocb: Kiocb<'_, Self::Ptr>, iov: &mut IovIterDest<'_>) -> Result<usize> {
+        let pipe_file = kiocb.file();
+        pipe_file.pipe.check_live()?;
+        let result = pipe_file
+            .pipe
+            .read(iov, is_nonblock(&kiocb), pipe_file.deadline());
+        pipe_file.restart_policy(result)
+    }
+
+    fn write_iter(kiocb: Kiocb<'_, Self::Ptr>, iov: &mut IovIterSource<'_>) -> Result<usize> {
+        let pipe_file = kiocb.file();
+        pipe_file.pipe.check_live()?;
+        let result = pipe_file.pipe.write(iov, is_nonblock(&kiocb));
+        pipe_file.restart_policy(result)
+    }
+
+    fn ioctl(pipe_file: &PipeFile, file: &File, cmd: u32, arg: usize) -> Result<isize> {
+        pipe_file.pipe.check_live()?;
+        match cmd {
+            SCULL_P_IOCWAITPATTERN => {
+                let req: ScullPattern = read_user(arg)?;
+                let len = req.len as usize;
+                if len == 0 || len > SCULL_PATTERN_MAX {
+                    return Err(ScullError::InvalidArgument.into());
+                }
+
+                let nonblock = file.flags() & file::flags::O_NONBLOCK != 0;
+                let result = pipe_file
+                    .pipe
+                    .wait_pattern(&req.pattern[..len], nonblock, pipe_file.deadline());
+                Ok(pipe_file.restart_policy(result)? as isize)
+            }
+            SCULL_P_IOCSNORESTART => {
+                pipe_file.no_restart.store(arg != 0, Ordering::Relaxed);
+                Ok(0)
+            }
+            SCULL_IOCSHUTDOWN => {
+                if !capable_sys_admin() {
+                    return Err(ScullError::NotPrivileged.into());
+                }
+                pipe_file.pipe.shutdown();
+                Ok(0)
+            }
+            SCULL_IOCGVERSION => {
+                write_user(arg, &ScullVersion::get())?;
+                Ok(0)
+            }
+            SCULL_P_IOCSDEADLINE => {
+                let ms = u32::try_from(arg).map_err(|_| ScullError::InvalidArgument)?;
+                pipe_file.deadline_ms.store(ms, Ordering::Relaxed);
+                Ok(0)
+            }
+            _ => Err(ScullError::UnknownCommand.into()),
+        }
+    }
+}
+
+// --- Events Device ---
+
+/// The write notification stream of a scull device.
+#[pin_data]
+struct ScullEventsDevice {
+    dev: Arc<ScullDev>,
+    #[pin]
+    misc: MiscDeviceRegistration<RustScullEvents>,
+}
+
+impl ScullEventsDevice {
+    fn register(name: &'static CStr, dev: Arc<ScullDev>) -> Result<Pin<KBox<Self>>> {
+        let options = MiscDeviceOptions { name };
+
+        KBox::pin_init(
+            try_pin_init!(ScullEventsDevice {
+                dev,
+                misc <- MiscDeviceRegistration::register(options),
+            }),
+            GFP_KERNEL,
+        )
+    }
+}
+
+/// Per-open events state; the queue itself lives in `ScullDev::watchers`.
+struct EventsFile {
+    dev: Arc<ScullDev>,
+}
+
+impl EventsFile {
+    fn id(&self) -> usize {
+        core::ptr::from_ref(self) as usize
+    }
+}
+
+struct RustScullEvents;
+
+#[vtable]
+impl MiscDevice for RustScullEvents {
+    type Ptr = KBox<EventsFile>;
+
+    fn open(_file: &File, misc: &MiscDeviceRegistration<Self>) -> Result<Self::Ptr> {
+        pr_debug!("rust_scull: events open()\n");
+
+        // SAFETY: Every `MiscDeviceRegistration<RustScullEvents>` is the `misc` field
+        // of a pinned `ScullEventsDevice`, which outlives the registration.
+        let events =
+            unsafe { &*container_of!(core::ptr::from_ref(misc), ScullEventsDevice, misc) };
+
+        let events_file = KBox::new(
+            EventsFile {
+                dev: events.dev.clone(),
+            },
+            GFP_KERNEL,
+        )?;
+        let queue = EventQueue::new(events_file.id())?;
+        events_file.dev.watchers.lock().push(queue, GFP_KERNEL)?;
+
+        Ok(events_file)
+    }
+
+    fn release(events_file: Self::Ptr, _file: &File) {
+        pr_debug!("rust_scull: events release()\n");
+
+        let id = events_file.id();
+        events_file.dev.watchers.lock().retain(|queue| queue.owner != id);
+    }
+
+    fn read_iter(kiocb: Kiocb<'_, Self::Ptr>, iov: &mut IovIterDest<'_>) -> Result<usize> {
+        let events_file = kiocb.file();
+        events_file
+            .dev
+            .read_events(events_file.id(), iov, is_nonblock(&kiocb))
+    }
+}
+
+// --- Concat Device ---
+
+/// A read-only view of one device's contents followed by another's.
+///
+/// Each member is read under its own lock, so a read crossing the boundary
+/// is not a snapshot of both. A member deleted while selected stays readable
+/// through this view until it is replaced.
+#[pin_data]
+struct ScullConcat {
+    /// `/dev/scull`, member 0.
+    scull: Arc<ScullDev>,
+    #[pin]
+    members: Mutex<[Option<Arc<ScullDev>>; 2]>,
+}
+
+impl ScullConcat {
+    fn new(scull: Arc<ScullDev>) -> Result<Arc<Self>> {
+        let first = scull.clone();
+        Arc::pin_init(
+            try_pin_init!(ScullConcat {
+                scull,
+                members <- new_mutex!([Some(first), None], "ScullConcat::members"),
+            }),
+            GFP_KERNEL,
+        )
+    }
+
+    /// Looks up member `index`, see `ScullConcatMembers`.
+    fn member(&self, index: u32) -> Result<Arc<ScullDev>> {
+        if index == 0 {
+            return Ok(self.scull.clone());
+        }
+        let slot = index as usize - 1;
+        match DYN_DEVICES.lock().get(slot) {
+            Some(Some(dev)) => Ok(dev.dev.clone()),
+            _ => Err(ENODEV),
+        }
+    }
+
+    /// Selects the members, failing with ENODEV if either does not exist.
+    fn set_members(&self, members: ScullConcatMembers) -> Result {
+        let first = self.member(members.first)?;
+        let second = self.member(members.second)?;
+        *self.members.lock() = [Some(first), Some(second)];
+        Ok(())
+    }
+
+    /// Reads from `pos` of the concatenated contents.
+    fn read(&self, pos: u64, iov: &mut IovIterDest<'_>) -> Result<usize> {
+        let members = self.members.lock().clone();
+
+        let mut offset = pos;
+        let mut done = 0;
+        for dev in members.iter().flatten() {
+            if iov.len() == 0 {
+                break;
+            }
+            if let Err(e) = dev.check_live() {
+                return if done == 0 { Err(e) } else { Ok(done) };
+            }
+
+            // Clamp to the size seen here so a member growing mid-read cannot
+            // shift where the next one starts
+            let size = dev.data.lock().data_end();
+            if offset >= size {
+                offset -= size;
+                continue;
+            }
+
+            let read = match dev.read(offset, size, iov) {
+                Ok(read) => read,
+                Err(e) if done == 0 => return Err(e),
+                Err(_) => break,
+            };
+            done += read;
+            if offset + (read as u64) < size {
+                break;
+            }
+            offset = 0;
+        }
+        Ok(done)
+    }
+}
+
+#[pin_data]
+struct ScullConcatDevice {
+    concat: Arc<ScullConcat>,
+    #[pin]
+    misc: MiscDeviceRegistration<RustScullConcat>,
+}
+
+impl ScullConcatDevice {
+    fn register(name: &'static CStr, scull: Arc<ScullDev>) -> Result<Pin<KBox<Self>>> {
+        let options = MiscDeviceOptions { name };
+
+        KBox::pin_init(
+            try_pin_init!(ScullConcatDevice {
+                concat: ScullConcat::new(scull)?,
+                misc <- MiscDeviceRegistration::register(options),
+            }),
+            GFP_KERNEL,
+        )
+    }
+}
+
+/// Per-open concat state.
+struct ConcatFile {
+    concat: Arc<ScullConcat>,
+}
+
+struct RustScullConcat;
+
+#[vtable]
+impl MiscDevice for RustScullConcat {
+    type Ptr = KBox<ConcatFile>;
+
+    fn open(file: &File, misc: &MiscDeviceRegistration<Self>) -> Result<Self::Ptr> {
+        pr_debug!("rust_scull: concat open()\n");
+
+        // SAFETY: `open` runs before the file is installed in any fd table, so
+        // nothing else can be accessing `f_mode`.
+        unsafe { (*file.as_ptr()).f_mode |= FMODE_ATOMIC_POS };
+
+        // SAFETY: Every `MiscDeviceRegistration<RustScullConcat>` is the `misc` field
+        // of a pinned `ScullConcatDevice`, which outlives the registration.
+        let concat =
+            unsafe { &*container_of!(core::ptr::from_ref(misc), ScullConcatDevice, misc) };
+
+        KBox::new(
+            ConcatFile {
+                concat: concat.concat.clone(),
+            },
+            GFP_KERNEL,
+        )
+    }
+
+    fn read_iter(
+        mut kiocb: Kiocb<'_, Self::Ptr>,
+        iov: &mut IovIterDest<'_>,
+    ) -> Result<usize> {
+        let pos = kiocb.ki_pos();
+        let result = kiocb.file().concat.read(pos as u64, iov);
+        if let Ok(read) = result {
+            *kiocb.ki_pos_mut() = pos + read as i64;
+        }
+        result
+    }
+
+    fn ioctl(
+        concat_file: &ConcatFile,
+        _file: &File,
+        cmd: u32,
+        arg: usize,
+    ) -> Result<isize> {
+        match cmd {
+            SCULL_C_IOCSMEMBERS => {
+                let members: ScullConcatMembers = read_user(arg)?;
+                concat_file.concat.set_members(members)?;
+                Ok(0)
+            }
+            _ => Err(ScullError::UnknownCommand.into()),
+        }
+    }
+}
+
+// --- Background Compaction ---
+
+/// Periodically compacts every device, see the `compact_interval_ms`
+/// parameter. Runs as a single long-lived item on `system_long`.
+#[pin_data]
+struct Compactor {
+    /// The static `/dev/scull` device; dynamic ones come from `DYN_DEVICES`.
+    dev: Arc<ScullDev>,
+    interval: Jiffies,
+    #[pin]
+    work: Work<Compactor>,
+    /// Set on unload; the worker exits once it sees it.
+    #[pin]
+    stop: Mutex<bool>,
+    #[pin]
+    wake: CondVar,
+}
+
+impl_has_work! {
+    impl HasWork<Self> for Compactor { self.work }
+}
+
+impl Compactor {
+    fn start(dev: Arc<ScullDev>, interval_ms: u32) -> Result<Arc<Self>> {
+        let compactor = Arc::pin_init(
+            try_pin_init!(Compactor {
+                dev,
+                interval: msecs_to_jiffies(interval_ms),
+                work <- new_work!("Compactor::work"),
+                stop <- new_mutex!(false, "Compactor::stop"),
+                wake <- new_condvar!("Compactor::wake"),
+            }),
+            GFP_KERNEL,
+        )?;
+        let _ = workqueue::system_long().enqueue(compactor.clone());
+        Ok(compactor)
+    }
+
+    /// Compacts each device in turn, holding each lock only for its own pass.
+    fn compact_all(&self) -> usize {
+        let mut freed = self.dev.data.lock().compact();
+        for dev in DYN_DEVICES.lock().iter().flatten() {
+            freed += dev.dev.data.lock().compact();
+        }
+        freed
+    }
+
+    /// Stops the worker and waits for it to exit.
+    fn stop(&self) {
+        *self.stop.lock() = true;
+        self.wake.notify_all();
+        // SAFETY: `work` was initialised by `new_work!` and is pinned inside
+        // this `Compactor`.
+        unsafe { bindings::flush_work(Work::raw_get(&self.work)) };
+    }
+}
+
+impl WorkItem for Compactor {
+    type Pointer = Arc<Compactor>;
+
+    fn run(this: Arc<Compactor>) {
+        loop {
+            let freed = this.compact_all();
+            if freed > 0 {
+                pr_debug!("rust_scull: compaction freed {} bytes\n", freed);
+            }
+
+            let mut stop = this.stop.lock();
+            if !*stop {
+                // Woken early only by `Compactor::stop`
+                let _ = this.wake.wait_interruptible_timeout(&mut stop, this.interval);
+            }
+            if *stop {
+                return;
+            }
+        }
+    }
+}
+
+// --- Soak Testing ---
+
+/// Most soak-test workers `stress_threads` may ask for.
+const SCULL_STRESS_MAX_THREADS: usize = 32;
+/// Bytes per block of a soak-test worker's scratch device.
+const SCULL_STRESS_BLOCK: usize = 512;
+/// Blocks in a soak-test worker's scratch device.
+const SCULL_STRESS_BLOCKS: usize = 256;
+/// Geometries a soak-test worker re-chunks its device to.
+const SCULL_STRESS_GEOMETRIES: [(usize, usize); 4] = [(64, 1), (512, 16), (1000, 3), (4000, 1000)];
+
+/// Bytes per record a torture-mode writer writes.
+const SCULL_TORTURE_RECORD: usize = 64;
+/// Records each torture-mode writer owns.
+const SCULL_TORTURE_SLOTS: usize = 64;
+/// First bytes of every torture record, telling it apart from a hole.
+const SCULL_TORTURE_MAGIC: u32 = 0x5343_5254;
+/// `TortureDev::published` entry of a record whose last write failed
+/// partway, so that it holds nothing that can be checked.
+const SCULL_TORTURE_TORN: u64 = u64::MAX;
+
+/// Returns a random `u32`.
+fn random_u32() -> u32 {
+    // SAFETY: `get_random_u32` has no preconditions.
+    unsafe { bindings::get_random_u32() }
+}
+
+/// The scratch device every worker of a torture run shares, see the
+/// `stress_mode` parameter.
+///
+/// Writer `w` owns records `w * SCULL_TORTURE_SLOTS ..` onwards, record `i`
+/// living at `i * SCULL_TORTURE_RECORD - shift`. Each record carries its
+/// index, a version and a checksum, so a reader can tell a torn, stale or
+/// misplaced record from the one last written.
+struct TortureDev {
+    dev: Arc<ScullDev>,
+    /// Per record, the version last written, 0 if none since the last
+    /// reset, or `SCULL_TORTURE_TORN`. Only changed with `dev.data` held.
+    published: KVec<AtomicU64>,
+    /// Bytes `SCULL_IOCDISCARDFRONT` has dropped since the last reset. Only
+    /// changed with `dev.data` held.
+    shift: AtomicU64,
+    /// Set by the first worker to find an inconsistency; everyone stops.
+    failed: AtomicBool,
+}
+
+impl TortureDev {
+    fn new(writers: usize) -> Result<Arc<Self>> {
+        let mut data = ScullDevData::new();
+        data.max_nodes = 0;
+        data.append_only = false;
+        // Nests with no other device's lock, so any class will do
+        let dev = Arc::pin_init(
+            ScullDev::new(data, c_str!("scull_torture"), data_lock_class(usize::MAX)),
+            GFP_KERNEL,
+        )?;
+
+        let mut published = KVec::with_capacity(writers * SCULL_TORTURE_SLOTS, GFP_KERNEL)?;
+        for _ in 0..writers * SCULL_TORTURE_SLOTS {
+            published.push(AtomicU64::new(0), GFP_KERNEL)?;
+        }
+        Ok(Arc::new(
+            TortureDev {
+                dev,
+                published,
+                shift: AtomicU64::new(0),
+                failed: AtomicBool::new(false),
+            },
+            GFP_KERNEL,
+        )?)
+    }
+
+    /// Builds version `version` of record `index`: magic, index, version, a
+    /// payload derived from all three, and an FNV-1a checksum of the rest.
+    fn record(index: usize, version: u64) -> [u8; SCULL_TORTURE_RECORD] {
+        let mut rec = [0u8; SCULL_TORTURE_RECORD];
+        rec[0..4].copy_from_slice(&SCULL_TORTURE_MAGIC.to_le_bytes());
+        rec[8..16].copy_from_slice(&(index as u64).to_le_bytes());
+        rec[16..24].copy_from_slice(&version.to_le_bytes());
+        for (i, byte) in rec[24..56].iter_mut().enumerate() {
+            *byte = (version.wrapping_mul(31) ^ (index * 7 + i) as u64) as u8;
+        }
+        let sum = stream_sum(STREAM_SUM_INIT, &rec[..56]);
+        rec[56..].copy_from_slice(&sum.to_le_bytes());
+        rec
+    }
+
+    /// Reads the record at `pos` into `rec`, holes and anything past the
+    /// end reading as the fill byte.
+    fn load(data: &ScullDevData, pos: u64, rec: &mut [u8; SCULL_TORTURE_RECORD]) {
+        let end = data.data_end();
+        let mut done = 0;
+        while done < rec.len() {
+            let offset = pos + done as u64;
+            if offset >= end {
+                rec[done..].fill(data.fill_byte);
+                return;
+            }
+            let (len, slice) = data.lookup(offset, clamp_len(end - offset).min(rec.len() - done));
+            match slice {
+                Some(bytes) => rec[done..done + len].copy_from_slice(bytes),
+                None => rec[done..done + len].fill(data.fill_byte),
+            }
+            done += len;
+        }
+    }
+
+    /// Checks the record at `pos` against `expected`, its `published`
+    /// entry, and returns what was wrong with it and the value found.
+    fn check(
+        data: &ScullDevData,
+        index: usize,
+        pos: u64,
+        expected: u64,
+    ) -> core::result::Result<(), (&'static str, u64, u64)> {
+        let mut rec = [0u8; SCULL_TORTURE_RECORD];
+        Self::load(data, pos, &mut rec);
+        let field = |at: usize| u64::from_le_bytes(rec[at..at + 8].try_into().unwrap_or_default());
+
+        if rec.iter().all(|&byte| byte == data.fill_byte) {
+            return match expected {
+                0 => Ok(()),
+                _ => Err(("record missing", expected, 0)),
+            };
+        }
+        let sum = stream_sum(STREAM_SUM_INIT, &rec[..56]);
+        if rec[0..4] != SCULL_TORTURE_MAGIC.to_le_bytes() || field(56) != sum {
+            return Err(("bad checksum", sum, field(56)));
+        }
+        if field(8) != index as u64 {
+            return Err(("misplaced record", index as u64, field(8)));
+        }
+        if field(16) != expected {
+            return Err(("wrong version", expected, field(16)));
+        }
+        Ok(())
+    }
+
+    /// Reports an inconsistency, unless another worker already has, and
+    /// stops the run.
+    fn report(&self, id: u32, pos: u64, what: &str, expected: u64, actual: u64) {
+        if self.failed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        pr_err!(
+            "rust_scull: stress worker {}: {} at offset {} (shift {}): expected {:#x}, found {:#x}, generation {}\n",
+            id,
+            what,
+            pos,
+            self.shift.load(Ordering::Relaxed),
+            expected,
+            actual,
+            self.dev.generation.load(Ordering::Relaxed)
+        );
+    }
+
+    /// Empties the device and forgets every record.
+    fn reset(&self, data: &mut ScullDevData) {
+        data.trim();
+        self.shift.store(0, Ordering::Relaxed);
+        for version in self.published.iter() {
+            version.store(0, Ordering::Relaxed);
+        }
+        self.dev.next_generation();
+    }
+}
+
+/// What a soak-test worker does.
+#[derive(Clone, Copy)]
+enum StressRole {
+    /// Random operations on a scratch device of its own.
+    Soak,
+    /// Torture mode: writes versions of the records it owns.
+    Writer,
+    /// Torture mode: checks random records against what was last written.
+    Reader,
+    /// Torture mode: resets, discards the front of and re-chunks the device.
+    Chaos,
+}
+
+/// A soak-test worker, see the `stress_threads` and `stress_mode`
+/// parameters. Runs as a long-lived item on `system_long`.
+///
+/// A `Soak` worker exercises a scratch device of its own, so it never
+/// touches data a user wrote, and checks every read against what it wrote:
+/// block by block, either its pattern or, if not written since the last
+/// trim, the fill byte. The torture roles share a `TortureDev` instead.
+#[pin_data]
+struct StressWorker {
+    id: u32,
+    role: StressRole,
+    /// The shared device, for the torture roles.
+    shared: Option<Arc<TortureDev>>,
+    /// `now_ns` time at which to stop.
+    deadline_ns: u64,
+    #[pin]
+    work: Work<StressWorker>,
+    /// Set on unload; the worker exits at its next operation.
+    stop: AtomicBool,
+}
+
+impl_has_work! {
+    impl HasWork<Self> for StressWorker { self.work }
+}
+
+impl StressWorker {
+    fn start(
+        id: u32,
+        seconds: u32,
+        role: StressRole,
+        shared: Option<Arc<TortureDev>>,
+    ) -> Result<Arc<Self>> {
+        let worker = Arc::pin_init(
+            try_pin_init!(StressWorker {
+                id,
+                role,
+                shared,
+                deadline_ns: now_ns() + seconds as u64 * 1_000_000_000,
+                work <- new_work!("StressWorker::work"),
+                stop: AtomicBool::new(false),
+            }),
+            GFP_KERNEL,
+        )?;
+        let _ = workqueue::system_long().enqueue(worker.clone());
+        Ok(worker)
+    }
+
+    /// Stops the worker and waits for it to exit.
+    fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // SAFETY: `work` was initialised by `new_work!` and is pinned inside
+        // this `StressWorker`.
+        unsafe { bindings::flush_work(Work::raw_get(&self.work)) };
+    }
+
+    /// The byte this worker writes at `offset`.
+    fn pattern(&self, offset: u64) -> u8 {
+        (offset.wrapping_mul(31) ^ (offset >> 8)) as u8 ^ (self.id as u8).wrapping_mul(97)
+    }
+
+    /// Returns true if `block` reads back as expected.
+    fn verify(&self, data: &ScullDevData, block: usize, written: bool) -> bool {
+        let start = (block * SCULL_STRESS_BLOCK) as u64;
+        let end = start + SCULL_STRESS_BLOCK as u64;
+        let readable = end.min(data.data_end());
+        if written && readable < end {
+            return false;
+        }
+
+        let mut pos = start;
+        while pos < readable {
+            let (len, slice) = data.lookup(pos, clamp_len(readable - pos));
+            for i in 0..len {
+                let actual = slice.map_or(data.fill_byte, |bytes| bytes[i]);
+                let expected = match written {
+                    true => self.pattern(pos + i as u64),
+                    false => data.fill_byte,
+                };
+                if actual != expected {
+                    return false;
+                }
+            }
+            pos += len as u64;
+        }
+        true
+    }
+
+    /// Runs random operations until the deadline or `stop`, then checks
+    /// every block. Returns the number of operations and of failed checks.
+    fn soak(&self) -> Result<(u64, u64)> {
+        let mut data = ScullDevData::new();
+        let mut written = KVec::new();
+        written.resize(SCULL_STRESS_BLOCKS, false, GFP_KERNEL)?;
+        let mut buf = KVec::new();
+        buf.resize(SCULL_STRESS_BLOCK, 0, GFP_KERNEL)?;
+
+        let mut ops = 0;
+        let mut failures = 0;
+        while now_ns() < self.deadline_ns && !self.stop.load(Ordering::Relaxed) {
+            let r = random_u32();
+            let block = (r >> 8) as usize % SCULL_STRESS_BLOCKS;
+            match r % 16 {
+                0..=6 => {
+                    let offset = (block * SCULL_STRESS_BLOCK) as u64;
+                    for (i, byte) in buf.iter_mut().enumerate() {
+                        *byte = self.pattern(offset + i as u64);
+                    }
+                    if data.store(offset, &buf, current_uid()).is_ok() {
+                        written[block] = true;
+                    } else {
+                        // Out of memory or over quota: start again from empty
+                        // rather than track a partly written block
+                        data.trim();
+                        written.fill(false);
+                    }
+                }
+                7..=12 => {
+                    if !self.verify(&data, block, written[block]) {
+                        failures += 1;
+                    }
+                }
+                13 => {
+                    data.trim();
+                    written.fill(false);
+                }
+                14 => {
+                    data.compact();
+                }
+                _ => {
+                    let geometries = &SCULL_STRESS_GEOMETRIES;
+                    let (quantum, qset) = geometries[block % geometries.len()];
+                    // Fails harmlessly with the contents unchanged
+                    let _ = data.requantum(quantum, qset);
+                }
+            }
+            ops += 1;
+
+            // Nothing here sleeps otherwise, so yield now and then
+            if ops % 64 == 0 {
+                // SAFETY: Called from process context holding no locks.
+                unsafe { bindings::msleep(1) };
+            }
+        }
+
+        for (block, &was_written) in written.iter().enumerate() {
+            if !self.verify(&data, block, was_written) {
+                failures += 1;
+            }
+        }
+        data.trim();
+        Ok((ops, failures))
+    }
+
+    /// Runs this worker's torture role until the deadline, `stop`, or the
+    /// first inconsistency anyone finds. Returns the number of operations
+    /// and 1 if this worker found the inconsistency.
+    fn torture(&self, t: &TortureDev) -> Result<(u64, u64)> {
+        let records = t.published.len();
+        let mut version = 0;
+        let mut ops = 0;
+        while now_ns() < self.deadline_ns
+            && !self.stop.load(Ordering::Relaxed)
+            && !t.failed.load(Ordering::Relaxed)
+        {
+            let r = random_u32() as usize;
+            match self.role {
+                StressRole::Writer => {
+                    let index = self.id as usize * SCULL_TORTURE_SLOTS + r % SCULL_TORTURE_SLOTS;
+                    version += 1;
+                    let rec = TortureDev::record(index, version);
+
+                    let mut data = t.dev.data.lock();
+                    // A record discarded from the front is gone until the
+                    // next reset
+                    let home = (index * SCULL_TORTURE_RECORD) as u64;
+                    if let Some(pos) = home.checked_sub(t.shift.load(Ordering::Relaxed)) {
+                        let published = match data.store(pos, &rec, current_uid()) {
+                            Ok(()) => version,
+                            Err(_) => SCULL_TORTURE_TORN,
+                        };
+                        t.published[index].store(published, Ordering::Relaxed);
+                        t.dev.next_generation();
+                    }
+                }
+                StressRole::Reader => {
+                    let index = r % records;
+                    let data = t.dev.data.lock();
+                    let home = (index * SCULL_TORTURE_RECORD) as u64;
+                    let expected = t.published[index].load(Ordering::Relaxed);
+                    if let Some(pos) = home.checked_sub(t.shift.load(Ordering::Relaxed)) {
+                        if expected != SCULL_TORTURE_TORN {
+                            if let Err((what, want, found)) =
+                                TortureDev::check(&data, index, pos, expected)
+                            {
+                                t.report(self.id, pos, what, want, found);
+                                return Ok((ops + 1, 1));
+                            }
+                        }
+                    }
+                }
+                StressRole::Chaos => {
+                    // Give the writers and readers time between upheavals
+                    // SAFETY: Called from process context holding no locks.
+                    unsafe { bindings::msleep((1 + r % 10) as u32) };
+
+                    let mut data = t.dev.data.lock();
+                    match r % 4 {
+                        0 => t.reset(&mut data),
+                        1 | 2 => {
+                            // Whole records only, so that every record's
+                            // position stays a multiple of the record size
+                            let end = data.data_end() / SCULL_TORTURE_RECORD as u64;
+                            let len = (1 + (r >> 8) as u64 % (SCULL_TORTURE_SLOTS as u64)).min(end);
+                            if let Ok(n) = data.discard_front(len * SCULL_TORTURE_RECORD as u64) {
+                                t.shift.fetch_add(n, Ordering::Relaxed);
+                                t.dev.next_generation();
+                            }
+                        }
+                        _ => {
+                            let geometries = &SCULL_STRESS_GEOMETRIES;
+                            let (quantum, qset) = geometries[(r >> 8) % geometries.len()];
+                            // Fails harmlessly with the contents unchanged
+                            let _ = data.requantum(quantum, qset);
+                        }
+                    }
+                }
+                StressRole::Soak => return Err(EINVAL),
+            }
+            ops += 1;
+
+            if ops % 64 == 0 {
+                // SAFETY: Called from process context holding no locks.
+                unsafe { bindings::msleep(1) };
+            }
+        }
+        Ok((ops, 0))
+    }
+}
+
+impl WorkItem for StressWorker {
+    type Pointer = Arc<StressWorker>;
+
+    fn run(this: Arc<StressWorker>) {
+        let result = match (this.role, &this.shared) {
+            (StressRole::Soak, _) => this.soak(),
+            (_, Some(shared)) => this.torture(shared),
+            (_, None) => Err(EINVAL),
+        };
+        match result {
+            Ok((ops, 0)) => pr_info!("rust_scull: stress worker {}: PASS, {} ops\n", this.id, ops),
+            Ok((ops, failures)) => pr_err!(
+                "rust_scull: stress worker {}: FAIL, {} of {} ops\n",
+                this.id,
+                failures,
+                ops
+            ),
+            Err(e) => pr_err!("rust_scull: stress worker {}: setup failed: {:?}\n", this.id, e),
+        }
+    }
+}
+
+// --- Module Implementation ---
+
+struct ScullModule {
+    _dev: Pin<KBox<ScullDevice>>,
+    _pipe: Pin<KBox<ScullPipeDevice>>,
+    _events: Pin<KBox<ScullEventsDevice>>,
+    _concat: Pin<KBox<ScullConcatDevice>>,
+    _parts: KVec<Pin<KBox<ScullDevice>>>,
+    compactor: Option<Arc<Compactor>>,
+    stress: KVec<Arc<StressWorker>>,
+    torture: Option<Arc<TortureDev>>,
+}
+
+impl kernel::Module for ScullModule {
+    fn init(_module: &'static ThisModule) -> Result<Self> {
+        pr_info!("rust_scull: Initializing module.\n");
+
+        let (quantum, qset) = default_geometry();
+        if quantum == 0 || qset == 0 || quantum.checked_mul(qset).is_none() {
+            pr_err!("rust_scull: invalid geometry quantum={} qset={}\n", quantum, qset);
+            return Err(ScullError::InvalidArgument.into());
+        }
+        let backend = *module_parameters::backend.value();
+        if backend as usize > SCULL_BACKEND_FLAT {
+            pr_err!("rust_scull: invalid backend {}\n", backend);
+            return Err(ScullError::InvalidArgument.into());
+        }
+
+        init_uid_quota();
+        UID_QUOTA.lock().limit = *module_parameters::uid_quota.value();
+        // SAFETY: Called exactly once, before any device is registered.
+        unsafe { DYN_DEVICES.init() };
+
+        let dev = ScullDevice::register(c_str!("scull"), 0, ScullDevData::new())?;
+        let pipe = ScullPipeDevice::register(c_str!("scullpipe"))?;
+        let events = ScullEventsDevice::register(c_str!("scull.events"), dev.dev.clone())?;
+        let concat = ScullConcatDevice::register(c_str!("scull.concat"), dev.dev.clone())?;
+
+        let nparts = *module_parameters::partitions.value() as usize;
+        let part_size = *module_parameters::partition_size.value();
+        if nparts > SCULL_PART_NAMES.len()
+            || (nparts > 0 && part_size == 0)
+            || part_size.checked_mul(nparts as u64).is_none()
+        {
+            return Err(ScullError::InvalidArgument.into());
+        }
+        let nstress = *module_parameters::stress_threads.value() as usize;
+        let nreaders = *module_parameters::stress_readers.value() as usize;
+        let torture = match *module_parameters::stress_mode.value() {
+            0 => false,
+            1 => true,
+            _ => return Err(ScullError::InvalidArgument.into()),
+        };
+        if nstress > SCULL_STRESS_MAX_THREADS || nreaders > SCULL_STRESS_MAX_THREADS {
+            return Err(ScullError::InvalidArgument.into());
+        }
+
+        let mut parts = KVec::with_capacity(nparts, GFP_KERNEL)?;
+        for (i, name) in SCULL_PART_NAMES[..nparts].iter().enumerate() {
+            let base = i as u64 * part_size;
+            parts.push(
+                ScullDevice::partition(name, dev.dev.clone(), base, part_size)?,
+                GFP_KERNEL,
+            )?;
+        }
+
+        pr_info!(
+            "rust_scull: Module initialized. Devices: /dev/scull, /dev/scullpipe, /dev/scull.events, /dev/scull.concat\n"
+        );
 
-impl ScullDevData {
-    fn new() -> Self {
-        ScullDevData {
-            data: None,
-            quantum: SCULL_QUANTUM_DEFAULT,
-            qset: SCULL_QSET_DEFAULT,
-            size: 0,
+        // In torture mode the stress_threads workers are the writers,
+        // followed by the readers and one chaos thread
+        let seconds = *module_parameters::stress_seconds.value();
+        let mut roles = KVec::with_capacity(nstress + nreaders + 1, GFP_KERNEL)?;
+        let mut torture_dev = None;
+        if torture && nstress > 0 {
+            torture_dev = Some(TortureDev::new(nstress)?);
+            for _ in 0..nstress {
+                roles.push(StressRole::Writer, GFP_KERNEL)?;
+            }
+            for _ in 0..nreaders {
+                roles.push(StressRole::Reader, GFP_KERNEL)?;
+            }
+            roles.push(StressRole::Chaos, GFP_KERNEL)?;
+        } else {
+            for _ in 0..nstress {
+                roles.push(StressRole::Soak, GFP_KERNEL)?;
+            }
         }
-    }
-
-    /// Empties the device.
-    fn trim(&mut self) {
-        let mut current = self.data.take();
+        let mut stress = KVec::with_capacity(roles.len(), GFP_KERNEL)?;
 
-        while let Some(mut qset_node) = current {
-            if let Some(data_array) = qset_node.data.take() {
-                for _quantum in data_array.into_iter().flatten() {
-                    // Quantum is dropped here
+        // Nothing may fail once the compactor or a stress worker is running,
+        // or it would outlive the module
+        let compactor = match *module_parameters::compact_interval_ms.value() {
+            0 => None,
+            ms => Some(Compactor::start(dev.dev.clone(), ms)?),
+        };
+        for (id, &role) in roles.iter().enumerate() {
+            match StressWorker::start(id as u32, seconds, role, torture_dev.clone()) {
+                // Cannot fail, the capacity is reserved
+                Ok(worker) => stress.push(worker, GFP_KERNEL)?,
+                Err(e) => {
+                    for worker in stress.iter() {
+                        worker.stop();
+                    }
+                    if let Some(torture_dev) = &torture_dev {
+                        torture_dev.dev.data.lock().trim();
+                    }
+                    if let Some(compactor) = &compactor {
+                        compactor.stop();
+                    }
+                    return Err(e);
                 }
             }
-            current = qset_node.next.take();
         }
 
-        self.size = 0;
-        self.quantum = SCULL_QUANTUM_DEFAULT;
-        self.qset = SCULL_QSET_DEFAULT;
+        Ok(ScullModule {
+            _dev: dev,
+            _pipe: pipe,
+            _events: events,
+            _concat: concat,
+            _parts: parts,
+            compactor,
+            stress,
+            torture: torture_dev,
+        })
     }
+}
 
-    fn follow(&mut self, item: usize) -> Result<&mut ScullQset> {
-        let current = &mut self.data;
+impl Drop for ScullModule {
+    fn drop(&mut self) {
+        for worker in self.stress.iter() {
+            worker.stop();
+        }
+        if let Some(torture) = &self.torture {
+            torture.dev.data.lock().trim();
+        }
+        if let Some(compactor) = &self.compactor {
+            compactor.stop();
+        }
 
-        // Allocate first qset if needed
-        if current.is_none() {
-            *current = Some(KBox::new(
-                ScullQset {
-                    data: None,
-                    next: None,
-                },
-                GFP_KERNEL,
-            )?);
+        // No migration step may run once the module text is gone
+        self._dev.dev.stop_migration();
+        let mut devices = DYN_DEVICES.lock();
+        for dev in devices.iter().flatten() {
+            dev.dev.stop_migration();
         }
+        // scull.concat may still hold dynamic devices; drop those references
+        // too so that the devices are freed here
+        *self._concat.concat.members.lock() = [None, None];
+        devices.clear();
+        drop(devices);
 
-        let mut current_node = current.as_mut().unwrap();
+        self._pipe.pipe.shutdown();
 
-        // Follow the list `item` times
-        for _ in 0..item {
-            if current_node.next.is_none() {
-                current_node.next = Some(KBox::new(
-                    ScullQset {
-                        data: None,
-                        next: None,
-                    },
-                    GFP_KERNEL,
-                )?);
+        // Free `/dev/scull`'s data now rather than when the fields drop, so
+        // that any quantum still charged at this point is a leak
+        let mut inner = self._dev.dev.data.lock();
+        inner.migration = None;
+        inner.trim();
+        drop(inner);
+
+        let quota = UID_QUOTA.lock();
+        let usage: u64 = quota.usage.iter().map(|&(_, bytes)| bytes).sum();
+        let quanta = LIVE_QUANTA.load(Ordering::Relaxed);
+        let nodes = LIVE_NODES.load(Ordering::Relaxed);
+        if usage != 0 || quanta != 0 || nodes != 0 {
+            pr_err!(
+                "rust_scull: leaked {} quanta ({} bytes) and {} qset nodes\n",
+                quanta,
+                usage,
+                nodes
+            );
+            for &(uid, bytes) in quota.usage.iter().filter(|&&(_, bytes)| bytes != 0) {
+                pr_err!("rust_scull:   uid {}: {} bytes\n", uid, bytes);
             }
-            current_node = current_node.next.as_mut().unwrap();
+            kernel::warn_on!(cfg!(CONFIG_DEBUG_KERNEL));
         }
+        drop(quota);
 
-        Ok(&mut **current_node)
+        pr_info!("rust_scull: Module cleanup complete.\n");
     }
 }
+// --- KUnit Tests ---
+//
+// KUnit runs a module's suites as it loads, possibly before `init`. Tests
+// that allocate quanta go through `test_dev`, which initializes `UID_QUOTA`
+// first, and free everything they allocate so that the leak check on unload
+// stays quiet.
 
-// --- Device Implementation ---
+#[kernel::macros::kunit_tests(rust_scull)]
+mod tests {
+    use super::*;
 
-struct RustScull;
+    /// Creates a device outside any registration, with the default settings
+    /// whatever the module parameters say.
+    pub(super) fn test_dev() -> Result<Arc<ScullDev>> {
+        init_uid_quota();
+        let mut data = ScullDevData::new();
+        data.flat = false;
+        data.max_nodes = 0;
+        data.append_only = false;
+        Arc::pin_init(
+            ScullDev::new(data, c_str!("scull_test"), data_lock_class(0)),
+            GFP_KERNEL,
+        )
+    }
 
-#[vtable]
-impl MiscDevice for RustScull {
-    type Ptr = Arc<Mutex<ScullDevData>>;
+    /// Writes `bytes` at `*pos` as `write_iter` would, from kernel memory.
+    pub(super) fn write_at(dev: &ScullDev, pos: &mut u64, bytes: &[u8]) -> Result<usize> {
+        let kvec = bindings::kvec {
+            iov_base: bytes.as_ptr().cast_mut().cast(),
+            iov_len: bytes.len(),
+        };
+        let mut iter = core::mem::MaybeUninit::<bindings::iov_iter>::uninit();
+        // SAFETY: `kvec` describes `bytes`, which the iterator only reads, and
+        // both outlive the iterator.
+        let iov = unsafe {
+            bindings::iov_iter_kvec(iter.as_mut_ptr(), bindings::ITER_SOURCE, &kvec, 1, bytes.len());
+            IovIterSource::from_raw(iter.as_mut_ptr())
+        };
+        dev.write(pos, false, u64::MAX, iov)
+    }
 
-    fn open(_file: &File, _misc: &MiscDeviceRegistration<Self>) -> Result<Self::Ptr> {
-        pr_debug!("rust_scull: open()\n");
+    /// Reads into `buf` from `pos` as `read_iter` would, into kernel memory.
+    pub(super) fn read_at(dev: &ScullDev, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        let kvec = bindings::kvec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        let mut iter = core::mem::MaybeUninit::<bindings::iov_iter>::uninit();
+        // SAFETY: `kvec` describes `buf`, which the iterator has to itself,
+        // and both outlive the iterator.
+        let iov = unsafe {
+            bindings::iov_iter_kvec(iter.as_mut_ptr(), bindings::ITER_DEST, &kvec, 1, buf.len());
+            IovIterDest::from_raw(iter.as_mut_ptr())
+        };
+        dev.read(pos, u64::MAX, iov)
+    }
 
-        let data = Arc::pin_init(new_mutex!(ScullDevData::new(), "ScullDevData"), GFP_KERNEL)?;
+    #[test]
+    fn split_offset_boundaries() {
+        let (quantum, qset) = (4000, 1000);
+        let itemsize = (quantum * qset) as u64;
 
+        assert_eq!(split_offset(0, quantum, qset), (0, 0, 0));
+        assert_eq!(split_offset(quantum as u64 - 1, quantum, qset), (0, 0, quantum - 1));
+        assert_eq!(split_offset(quantum as u64, quantum, qset), (0, 1, 0));
+        assert_eq!(split_offset(itemsize - 1, quantum, qset), (0, qset - 1, quantum - 1));
+        assert_eq!(split_offset(itemsize, quantum, qset), (1, 0, 0));
+        assert_eq!(split_offset(3 * itemsize + 5, quantum, qset), (3, 0, 5));
+    }
 
-        // Note: We can't easily check for O_WRONLY here without file flags access
-        // This is a limitation of the current API
+    #[test]
+    fn split_offset_tiny_geometry() {
+        assert_eq!(split_offset(0, 1, 1), (0, 0, 0));
+        assert_eq!(split_offset(7, 1, 1), (7, 0, 0));
+        assert_eq!(split_offset(4, 1, 3), (1, 1, 0));
+        assert_eq!(split_offset(4, 3, 1), (1, 0, 1));
+    }
 
-        Ok(data)
+    #[test]
+    fn quantum_span_stops_at_boundary() {
+        assert_eq!(quantum_span(10, 4, 0), 4);
+        assert_eq!(quantum_span(10, 4, 3), 1);
+        assert_eq!(quantum_span(2, 4, 1), 2);
+        assert_eq!(quantum_span(0, 4, 0), 0);
+        assert_eq!(quantum_span(usize::MAX, 1, 0), 1);
     }
 
-    fn release(device: Self::Ptr, _file: &File) {
-        pr_debug!("rust_scull: release()\n");
-        // Device data is automatically dropped when Arc count reaches 0
-        drop(device);
+    #[test]
+    fn size_conversions() {
+        assert_eq!(clamp_len(0), 0);
+        assert_eq!(clamp_len(42), 42);
+        assert_eq!(clamp_len(u64::MAX), usize::MAX);
+
+        assert_eq!(ret_size(42), Ok(42));
+        assert_eq!(ret_size(u64::MAX), Err(EOVERFLOW));
     }
 
-    fn read_iter(
-        kiocb: kernel::fs::Kiocb<'_, Self::Ptr>,
-        iov: &mut IovIterDest<'_>,
-    ) -> Result<usize> {
-        let offset = kiocb.ki_pos() as u64;
-        let device = kiocb.file();
-        let inner = device.lock();
+    #[test]
+    fn max_size_keeps_item_in_range() {
+        let mut data = ScullDevData::new();
+        for (quantum, qset) in [(1, 1), (1, 3), (4000, 1000), (usize::MAX, 1)] {
+            data.quantum = quantum;
+            data.qset = qset;
 
-        let itemsize = inner.quantum * inner.qset;
+            let max = data.max_size();
+            assert!(max <= i64::MAX as u64);
+            let itemsize = (quantum * qset) as u64;
+            let (item, _, _) = data.position(max - 1);
+            assert_eq!(item as u64, (max - 1) / itemsize);
+        }
+    }
 
-        // Check for EOF
-        if offset >= inner.size {
-            return Ok(0);
+    #[test]
+    fn translate_rejects_bad_positions() -> Result {
+        let mut file = ScullFile {
+            dev: test_dev()?,
+            index: 0,
+            base: 100,
+            limit: 200,
+        };
+        assert_eq!(file.translate(0), Ok(100));
+        assert_eq!(file.translate(5), Ok(105));
+        assert_eq!(file.translate(-1), Err(EINVAL));
+
+        file.base = u64::MAX - 1;
+        assert_eq!(file.translate(1), Ok(u64::MAX));
+        assert_eq!(file.translate(2), Err(EINVAL));
+        Ok(())
+    }
+
+    #[test]
+    fn trim_empty() {
+        let mut data = ScullDevData::new();
+        assert!(data.is_empty());
+
+        data.trim();
+        assert!(data.is_empty());
+        assert!(data.data.is_none());
+        assert_eq!(data.node_count, 0);
+    }
+
+    #[test]
+    fn trim_one_node() {
+        let live = LIVE_NODES.load(Ordering::Relaxed);
+        let mut data = ScullDevData::new();
+
+        assert!(data.follow(0).is_ok());
+        assert_eq!(data.node_count, 1);
+        // An unused head node does not make the device non-empty
+        assert!(data.is_empty());
+
+        data.trim();
+        assert!(data.data.is_none());
+        assert_eq!(data.node_count, 0);
+        assert_eq!(LIVE_NODES.load(Ordering::Relaxed), live);
+    }
+
+    #[test]
+    fn trim_long_chain() {
+        let live = LIVE_NODES.load(Ordering::Relaxed);
+        let mut data = ScullDevData::new();
+        data.max_nodes = 0;
+
+        assert!(data.follow(9999).is_ok());
+        assert_eq!(data.node_count, 10000);
+        assert_eq!(LIVE_NODES.load(Ordering::Relaxed), live + 10000);
+
+        data.trim();
+        assert!(data.is_empty());
+        assert_eq!(data.node_count, 0);
+        assert_eq!(LIVE_NODES.load(Ordering::Relaxed), live);
+    }
+
+    #[test]
+    fn trim_restores_geometry() {
+        let mut data = ScullDevData::new();
+        data.quantum = 1;
+        data.qset = 1;
+        data.align = 4;
+
+        data.trim();
+        assert_eq!((data.quantum, data.qset), default_geometry());
+        assert_eq!(data.running_sum, STREAM_SUM_INIT);
+    }
+
+    #[test]
+    fn sparse_layout() {
+        let mut data = ScullDevData::new();
+        data.quantum = 1;
+        data.qset = 1;
+
+        // Reaching node 3 allocates the nodes before it, but no quanta
+        assert!(data.follow(3).is_ok());
+        assert_eq!(data.node_count, 4);
+        assert!(!data.is_empty());
+        for item in 0..5 {
+            assert!(data.quantum_at(item, 0).is_none());
         }
+        // Following an existing node allocates nothing
+        assert!(data.follow(2).is_ok());
+        assert_eq!(data.node_count, 4);
 
-        if itemsize == 0 {
-            return Err(EFAULT);
+        data.trim();
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn flat_layout() {
+        let mut data = ScullDevData::new();
+        data.flat = true;
+        assert_eq!(data.max_size(), i32::MAX as u64);
+
+        // Before the first write the whole device is one hole
+        assert_eq!(data.span(12345, 1 << 20), 1 << 20);
+        let (quantum_buf, _, len) = data.segment(12345, 1 << 20);
+        assert!(quantum_buf.is_none());
+        assert_eq!(len, 1 << 20);
+        assert!(!data.is_written(0, 1 << 20));
+
+        data.size = 100;
+        let mut out = [ScullExtent::default(); 1];
+        assert_eq!(data.map_extents(0, &mut out), (0, 100));
+        assert!(data.check(0, 1).is_ok());
+
+        // The layout does not depend on the geometry, so nothing is copied
+        assert!(data.requantum(7, 3).is_ok());
+        assert_eq!((data.quantum, data.qset), (7, 3));
+        assert!(data.data.is_none());
+        assert_eq!(data.node_count, 0);
+    }
+
+    #[test]
+    fn node_limit() {
+        let mut data = ScullDevData::new();
+        data.max_nodes = 2;
+
+        assert!(data.follow(1).is_ok());
+        assert_eq!(data.follow(2).err(), Some(EFBIG));
+        assert_eq!(data.node_count, 2);
+
+        data.trim();
+        assert_eq!(data.node_count, 0);
+    }
+
+    #[test]
+    fn data_end_excludes_sentinel() {
+        let mut data = ScullDevData::new();
+        assert_eq!(data.data_end(), 0);
+
+        data.sentinel = Some(0);
+        assert_eq!(data.data_end(), 0);
+        data.size = 10;
+        assert_eq!(data.data_end(), 9);
+
+        data.sentinel = None;
+        assert_eq!(data.data_end(), 10);
+        data.size = 0;
+    }
+
+    #[test]
+    fn alignment() {
+        let mut data = ScullDevData::new();
+        assert!(data.check_aligned(3, 5).is_ok());
+
+        data.align = 4;
+        assert!(data.check_aligned(8, 4).is_ok());
+        assert_eq!(data.check_aligned(2, 4), Err(EINVAL));
+        assert_eq!(data.check_aligned(8, 3), Err(EINVAL));
+    }
+
+    /// Creates a device whose `data` lock is in the class of device `index`.
+    fn indexed_dev(index: usize) -> Result<Arc<ScullDev>> {
+        Arc::pin_init(
+            ScullDev::new(ScullDevData::new(), c_str!("scull_test"), data_lock_class(index)),
+            GFP_KERNEL,
+        )
+    }
+
+    #[test]
+    fn nested_data_locks() -> Result {
+        // In ascending index order. Were the classes shared, lockdep would
+        // report this as recursive locking.
+        let (first, second) = (indexed_dev(0)?, indexed_dev(1)?);
+        let outer = first.data.lock();
+        let inner = second.data.lock();
+        drop(inner);
+        drop(outer);
+        Ok(())
+    }
+
+    /// Takes the locks of `nested_data_locks` both ways round, which
+    /// lockdep should report as a possible deadlock. Only built with
+    /// `SCULL_LOCKDEP_TEST=y`, since lockdep turns itself off after its
+    /// first report.
+    #[cfg(scull_lockdep_test)]
+    #[test]
+    fn wrong_order_data_locks() -> Result {
+        let (first, second) = (indexed_dev(0)?, indexed_dev(1)?);
+        // Record the right order first, whichever case ran before
+        let outer = first.data.lock();
+        drop(second.data.lock());
+        drop(outer);
+
+        let outer = second.data.lock();
+        let inner = first.data.lock();
+        drop(inner);
+        drop(outer);
+        Ok(())
+    }
+
+    #[test]
+    fn pipe_shutdown() -> Result {
+        let pipe = Arc::pin_init(ScullPipe::new(), GFP_KERNEL)?;
+        pipe.attach(true, true)?;
+
+        let mut buf = [0u8; 4];
+        let kvec = bindings::kvec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        let mut iter = core::mem::MaybeUninit::<bindings::iov_iter>::uninit();
+        // SAFETY: `kvec` describes `buf`, which the iterator has to itself,
+        // and both outlive the iterator.
+        let iov = unsafe {
+            bindings::iov_iter_kvec(iter.as_mut_ptr(), bindings::ITER_DEST, &kvec, 1, buf.len());
+            IovIterDest::from_raw(iter.as_mut_ptr())
+        };
+
+        // Blocking calls give up rather than wait for data that won't come
+        pipe.shutdown();
+        assert_eq!(pipe.check_live(), Err(ENODEV));
+        assert_eq!(pipe.read(iov, false, None), Err(ENODEV));
+        assert_eq!(pipe.wait_pattern(b"x", false, None), Err(ENODEV));
+        assert_eq!(
+            pipe.attach(true, false).map_err(Error::to_errno),
+            Err(-(bindings::ESHUTDOWN as i32))
+        );
+
+        pipe.detach(true, true);
+        let inner = pipe.inner.lock();
+        assert_eq!((inner.readers, inner.writers), (0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn per_cpu_counter() -> Result {
+        let counter = PerCpuCounter::new()?;
+        assert_eq!(counter.slots.len(), nr_cpu_ids() as usize);
+        counter.add(3);
+        counter.add(4);
+        assert_eq!(counter.sum(), 7);
+
+        // What another, possibly offline, CPU counted is summed too
+        let last = counter.slots.len() - 1;
+        counter.slots[last].0.fetch_add(5, Ordering::Relaxed);
+        assert_eq!(counter.sum(), 12);
+        Ok(())
+    }
+
+    #[test]
+    fn shared_across_opens() -> Result {
+        let dev = test_dev()?;
+        let handles = [1, 2, 3];
+        for _ in handles {
+            dev.count_open()?;
         }
 
-        // Calculate how much to read
-        let mut count = iov.len();
-        if offset + count as u64 > inner.size {
-            count = (inner.size - offset) as usize;
+        // Every open sees the same data
+        assert_eq!(write_at(&dev, &mut 0, b"shared"), Ok(6));
+        let mut buf = [0; 6];
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(6));
+        assert_eq!(&buf, b"shared");
+
+        // Extra references to the device do not delay the last close, and
+        // it happens exactly once
+        let extra = dev.clone();
+        for id in handles {
+            assert_eq!(dev.release(id), id == 3);
         }
+        assert_eq!(dev.open_count.load(Ordering::Relaxed), 0);
+        drop(extra);
 
-        // Find position
-        let item = (offset / itemsize as u64) as usize;
-        let rest = offset % itemsize as u64;
-        let s_pos = (rest / inner.quantum as u64) as usize;
-        let q_pos = (rest % inner.quantum as u64) as usize;
+        dev.data.lock().trim();
+        Ok(())
+    }
 
-        // Follow the list (read-only, no allocation)
-        let mut dptr = inner.data.as_ref();
-        for _ in 0..item {
-            match dptr {
-                Some(node) => dptr = node.next.as_ref(),
-                None => return Ok(0),
-            }
+    #[test]
+    fn multi_quantum_io() -> Result {
+        let dev = test_dev()?;
+        {
+            let mut data = dev.data.lock();
+            data.quantum = 4000;
+            data.qset = 1000;
+        }
+        let mut pattern = KVec::new();
+        for i in 0..4500 {
+            pattern.push(i as u8, GFP_KERNEL)?;
         }
 
-        // Get the quantum
-        let quantum_buf = match dptr
-            .and_then(|node| node.data.as_ref())
-            .and_then(|data_array| data_array.get(s_pos))
-            .and_then(|quantum_opt| quantum_opt.as_ref())
+        // One call fills the first quantum and carries on into the second
+        let mut pos = 0;
+        assert_eq!(write_at(&dev, &mut pos, &pattern), Ok(4500));
+        assert_eq!(pos, 4500);
+        assert!(dev.data.lock().quantum_at(0, 1).is_some());
+
+        let mut buf = KVec::new();
+        buf.resize(4500, 0, GFP_KERNEL)?;
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(4500));
+        assert!(buf[..] == pattern[..]);
+        dev.data.lock().trim();
+        Ok(())
+    }
+
+    #[test]
+    fn final_quantum_clamped() -> Result {
+        let dev = test_dev()?;
         {
-            Some(buf) => buf,
-            None => return Ok(0),
+            let mut data = dev.data.lock();
+            data.quantum = 4000;
+            data.qset = 1000;
+        }
+        let mut buf = KVec::new();
+        buf.resize(4005, b'a', GFP_KERNEL)?;
+        assert_eq!(write_at(&dev, &mut 0, &buf), Ok(4005));
+
+        // The second quantum is allocated in full but holds only 5 bytes
+        assert_eq!(dev.data.lock().quantum_at(0, 1).map(|q| q.buf.len()), Some(4000));
+        buf.fill(0);
+        assert_eq!(read_at(&dev, 4000, &mut buf), Ok(5));
+        assert_eq!(&buf[..6], b"aaaaa\0");
+        assert_eq!(read_at(&dev, 4005, &mut buf), Ok(0));
+        dev.data.lock().trim();
+        Ok(())
+    }
+
+    #[test]
+    fn geometry_busy_while_data() -> Result {
+        let dev = test_dev()?;
+        let mut geometry = ScullGeometry {
+            quantum: 8,
+            qset: 4,
+            flags: 0,
+            reserved: 0,
         };
+        assert_eq!(write_at(&dev, &mut 0, b"precious"), Ok(8));
 
-        // Read only up to the end of this quantum
-        if count > inner.quantum - q_pos {
-            count = inner.quantum - q_pos;
+        // Without FORCE nothing changes
+        {
+            let mut data = dev.data.lock();
+            let before = (data.quantum, data.qset);
+            assert_eq!(data.set_geometry(&geometry), Err(EBUSY));
+            assert_eq!((data.quantum, data.qset), before);
+        }
+        let mut buf = [0; 8];
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(8));
+        assert_eq!(&buf, b"precious");
+
+        // REQUANTUM re-chunks the data instead, but not mid-migration
+        {
+            let mut data = dev.data.lock();
+            data.start_migration(16)?;
+            assert_eq!(data.requantum(8, 4), Err(EBUSY));
+            data.migration = None;
+            data.requantum(8, 4)?;
+            assert_eq!((data.quantum, data.qset, data.size), (8, 4, 8));
+        }
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(8));
+        assert_eq!(&buf, b"precious");
+
+        // FORCE trims first
+        geometry.quantum = 16;
+        geometry.flags = SCULL_GEOMETRY_FORCE;
+        assert_eq!(dev.data.lock().set_geometry(&geometry), Ok(true));
+        let data = dev.data.lock();
+        assert!(data.is_empty());
+        assert_eq!((data.quantum, data.qset, data.size), (16, 4, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn migration() -> Result {
+        let dev = test_dev()?;
+        {
+            let mut data = dev.data.lock();
+            data.quantum = 4;
+            data.qset = 2;
         }
+        assert_eq!(write_at(&dev, &mut 0, b"0123456789"), Ok(10));
 
-        let end = (q_pos + count).min(quantum_buf.len());
-        let slice_to_read = &quantum_buf[q_pos..end];
+        {
+            let mut data = dev.data.lock();
+            data.start_migration(3)?;
+            assert_eq!(data.start_migration(5), Err(EBUSY));
+            while data.migrate_step()? {}
+            assert!(data.migration.is_none());
 
-        // Copy data to user space
-        iov.copy_to_iter(slice_to_read);
+            // Offset 9 is now in node 1, quantum 1
+            assert_eq!((data.quantum, data.qset, data.size), (3, 2, 10));
+            assert_eq!(data.node_count, 2);
+            assert!(data.quantum_at(1, 1).is_some());
+            assert!(data.quantum_at(1, 0).is_some());
+        }
 
-        Ok(slice_to_read.len())
+        let mut buf = [0; 10];
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(10));
+        assert_eq!(&buf, b"0123456789");
+        dev.data.lock().trim();
+        Ok(())
     }
 
-    fn write_iter(
-        kiocb: kernel::fs::Kiocb<'_, Self::Ptr>,
-        iov: &mut IovIterSource<'_>,
-    ) -> Result<usize> {
-        let offset = kiocb.ki_pos() as u64;
-        let device = kiocb.file();
-        let mut inner = device.lock();
+    #[test]
+    fn sentinel_round_trip() -> Result {
+        let dev = test_dev()?;
+        dev.data.lock().sentinel = Some(b'$');
 
-        // cache fields so we don't need to borrow `inner` later
-        let quantum = inner.quantum;
-        let qset = inner.qset;
+        // Stored after the data but never read back
+        assert_eq!(write_at(&dev, &mut 0, b"abc"), Ok(3));
+        assert_eq!(dev.data.lock().size, 4);
+        let mut buf = [0; 16];
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"abc");
+        assert_eq!(read_at(&dev, 3, &mut buf), Ok(0));
 
-        let itemsize = quantum * qset;
+        // The next write at the end overwrites it
+        let mut pos = 3;
+        assert_eq!(write_at(&dev, &mut pos, b"de"), Ok(2));
+        assert_eq!(pos, 5);
+        // and one past the end turns it into part of the hole
+        assert_eq!(write_at(&dev, &mut 8, b"x"), Ok(1));
+        {
+            let data = dev.data.lock();
+            assert_eq!(data.size, 10);
+            let stored = data.quantum_at(0, 0).map(|q| &q.buf[..10]);
+            assert_eq!(stored, Some(&b"abcde\0\0\0x$"[..]));
+        }
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(9));
+        assert_eq!(&buf[..9], b"abcde\0\0\0x");
+        dev.data.lock().trim();
+        Ok(())
+    }
 
-        if itemsize == 0 {
-            return Err(EFAULT);
+    #[test]
+    fn holes_read_as_fill() -> Result {
+        let dev = test_dev()?;
+        {
+            let mut data = dev.data.lock();
+            data.quantum = 4;
+            data.qset = 2;
+            data.fill_byte = b'.';
+        }
+        assert_eq!(write_at(&dev, &mut 0, b"ab"), Ok(2));
+        assert_eq!(write_at(&dev, &mut 16, b"q"), Ok(1));
+        {
+            let mut data = dev.data.lock();
+            // Reaching node 2 left node 1 without a qset array
+            let node = data.data.as_ref().and_then(|node| node.next.as_ref());
+            assert!(node.is_some_and(|node| node.data.is_none()));
+            // and nodes past the chain are missing altogether
+            data.size = 30;
+            assert_eq!(data.node_count, 3);
         }
 
-        let count = iov.len();
+        let mut buf = [0; 32];
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(30));
+        assert_eq!(&buf[..30], b"ab..............q.............");
+        dev.data.lock().trim();
+        Ok(())
+    }
+
+    #[test]
+    fn max_gap() -> Result {
+        let dev = test_dev()?;
+        assert_eq!(write_at(&dev, &mut 0, b"abcd"), Ok(4));
+        dev.data.lock().max_gap = 8;
 
-        // Find position using cached values
-        let item = (offset / itemsize as u64) as usize;
-        let rest = offset % itemsize as u64;
-        let s_pos = (rest / quantum as u64) as usize;
-        let q_pos = (rest % quantum as u64) as usize;
+        // Up to 8 bytes past the end may be left as a hole
+        assert_eq!(write_at(&dev, &mut 13, b"x"), Err(EINVAL));
+        assert_eq!(dev.data.lock().size, 4);
+        assert_eq!(write_at(&dev, &mut 12, b"y"), Ok(1));
+        // measured from the new end
+        assert_eq!(write_at(&dev, &mut 21, b"z"), Ok(1));
+        assert_eq!(dev.data.lock().size, 22);
+        dev.data.lock().trim();
+        Ok(())
+    }
 
-        
-        let written_total: usize;
+    #[test]
+    fn full_item() -> Result {
+        let dev = test_dev()?;
         {
-            let dptr = inner.follow(item)?; 
+            let mut data = dev.data.lock();
+            data.quantum = 4;
+            data.qset = 3;
+        }
 
-            if dptr.data.is_none() {
-                let mut qset_vec = KVec::new();
-                while qset_vec.len() < qset {
-                    qset_vec.push(None, GFP_KERNEL)?;
-                }
-                dptr.data = Some(qset_vec);
+        // Exactly one node's worth fills every quantum of node 0
+        assert_eq!(write_at(&dev, &mut 0, b"abcdefghijkl"), Ok(12));
+        {
+            let data = dev.data.lock();
+            assert_eq!(data.node_count, 1);
+            for s_pos in 0..3 {
+                assert!(data.quantum_at(0, s_pos).is_some());
             }
-            let data_array = dptr.data.as_mut().unwrap();
+        }
 
-            if data_array[s_pos].is_none() {
-                let mut quantum_vec = KVec::new();
-                quantum_vec.resize(quantum, 0, GFP_KERNEL)?;
-                data_array[s_pos] = Some(quantum_vec);
-            }
-            let quantum_buf = data_array[s_pos].as_mut().unwrap();
+        // The next byte starts node 1
+        assert_eq!(write_at(&dev, &mut 12, b"m"), Ok(1));
+        let mut data = dev.data.lock();
+        assert_eq!(data.node_count, 2);
+        assert_eq!(data.quantum_at(1, 0).map(|q| q.buf[0]), Some(b'm'));
+        assert!(data.quantum_at(1, 1).is_none());
+        data.trim();
+        Ok(())
+    }
 
-            let mut write_count = count;
-            if write_count > quantum - q_pos {
-                write_count = quantum - q_pos;
-            }
+    #[test]
+    fn chunked_reads() -> Result {
+        let dev = test_dev()?;
+        {
+            let mut data = dev.data.lock();
+            data.quantum = 4;
+            data.qset = 2;
+        }
+        assert_eq!(write_at(&dev, &mut 0, b"0123456789"), Ok(10));
 
-            let slice_to_write = &mut quantum_buf[q_pos..q_pos + write_count];
+        let mut buf = [0; 10];
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(10));
+        assert_eq!(&buf, b"0123456789");
 
-            let copied = iov.copy_from_iter(slice_to_write);
-            written_total = copied; 
-        } 
+        // At most the rest of one quantum per call
+        dev.data.lock().chunked = true;
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(4));
+        assert_eq!(read_at(&dev, 2, &mut buf), Ok(2));
+        assert_eq!(read_at(&dev, 8, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"89");
+        dev.data.lock().trim();
+        Ok(())
+    }
 
-        let new_offset = offset + written_total as u64;
-        if inner.size < new_offset {
-            inner.size = new_offset;
+    #[test]
+    fn write_once() -> Result {
+        let dev = test_dev()?;
+        dev.data.lock().write_once = true;
+
+        assert_eq!(write_at(&dev, &mut 0, b"abcd"), Ok(4));
+        // Overwriting any written byte fails, even partly
+        assert_eq!(write_at(&dev, &mut 0, b"x"), Err(EPERM));
+        assert_eq!(write_at(&dev, &mut 3, b"xy"), Err(EPERM));
+        // Bytes never written may be, holes included
+        assert_eq!(write_at(&dev, &mut 4, b"ef"), Ok(2));
+        assert_eq!(write_at(&dev, &mut 10, b"k"), Ok(1));
+        assert_eq!(write_at(&dev, &mut 8, b"ij"), Ok(2));
+
+        let mut buf = [0xff; 11];
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(11));
+        assert_eq!(&buf, b"abcdef\0\0ijk");
+        dev.data.lock().trim();
+        Ok(())
+    }
+
+    #[test]
+    fn writes_stop_at_node_limit() -> Result {
+        let dev = test_dev()?;
+        {
+            let mut data = dev.data.lock();
+            data.quantum = 4;
+            data.qset = 2;
+            data.max_nodes = 2;
         }
 
-        Ok(written_total)
+        // Two nodes of 8 bytes each, then a write straddling the end of the
+        // last one is cut short
+        assert_eq!(write_at(&dev, &mut 0, b"01234567"), Ok(8));
+        assert_eq!(write_at(&dev, &mut 14, b"efgh"), Ok(2));
+        assert_eq!(dev.data.lock().node_count, 2);
+        // Nothing at all lands in a third node
+        assert_eq!(write_at(&dev, &mut 16, b"x"), Err(EFBIG));
+        assert_eq!(dev.data.lock().node_count, 2);
+        assert_eq!(dev.data.lock().size, 16);
+
+        // Lowering the limit only stops growth
+        dev.data.lock().max_nodes = 1;
+        assert_eq!(write_at(&dev, &mut 8, b"89"), Ok(2));
+        dev.data.lock().trim();
+        Ok(())
     }
 
-    // fn write_iter(
-    //     kiocb: kernel::fs::Kiocb<'_, Self::Ptr>,
-    //     iov: &mut IovIterSource<'_>,
-    // ) -> Result<usize> {
-    //     let offset = kiocb.ki_pos() as u64;
-    //     let device = kiocb.file();
-    //     let mut inner = device.lock();
-
-    //     let itemsize = inner.quantum * inner.qset;
-
-    //     if itemsize == 0 {
-    //         return Err(EFAULT);
-    //     }
-
-    //     let count = iov.len();
-
-    //     // Find position
-    //     let item = (offset / itemsize as u64) as usize;
-    //     let rest = offset % itemsize as u64;
-    //     let s_pos = (rest / inner.quantum as u64) as usize;
-    //     let q_pos = (rest % inner.quantum as u64) as usize;
-
-    //     // Follow the list up to the right position (allocating as we go)
-    //     let dptr = inner.follow(item)?;
-
-    //     // Allocate the qset array if needed
-    //     if dptr.data.is_none() {
-    //         let mut qset_vec = KVec::new();
-    //         while qset_vec.len() < inner.qset {
-    //             qset_vec.push(None, GFP_KERNEL)?;
-    //         }
-    //         dptr.data = Some(qset_vec);
-    //     }
-    //     let data_array = dptr.data.as_mut().unwrap();
-
-    //     // Allocate the quantum if needed
-    //     if data_array[s_pos].is_none() {
-    //         let mut quantum_vec = KVec::new();
-    //         quantum_vec.resize(inner.quantum, 0, GFP_KERNEL)?;
-    //         data_array[s_pos] = Some(quantum_vec);
-    //     }
-    //     let quantum_buf = data_array[s_pos].as_mut().unwrap();
-
-    //     // Write only up to the end of this quantum
-    //     let mut write_count = count;
-    //     if write_count > inner.quantum - q_pos {
-    //         write_count = inner.quantum - q_pos;
-    //     }
-
-    //     let slice_to_write = &mut quantum_buf[q_pos..q_pos + write_count];
-
-    //     // Copy data from user space
-    //     iov.copy_from_iter(slice_to_write);
-
-    //     let new_offset = offset + write_count as u64;
-    //     if inner.size < new_offset {
-    //         inner.size = new_offset;
-    //     }
-
-    //     Ok(write_count)
-    // }
+    #[test]
+    fn protected_range() -> Result {
+        let dev = test_dev()?;
+        assert_eq!(write_at(&dev, &mut 0, b"0123456789"), Ok(10));
+        dev.data.lock().protected.insert(3, 7)?;
 
-    fn ioctl(
-        device: ArcBorrow<'_, Mutex<ScullDevData>>,
-        _file: &File,
-        cmd: u32,
-        arg: usize,
-    ) -> Result<isize> {
-        pr_debug!("rust_scull: ioctl() cmd={}, arg={}\n", cmd, arg);
+        // Writes next to the range go through
+        assert_eq!(write_at(&dev, &mut 0, b"abc"), Ok(3));
+        assert_eq!(write_at(&dev, &mut 7, b"hij"), Ok(3));
+        // Any overlap fails the whole write
+        assert_eq!(write_at(&dev, &mut 2, b"xy"), Err(EPERM));
+        assert_eq!(write_at(&dev, &mut 6, b"xy"), Err(EPERM));
+        assert_eq!(write_at(&dev, &mut 0, b"xxxxxxxxxx"), Err(EPERM));
 
-        // Basic ioctl handling
-        // For a full implementation, you would need to define ioctl commands
-        // using kernel::ioctl macros
+        let mut buf = [0; 10];
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(10));
+        assert_eq!(&buf, b"abc3456hij");
 
-        match cmd {
-            // Example: Reset device
-            0 => {
-                let mut inner = device.lock();
-                inner.trim();
-                Ok(0)
-            }
-            _ => Err(ENOTTY),
+        dev.data.lock().protected.remove(3, 7)?;
+        assert_eq!(write_at(&dev, &mut 3, b"defg"), Ok(4));
+        assert_eq!(read_at(&dev, 0, &mut buf), Ok(10));
+        assert_eq!(&buf, b"abcdefghij");
+        dev.data.lock().trim();
+        Ok(())
+    }
+
+    #[test]
+    fn uid_quota() -> Result {
+        let dev = test_dev()?;
+        let uid = current_uid();
+        let quantum = dev.data.lock().quantum as u64;
+        let usage = || {
+            let quota = UID_QUOTA.lock();
+            quota.usage.iter().find(|&&(u, _)| u == uid).map_or(0, |&(_, bytes)| bytes)
+        };
+
+        // Leave room for exactly one more quantum
+        let used = usage();
+        let saved = core::mem::replace(&mut UID_QUOTA.lock().limit, used + quantum);
+        let first = write_at(&dev, &mut 0, b"a");
+        // The second quantum is over the limit
+        let second = write_at(&dev, &mut { quantum }, b"b");
+        let charged = usage();
+        // Emptying the device credits the quantum back
+        dev.data.lock().trim();
+        let credited = usage();
+        let third = write_at(&dev, &mut { quantum }, b"c");
+        dev.data.lock().trim();
+        UID_QUOTA.lock().limit = saved;
+
+        assert_eq!(first, Ok(1));
+        assert_eq!(second.map_err(Error::to_errno), Err(-(bindings::EDQUOT as i32)));
+        assert_eq!(charged, used + quantum);
+        assert_eq!(credited, used);
+        assert_eq!(third, Ok(1));
+        assert_eq!(usage(), used);
+        Ok(())
+    }
+
+    #[test]
+    fn torture_record_check() -> Result {
+        // What `TortureDev::check` finds wrong, if anything
+        fn fault(data: &ScullDevData, index: usize, pos: u64, expected: u64) -> Option<&str> {
+            TortureDev::check(data, index, pos, expected)
+                .err()
+                .map(|(what, _, _)| what)
         }
+
+        let dev = test_dev()?;
+        let mut data = dev.data.lock();
+        let rec = TortureDev::record(3, 7);
+        let pos = (3 * SCULL_TORTURE_RECORD) as u64;
+
+        // Nothing written yet reads as a hole
+        assert_eq!(fault(&data, 3, pos, 0), None);
+        data.store(pos, &rec, 0)?;
+        assert_eq!(fault(&data, 3, pos, 7), None);
+        assert_eq!(fault(&data, 3, pos, 8), Some("wrong version"));
+        assert_eq!(fault(&data, 4, pos, 7), Some("misplaced record"));
+        assert_eq!(fault(&data, 2, 0, 1), Some("record missing"));
+
+        // A flipped payload byte fails the checksum
+        data.store(pos + 30, &[rec[30] ^ 1], 0)?;
+        assert_eq!(fault(&data, 3, pos, 7), Some("bad checksum"));
+        data.trim();
+        Ok(())
     }
 }
 
-// --- Module Implementation ---
+#[kernel::macros::kunit_tests(rust_scull_ioctl)]
+mod ioctl_tests {
+    use super::tests::{read_at, test_dev, write_at};
+    use super::*;
+    use kernel::ioctl::{_IOC_DIR, _IOC_NR, _IOC_SIZE, _IOC_TYPE};
 
-struct ScullModule {
-    _dev: Pin<KBox<MiscDeviceRegistration<RustScull>>>,
-}
+    /// Every command number, across all the device types, except the
+    /// optional ones in `OPTIONAL_COMMANDS` and the debug ones in
+    /// `DEBUG_COMMANDS`.
+    const COMMANDS: [u32; 58] = [
+        SCULL_IOCRESET,
+        SCULL_IOCSWRITEONCE,
+        SCULL_IOCGWRITEONCE,
+        SCULL_IOCSUIDQUOTA,
+        SCULL_IOCPROTECT,
+        SCULL_IOCUNPROTECT,
+        SCULL_IOCSMAXNODES,
+        SCULL_IOCPEEK,
+        SCULL_IOCSFILL,
+        SCULL_IOCGFILL,
+        SCULL_IOCSCHUNKED,
+        SCULL_IOCSAPPENDONLY,
+        SCULL_IOCGAPPENDONLY,
+        SCULL_IOCGSTATS,
+        SCULL_IOCDROPCACHE,
+        SCULL_IOCSMODE,
+        SCULL_IOCGMODE,
+        SCULL_P_IOCSDEADLINE,
+        SCULL_IOCLOCK,
+        SCULL_IOCUNLOCK,
+        SCULL_IOCSSENTINEL,
+        SCULL_IOCGSENTINEL,
+        SCULL_IOCEXTEND,
+        SCULL_IOCGLASTWRITER,
+        SCULL_IOCSALIGN,
+        SCULL_IOCGALIGN,
+        SCULL_IOCCOMPACT,
+        SCULL_IOCGFOOTPRINT,
+        SCULL_P_IOCSNORESTART,
+        SCULL_IOCGGEN,
+        SCULL_IOCREADEX,
+        SCULL_IOCSGEOMETRY,
+        SCULL_IOCGGEOMETRY,
+        SCULL_IOCREQUANTUM,
+        SCULL_P_IOCWAITPATTERN,
+        SCULL_IOCGMAXNODES,
+        SCULL_IOCGNODES,
+        SCULL_IOCSGFP,
+        SCULL_IOCGGFP,
+        SCULL_IOCGSIZES,
+        SCULL_C_IOCSMEMBERS,
+        SCULL_IOCGFILLSTATS,
+        SCULL_IOCSRECORD,
+        SCULL_IOCGRECORD,
+        SCULL_IOCSHUTDOWN,
+        SCULL_IOCCHECK,
+        SCULL_IOCGCREATED,
+        SCULL_IOCSMAXGAP,
+        SCULL_IOCGMAXGAP,
+        SCULL_IOCLIST,
+        SCULL_IOCGRUNNINGCRC,
+        SCULL_IOCDISCARDFRONT,
+        SCULL_IOCSMAXOPENS,
+        SCULL_IOCGMAXOPENS,
+        SCULL_IOCSLABEL,
+        SCULL_IOCGLABEL,
+        SCULL_IOCGVERSION,
+        SCULL_IOCGBACKEND,
+    ];
 
-impl kernel::Module for ScullModule {
-    fn init(_module: &'static ThisModule) -> Result<Self> {
-        pr_info!("rust_scull: Initializing module.\n");
+    /// The commands a `SCULL_MINIMAL=y` build leaves out.
+    const OPTIONAL_COMMANDS: [u32; 9] = [
+        SCULL_IOCCOMMIT,
+        SCULL_IOCROLLBACK,
+        SCULL_IOCGETLOG,
+        SCULL_IOCCLEARLOG,
+        SCULL_IOCSLOGGING,
+        SCULL_IOCFORK,
+        SCULL_IOCMIGRATE,
+        SCULL_IOCGMIGRATE,
+        SCULL_IOCFIEMAP,
+    ];
+
+    #[cfg(CONFIG_DEBUG_KERNEL)]
+    const DEBUG_COMMANDS: [u32; 2] = [SCULL_IOCINJECT, SCULL_IOCINJECTCOPY];
+    #[cfg(not(CONFIG_DEBUG_KERNEL))]
+    const DEBUG_COMMANDS: [u32; 0] = [];
+
+    /// Commands that carry a struct, with the size of the struct.
+    const SIZED: [(u32, usize); 28] = [
+        (SCULL_IOCPROTECT, size_of::<ScullRange>()),
+        (SCULL_IOCUNPROTECT, size_of::<ScullRange>()),
+        (SCULL_IOCPEEK, size_of::<ScullPeek>()),
+        (SCULL_IOCGSTATS, SCULL_STATS_SIZE_VER0),
+        (SCULL_IOCGETLOG, size_of::<ScullLogReq>()),
+        (SCULL_IOCLOCK, size_of::<ScullLockReq>()),
+        (SCULL_IOCUNLOCK, size_of::<ScullLockReq>()),
+        (SCULL_IOCFIEMAP, size_of::<ScullFiemap>()),
+        (SCULL_IOCGLASTWRITER, size_of::<ScullWriter>()),
+        (SCULL_IOCGFOOTPRINT, size_of::<u64>()),
+        (SCULL_IOCGGEN, size_of::<u64>()),
+        (SCULL_IOCREADEX, size_of::<ScullReadEx>()),
+        (SCULL_IOCSGEOMETRY, size_of::<ScullGeometry>()),
+        (SCULL_IOCGGEOMETRY, size_of::<ScullGeometry>()),
+        (SCULL_IOCREQUANTUM, size_of::<ScullGeometry>()),
+        (SCULL_P_IOCWAITPATTERN, size_of::<ScullPattern>()),
+        (SCULL_IOCGSIZES, size_of::<ScullSizes>()),
+        (SCULL_C_IOCSMEMBERS, size_of::<ScullConcatMembers>()),
+        (SCULL_IOCGFILLSTATS, SCULL_FILL_STATS_SIZE_VER0),
+        (SCULL_IOCCHECK, size_of::<ScullCheck>()),
+        (SCULL_IOCGCREATED, size_of::<u64>()),
+        (SCULL_IOCSMAXGAP, size_of::<u64>()),
+        (SCULL_IOCGMAXGAP, size_of::<u64>()),
+        (SCULL_IOCLIST, size_of::<ScullListReq>()),
+        (SCULL_IOCGRUNNINGCRC, size_of::<u64>()),
+        (SCULL_IOCSLABEL, SCULL_LABEL_LEN),
+        (SCULL_IOCGLABEL, SCULL_LABEL_LEN),
+        (SCULL_IOCGVERSION, size_of::<ScullVersion>()),
+    ];
+
+    fn test_file(limit: u64) -> Result<ScullFile> {
+        Ok(ScullFile {
+            dev: test_dev()?,
+            index: 0,
+            base: 0,
+            limit,
+        })
+    }
 
-        let options = MiscDeviceOptions {
-            name: kernel::c_str!("scull"),
+    /// Returns every command this build implements.
+    fn built_commands() -> impl Iterator<Item = u32> {
+        let optional = if cfg!(scull_minimal) {
+            &[][..]
+        } else {
+            &OPTIONAL_COMMANDS[..]
         };
-        
-        let dev = KBox::pin_init(MiscDeviceRegistration::register(options), GFP_KERNEL)?;
+        COMMANDS
+            .into_iter()
+            .chain(optional.iter().copied())
+            .chain(DEBUG_COMMANDS)
+    }
+
+    /// Moves `cmd` to another ioctl type, keeping its number and size.
+    fn other_magic(cmd: u32) -> u32 {
+        (cmd & !(0xff << 8)) | ((b'x' as u32) << 8)
+    }
+
+    #[test]
+    fn command_numbers() {
+        // A minimal build keeps the optional numbers reserved
+        let mut seen = 0u128;
+        for cmd in COMMANDS.into_iter().chain(OPTIONAL_COMMANDS).chain(DEBUG_COMMANDS) {
+            assert_eq!(_IOC_TYPE(cmd), SCULL_IOC_MAGIC);
+            let bit = 1u128 << _IOC_NR(cmd);
+            assert_eq!(seen & bit, 0);
+            seen |= bit;
+        }
+    }
+
+    #[test]
+    fn command_sizes() {
+        for (cmd, size) in SIZED {
+            assert_eq!(_IOC_SIZE(cmd) as usize, size);
+        }
+        // The sized structs encode their first version and are read back
+        assert_eq!(_IOC_DIR(SCULL_IOCGSTATS), _IOC_DIR(_IOWR::<u8>(0, 0)));
+        assert_eq!(_IOC_DIR(SCULL_IOCGFILLSTATS), _IOC_DIR(_IOWR::<u8>(0, 0)));
+    }
+
+    #[test]
+    fn compat_layout() {
+        // 32-bit callers reach us through `compat_ptr_ioctl`, which passes
+        // the argument through untranslated. The structs hold only
+        // fixed-width fields, padded by hand to a multiple of 8 bytes so that
+        // i386's 4-byte alignment of `u64` lays them out the same.
+        for (_, size) in SIZED {
+            assert_eq!(size % size_of::<u64>(), 0);
+        }
+        assert_eq!(size_of::<ScullStats>() % size_of::<u64>(), 0);
+        assert_eq!(size_of::<ScullFillStats>() % size_of::<u64>(), 0);
+    }
+
+    #[test]
+    fn wrong_magic() -> Result {
+        let file = test_file(u64::MAX)?;
+        for cmd in built_commands() {
+            assert_eq!(file.dev.ioctl(&file, other_magic(cmd), 0), Err(ENOTTY));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_size() -> Result {
+        let file = test_file(u64::MAX)?;
+        for (cmd, size) in SIZED {
+            let bigger = cmd + (8 << 16);
+            assert_eq!(_IOC_SIZE(bigger) as usize, size + 8);
+            assert_eq!(file.dev.ioctl(&file, bigger, 0), Err(ENOTTY));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bad_user_pointer() -> Result {
+        let file = test_file(u64::MAX)?;
+        assert_eq!(file.dev.ioctl(&file, SCULL_IOCSGEOMETRY, 0), Err(EFAULT));
+        assert_eq!(file.dev.ioctl(&file, SCULL_IOCGGEOMETRY, 0), Err(EFAULT));
+        assert_eq!(file.dev.ioctl(&file, SCULL_IOCGSTATS, 0), Err(EFAULT));
+        Ok(())
+    }
+
+    #[test]
+    fn bad_arguments() -> Result {
+        let file = test_file(u64::MAX)?;
+        let dev = &file.dev;
+        assert_eq!(dev.ioctl(&file, SCULL_IOCSFILL, 256), Err(EINVAL));
+        assert_eq!(dev.ioctl(&file, SCULL_IOCSSENTINEL, 256), Err(EINVAL));
+        assert_eq!(dev.ioctl(&file, SCULL_IOCSGFP, usize::MAX), Err(EINVAL));
+        assert_eq!(dev.ioctl(&file, SCULL_IOCSALIGN, 3), Err(EINVAL));
+        assert_eq!(dev.ioctl(&file, SCULL_IOCSRECORD, usize::MAX), Err(EINVAL));
+        assert_eq!(dev.ioctl(&file, SCULL_IOCSMODE, 1 << 8), Err(EINVAL));
+        #[cfg(not(scull_minimal))]
+        assert_eq!(dev.ioctl(&file, SCULL_IOCFORK, SCULL_FORK_FLAT + 1), Err(EINVAL));
+
+        assert_eq!(dev.ioctl(&file, SCULL_IOCSALIGN, 8), Ok(0));
+        assert_eq!(dev.ioctl(&file, SCULL_IOCGALIGN, 0), Ok(8));
+        Ok(())
+    }
 
-        pr_info!("rust_scull: Module initialized. Device: /dev/scull\n");
+    #[test]
+    fn setters_need_sys_admin() -> Result {
+        let file = test_file(u64::MAX)?;
+        let dev = &file.dev;
 
-        Ok(ScullModule { _dev: dev })
+        DENY_SYS_ADMIN.store(true, Ordering::Relaxed);
+        // Enabling write-once needs no privilege, only clearing it does
+        let enable = dev.ioctl(&file, SCULL_IOCSWRITEONCE, 1);
+        let results = [
+            dev.ioctl(&file, SCULL_IOCSMAXNODES, 1),
+            dev.ioctl(&file, SCULL_IOCSUIDQUOTA, 1),
+            dev.ioctl(&file, SCULL_IOCSMODE, 0),
+            dev.ioctl(&file, SCULL_IOCSHUTDOWN, 0),
+            dev.ioctl(&file, SCULL_IOCSWRITEONCE, 0),
+            dev.ioctl(&file, SCULL_IOCUNPROTECT, 0),
+        ];
+        DENY_SYS_ADMIN.store(false, Ordering::Relaxed);
+
+        assert_eq!(enable, Ok(0));
+        for result in results {
+            assert_eq!(result, Err(EPERM));
+        }
+        // Nothing changed
+        assert_eq!(dev.ioctl(&file, SCULL_IOCGWRITEONCE, 0), Ok(1));
+        assert_eq!(
+            dev.ioctl(&file, SCULL_IOCGMODE, 0),
+            Ok((SCULL_MODE_READ | SCULL_MODE_WRITE) as isize)
+        );
+        assert!(dev.check_live().is_ok());
+
+        assert_eq!(dev.ioctl(&file, SCULL_IOCSWRITEONCE, 0), Ok(0));
+        Ok(())
+    }
+
+    #[test]
+    fn generation() -> Result {
+        let file = test_file(u64::MAX)?;
+        let dev = &file.dev;
+        let generation = || dev.generation.load(Ordering::Relaxed);
+
+        let start = generation();
+        assert_eq!(write_at(dev, &mut 0, b"abc"), Ok(3));
+        let written = generation();
+        assert!(written > start);
+        // Reading changes nothing
+        assert_eq!(read_at(dev, 0, &mut [0; 3]), Ok(3));
+        assert_eq!(generation(), written);
+        assert_eq!(dev.ioctl(&file, SCULL_IOCRESET, 0), Ok(0));
+        assert!(generation() > written);
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_mode() -> Result {
+        let file = test_file(u64::MAX)?;
+        let dev = &file.dev;
+        assert_eq!(dev.ioctl(&file, SCULL_IOCSMODE, SCULL_MODE_READ), Ok(0));
+
+        assert_eq!(dev.ioctl(&file, SCULL_IOCRESET, 0), Err(EACCES));
+        assert_eq!(dev.ioctl(&file, SCULL_IOCEXTEND, 1), Err(EACCES));
+        assert_eq!(dev.ioctl(&file, SCULL_IOCDISCARDFRONT, 1), Err(EACCES));
+        #[cfg(not(scull_minimal))]
+        assert_eq!(dev.ioctl(&file, SCULL_IOCMIGRATE, 8), Err(EACCES));
+        assert!(dev.data.lock().check_not_migrating().is_ok());
+        Ok(())
+    }
+
+    /// Only built, and so only run, by a `SCULL_MINIMAL=y` module.
+    #[cfg(scull_minimal)]
+    #[test]
+    fn minimal_build() -> Result {
+        let file = test_file(u64::MAX)?;
+        for cmd in OPTIONAL_COMMANDS {
+            assert_eq!(file.dev.ioctl(&file, cmd, 0), Err(ENOTTY));
+        }
+        let optional = SCULL_FEAT_VERSIONS
+            | SCULL_FEAT_FORK
+            | SCULL_FEAT_MIGRATE
+            | SCULL_FEAT_LOG
+            | SCULL_FEAT_FIEMAP;
+        assert_eq!(ScullVersion::get().features & optional, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn partition_commands() -> Result {
+        let file = test_file(100)?;
+        let dev = &file.dev;
+        assert_eq!(dev.ioctl(&file, SCULL_IOCRESET, 0), Err(ENOTTY));
+        assert_eq!(dev.ioctl(&file, SCULL_IOCSMAXNODES, 1), Err(ENOTTY));
+        assert_eq!(dev.ioctl(&file, SCULL_IOCDISCARDFRONT, 1), Err(ENOTTY));
+        assert!(dev.ioctl(&file, SCULL_IOCGMAXNODES, 0).is_ok());
+        // A partition cannot reach the bare reset number either
+        assert_eq!(dev.ioctl(&file, SCULL_IOCRESET_LEGACY, 0), Err(ENOTTY));
+        Ok(())
+    }
+
+    #[test]
+    fn sized_struct_versions() {
+        let stats = size_of::<ScullStats>() as u32;
+        assert_eq!(sized_len::<ScullStats>(0), Err(EINVAL));
+        assert_eq!(sized_len::<ScullStats>(SCULL_STATS_SIZE_VER0 as u32 - 1), Err(EINVAL));
+        assert_eq!(
+            sized_len::<ScullStats>(SCULL_STATS_SIZE_VER0 as u32),
+            Ok(SCULL_STATS_SIZE_VER0)
+        );
+        assert_eq!(sized_len::<ScullStats>(stats), Ok(stats as usize));
+        assert_eq!(sized_len::<ScullStats>(stats + 8), Err(EINVAL));
+
+        let fill = size_of::<ScullFillStats>() as u32;
+        assert_eq!(
+            sized_len::<ScullFillStats>(SCULL_FILL_STATS_SIZE_VER0 as u32),
+            Ok(SCULL_FILL_STATS_SIZE_VER0)
+        );
+        assert_eq!(sized_len::<ScullFillStats>(fill), Ok(fill as usize));
+        assert_eq!(sized_len::<ScullFillStats>(fill + 1), Err(EINVAL));
+    }
+
+    #[test]
+    fn error_codes() {
+        let cases = [
+            (ScullError::QuantumZero, EFAULT),
+            (ScullError::TooManyNodes, EFBIG),
+            (ScullError::OffsetOverflow, EFBIG),
+            (ScullError::NoSpace, ENOSPC),
+            (ScullError::BadAddress, EFAULT),
+            (ScullError::Busy, EBUSY),
+            (ScullError::NotPrivileged, EPERM),
+            (ScullError::Protected, EPERM),
+            (ScullError::AccessDenied, EACCES),
+            (ScullError::InvalidArgument, EINVAL),
+            (ScullError::NotSupported, EOPNOTSUPP),
+            (ScullError::UnknownCommand, ENOTTY),
+        ];
+        for (err, errno) in cases {
+            assert_eq!(Error::from(err), errno);
+        }
+        assert_eq!(
+            Error::from(ScullError::QuotaExceeded).to_errno(),
+            -(bindings::EDQUOT as i32)
+        );
     }
 }
 
-impl Drop for ScullModule {
-    fn drop(&mut self) {
-        pr_info!("rust_scull: Module cleanup complete.\n");
+#[kernel::macros::kunit_tests(rust_scull_uapi)]
+mod uapi_tests {
+    use super::*;
+    use core::mem::offset_of;
+
+    #[allow(non_upper_case_globals)]
+    extern "C" {
+        /// The values `include/uapi/scull.h` defines, see `scull_uapi.c`.
+        static scull_uapi_values: [u64; 0];
+        static scull_uapi_count: u32;
     }
-}
\ No newline at end of file
+
+    /// The driver's values, in the order `scull_uapi.c` lists the header's.
+    const DRIVER: &[u64] = &[
+        // ioctl numbers
+        SCULL_IOCRESET as u64,
+        SCULL_IOCSWRITEONCE as u64,
+        SCULL_IOCGWRITEONCE as u64,
+        SCULL_IOCSUIDQUOTA as u64,
+        SCULL_IOCPROTECT as u64,
+        SCULL_IOCUNPROTECT as u64,
+        SCULL_IOCSMAXNODES as u64,
+        SCULL_IOCPEEK as u64,
+        SCULL_IOCSFILL as u64,
+        SCULL_IOCGFILL as u64,
+        SCULL_IOCSCHUNKED as u64,
+        SCULL_IOCSAPPENDONLY as u64,
+        SCULL_IOCGAPPENDONLY as u64,
+        SCULL_IOCGSTATS as u64,
+        SCULL_IOCCOMMIT as u64,
+        SCULL_IOCROLLBACK as u64,
+        SCULL_IOCDROPCACHE as u64,
+        SCULL_IOCGETLOG as u64,
+        SCULL_IOCCLEARLOG as u64,
+        SCULL_IOCSLOGGING as u64,
+        SCULL_IOCSMODE as u64,
+        SCULL_IOCGMODE as u64,
+        SCULL_IOCFORK as u64,
+        SCULL_IOCLOCK as u64,
+        SCULL_IOCUNLOCK as u64,
+        SCULL_IOCMIGRATE as u64,
+        SCULL_IOCGMIGRATE as u64,
+        SCULL_IOCSSENTINEL as u64,
+        SCULL_IOCGSENTINEL as u64,
+        SCULL_IOCFIEMAP as u64,
+        SCULL_IOCEXTEND as u64,
+        SCULL_IOCGLASTWRITER as u64,
+        SCULL_IOCSALIGN as u64,
+        SCULL_IOCGALIGN as u64,
+        SCULL_IOCCOMPACT as u64,
+        SCULL_IOCGFOOTPRINT as u64,
+        SCULL_IOCGGEN as u64,
+        SCULL_IOCREADEX as u64,
+        SCULL_IOCSGEOMETRY as u64,
+        SCULL_IOCGGEOMETRY as u64,
+        SCULL_IOCREQUANTUM as u64,
+        SCULL_IOCGMAXNODES as u64,
+        SCULL_IOCGNODES as u64,
+        SCULL_IOCSGFP as u64,
+        SCULL_IOCGGFP as u64,
+        SCULL_IOCGSIZES as u64,
+        SCULL_IOCGFILLSTATS as u64,
+        SCULL_IOCSRECORD as u64,
+        SCULL_IOCGRECORD as u64,
+        SCULL_IOCSHUTDOWN as u64,
+        SCULL_IOCCHECK as u64,
+        SCULL_IOCGCREATED as u64,
+        SCULL_IOCSMAXGAP as u64,
+        SCULL_IOCGMAXGAP as u64,
+        SCULL_IOCLIST as u64,
+        SCULL_IOCGRUNNINGCRC as u64,
+        SCULL_IOCDISCARDFRONT as u64,
+        SCULL_IOCSMAXOPENS as u64,
+        SCULL_IOCGMAXOPENS as u64,
+        SCULL_IOCSLABEL as u64,
+        SCULL_IOCGLABEL as u64,
+        SCULL_IOCGVERSION as u64,
+        SCULL_IOCGBACKEND as u64,
+        SCULL_P_IOCSDEADLINE as u64,
+        SCULL_P_IOCSNORESTART as u64,
+        SCULL_P_IOCWAITPATTERN as u64,
+        SCULL_C_IOCSMEMBERS as u64,
+        // Other constants
+        SCULL_PATTERN_MAX as u64,
+        SCULL_LABEL_LEN as u64,
+        SCULL_STATS_SIZE_VER0 as u64,
+        SCULL_LOCK_EXCLUSIVE as u64,
+        SCULL_LOCK_NONBLOCK as u64,
+        SCULL_LOG_READ as u64,
+        SCULL_LOG_WRITE as u64,
+        SCULL_EXTENT_LAST as u64,
+        SCULL_EVENT_OVERFLOW as u64,
+        SCULL_GEOMETRY_FORCE as u64,
+        SCULL_CHECK_NODE_COUNT as u64,
+        SCULL_CHECK_QSET_LEN as u64,
+        SCULL_CHECK_QUANTUM_LEN as u64,
+        SCULL_CHECK_DIRTY_LEN as u64,
+        SCULL_CHECK_DIRTY_PAST_SIZE as u64,
+        SCULL_CHECK_SIZE as u64,
+        SCULL_CHECK_PROTECTED as u64,
+        SCULL_CHECK_LOCKS as u64,
+        SCULL_FILL_STATS_SIZE_VER0 as u64,
+        SCULL_VERSION_MAJOR as u64,
+        SCULL_VERSION_MINOR as u64,
+        SCULL_VERSION_PATCH as u64,
+        SCULL_FEAT_VERSIONS as u64,
+        SCULL_FEAT_FORK as u64,
+        SCULL_FEAT_MIGRATE as u64,
+        SCULL_FEAT_LOG as u64,
+        SCULL_FEAT_FIEMAP as u64,
+        SCULL_FEAT_INJECT as u64,
+        SCULL_FEAT_POISON as u64,
+        SCULL_FEAT_PARANOID as u64,
+        SCULL_FEAT_PARTITIONS as u64,
+        SCULL_FEAT_COMPACT as u64,
+        SCULL_RESET_FORCE as u64,
+        SCULL_MODE_READ as u64,
+        SCULL_MODE_WRITE as u64,
+        SCULL_SENTINEL_NONE as u64,
+        SCULL_GFP_KERNEL as u64,
+        SCULL_GFP_ATOMIC as u64,
+        SCULL_GAP_UNLIMITED as u64,
+        SCULL_BACKEND_QSET as u64,
+        SCULL_BACKEND_FLAT as u64,
+        SCULL_FORK_SAME as u64,
+        SCULL_FORK_QSET as u64,
+        SCULL_FORK_FLAT as u64,
+        // Struct sizes and field offsets
+        size_of::<ScullRange>() as u64,
+        offset_of!(ScullRange, len) as u64,
+        size_of::<ScullStats>() as u64,
+        offset_of!(ScullStats, reserved) as u64,
+        offset_of!(ScullStats, bytes_read) as u64,
+        offset_of!(ScullStats, bytes_written) as u64,
+        offset_of!(ScullStats, open_count) as u64,
+        size_of::<ScullWriter>() as u64,
+        offset_of!(ScullWriter, uid) as u64,
+        size_of::<ScullLockReq>() as u64,
+        offset_of!(ScullLockReq, len) as u64,
+        offset_of!(ScullLockReq, flags) as u64,
+        offset_of!(ScullLockReq, reserved) as u64,
+        size_of::<ScullLogEntry>() as u64,
+        offset_of!(ScullLogEntry, offset) as u64,
+        offset_of!(ScullLogEntry, len) as u64,
+        offset_of!(ScullLogEntry, result) as u64,
+        offset_of!(ScullLogEntry, pid) as u64,
+        offset_of!(ScullLogEntry, op) as u64,
+        size_of::<ScullLogReq>() as u64,
+        offset_of!(ScullLogReq, count) as u64,
+        size_of::<ScullListReq>() as u64,
+        offset_of!(ScullListReq, count) as u64,
+        size_of::<ScullDevInfo>() as u64,
+        offset_of!(ScullDevInfo, size) as u64,
+        offset_of!(ScullDevInfo, quantum) as u64,
+        offset_of!(ScullDevInfo, qset) as u64,
+        size_of::<ScullExtent>() as u64,
+        offset_of!(ScullExtent, length) as u64,
+        offset_of!(ScullExtent, flags) as u64,
+        offset_of!(ScullExtent, reserved) as u64,
+        size_of::<ScullFiemap>() as u64,
+        offset_of!(ScullFiemap, buf) as u64,
+        offset_of!(ScullFiemap, count) as u64,
+        offset_of!(ScullFiemap, mapped) as u64,
+        offset_of!(ScullFiemap, next) as u64,
+        size_of::<ScullEvent>() as u64,
+        offset_of!(ScullEvent, len) as u64,
+        offset_of!(ScullEvent, generation) as u64,
+        offset_of!(ScullEvent, timestamp_ns) as u64,
+        offset_of!(ScullEvent, pid) as u64,
+        offset_of!(ScullEvent, flags) as u64,
+        size_of::<ScullPeek>() as u64,
+        offset_of!(ScullPeek, buf) as u64,
+        offset_of!(ScullPeek, len) as u64,
+        size_of::<ScullGeometry>() as u64,
+        offset_of!(ScullGeometry, qset) as u64,
+        offset_of!(ScullGeometry, flags) as u64,
+        offset_of!(ScullGeometry, reserved) as u64,
+        size_of::<ScullPattern>() as u64,
+        offset_of!(ScullPattern, len) as u64,
+        offset_of!(ScullPattern, reserved) as u64,
+        size_of::<ScullReadEx>() as u64,
+        offset_of!(ScullReadEx, buf) as u64,
+        offset_of!(ScullReadEx, len) as u64,
+        offset_of!(ScullReadEx, copied) as u64,
+        offset_of!(ScullReadEx, more) as u64,
+        offset_of!(ScullReadEx, reserved) as u64,
+        size_of::<ScullSizes>() as u64,
+        offset_of!(ScullSizes, qset_entry) as u64,
+        offset_of!(ScullSizes, dirty_word) as u64,
+        offset_of!(ScullSizes, dev_data) as u64,
+        size_of::<ScullCheck>() as u64,
+        offset_of!(ScullCheck, item) as u64,
+        offset_of!(ScullCheck, s_pos) as u64,
+        offset_of!(ScullCheck, code) as u64,
+        offset_of!(ScullCheck, reserved) as u64,
+        size_of::<ScullFillStats>() as u64,
+        offset_of!(ScullFillStats, reserved) as u64,
+        offset_of!(ScullFillStats, quanta) as u64,
+        offset_of!(ScullFillStats, full) as u64,
+        offset_of!(ScullFillStats, partial) as u64,
+        offset_of!(ScullFillStats, valid_bytes) as u64,
+        offset_of!(ScullFillStats, padding_bytes) as u64,
+        size_of::<ScullConcatMembers>() as u64,
+        offset_of!(ScullConcatMembers, second) as u64,
+        size_of::<ScullVersion>() as u64,
+        offset_of!(ScullVersion, minor) as u64,
+        offset_of!(ScullVersion, patch) as u64,
+        offset_of!(ScullVersion, reserved) as u64,
+        offset_of!(ScullVersion, features) as u64,
+    ];
+
+    #[cfg(CONFIG_DEBUG_KERNEL)]
+    const DEBUG: &[u64] = &[SCULL_IOCINJECT as u64, SCULL_IOCINJECTCOPY as u64];
+    #[cfg(not(CONFIG_DEBUG_KERNEL))]
+    const DEBUG: &[u64] = &[];
+
+    #[test]
+    fn header_matches_driver() {
+        // SAFETY: `scull_uapi.c` defines `scull_uapi_values` with
+        // `scull_uapi_count` entries, and neither is ever written.
+        let header = unsafe {
+            core::slice::from_raw_parts(
+                core::ptr::addr_of!(scull_uapi_values).cast::<u64>(),
+                scull_uapi_count as usize,
+            )
+        };
+
+        assert_eq!(header.len(), DRIVER.len() + DEBUG.len());
+        for (i, (&c, &rust)) in header.iter().zip(DRIVER.iter().chain(DEBUG)).enumerate() {
+            // The index locates the entry in both lists
+            assert_eq!((i, c), (i, rust));
+        }
+    }
+}
@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * The values include/uapi/scull.h defines, in the order the rust_scull_uapi
+ * KUnit suite in scull.rs lists the driver's own. Built only with KUnit.
+ */
+
+#include <linux/kernel.h>
+#include <linux/stddef.h>
+
+#include "scull.h"
+
+const u64 scull_uapi_values[] = {
+	/* ioctl numbers */
+	SCULL_IOCRESET,
+	SCULL_IOCSWRITEONCE,
+	SCULL_IOCGWRITEONCE,
+	SCULL_IOCSUIDQUOTA,
+	SCULL_IOCPROTECT,
+	SCULL_IOCUNPROTECT,
+	SCULL_IOCSMAXNODES,
+	SCULL_IOCPEEK,
+	SCULL_IOCSFILL,
+	SCULL_IOCGFILL,
+	SCULL_IOCSCHUNKED,
+	SCULL_IOCSAPPENDONLY,
+	SCULL_IOCGAPPENDONLY,
+	SCULL_IOCGSTATS,
+	SCULL_IOCCOMMIT,
+	SCULL_IOCROLLBACK,
+	SCULL_IOCDROPCACHE,
+	SCULL_IOCGETLOG,
+	SCULL_IOCCLEARLOG,
+	SCULL_IOCSLOGGING,
+	SCULL_IOCSMODE,
+	SCULL_IOCGMODE,
+	SCULL_IOCFORK,
+	SCULL_IOCLOCK,
+	SCULL_IOCUNLOCK,
+	SCULL_IOCMIGRATE,
+	SCULL_IOCGMIGRATE,
+	SCULL_IOCSSENTINEL,
+	SCULL_IOCGSENTINEL,
+	SCULL_IOCFIEMAP,
+	SCULL_IOCEXTEND,
+	SCULL_IOCGLASTWRITER,
+	SCULL_IOCSALIGN,
+	SCULL_IOCGALIGN,
+	SCULL_IOCCOMPACT,
+	SCULL_IOCGFOOTPRINT,
+	SCULL_IOCGGEN,
+	SCULL_IOCREADEX,
+	SCULL_IOCSGEOMETRY,
+	SCULL_IOCGGEOMETRY,
+	SCULL_IOCREQUANTUM,
+	SCULL_IOCGMAXNODES,
+	SCULL_IOCGNODES,
+	SCULL_IOCSGFP,
+	SCULL_IOCGGFP,
+	SCULL_IOCGSIZES,
+	SCULL_IOCGFILLSTATS,
+	SCULL_IOCSRECORD,
+	SCULL_IOCGRECORD,
+	SCULL_IOCSHUTDOWN,
+	SCULL_IOCCHECK,
+	SCULL_IOCGCREATED,
+	SCULL_IOCSMAXGAP,
+	SCULL_IOCGMAXGAP,
+	SCULL_IOCLIST,
+	SCULL_IOCGRUNNINGCRC,
+	SCULL_IOCDISCARDFRONT,
+	SCULL_IOCSMAXOPENS,
+	SCULL_IOCGMAXOPENS,
+	SCULL_IOCSLABEL,
+	SCULL_IOCGLABEL,
+	SCULL_IOCGVERSION,
+	SCULL_IOCGBACKEND,
+	SCULL_P_IOCSDEADLINE,
+	SCULL_P_IOCSNORESTART,
+	SCULL_P_IOCWAITPATTERN,
+	SCULL_C_IOCSMEMBERS,
+	/* other constants */
+	SCULL_PATTERN_MAX,
+	SCULL_LABEL_LEN,
+	SCULL_STATS_SIZE_VER0,
+	SCULL_LOCK_EXCLUSIVE,
+	SCULL_LOCK_NONBLOCK,
+	SCULL_LOG_READ,
+	SCULL_LOG_WRITE,
+	SCULL_EXTENT_LAST,
+	SCULL_EVENT_OVERFLOW,
+	SCULL_GEOMETRY_FORCE,
+	SCULL_CHECK_NODE_COUNT,
+	SCULL_CHECK_QSET_LEN,
+	SCULL_CHECK_QUANTUM_LEN,
+	SCULL_CHECK_DIRTY_LEN,
+	SCULL_CHECK_DIRTY_PAST_SIZE,
+	SCULL_CHECK_SIZE,
+	SCULL_CHECK_PROTECTED,
+	SCULL_CHECK_LOCKS,
+	SCULL_FILL_STATS_SIZE_VER0,
+	SCULL_VERSION_MAJOR,
+	SCULL_VERSION_MINOR,
+	SCULL_VERSION_PATCH,
+	SCULL_FEAT_VERSIONS,
+	SCULL_FEAT_FORK,
+	SCULL_FEAT_MIGRATE,
+	SCULL_FEAT_LOG,
+	SCULL_FEAT_FIEMAP,
+	SCULL_FEAT_INJECT,
+	SCULL_FEAT_POISON,
+	SCULL_FEAT_PARANOID,
+	SCULL_FEAT_PARTITIONS,
+	SCULL_FEAT_COMPACT,
+	SCULL_RESET_FORCE,
+	SCULL_MODE_READ,
+	SCULL_MODE_WRITE,
+	SCULL_SENTINEL_NONE,
+	SCULL_GFP_KERNEL,
+	SCULL_GFP_ATOMIC,
+	SCULL_GAP_UNLIMITED,
+	SCULL_BACKEND_QSET,
+	SCULL_BACKEND_FLAT,
+	SCULL_FORK_SAME,
+	SCULL_FORK_QSET,
+	SCULL_FORK_FLAT,
+	/* struct sizes and field offsets */
+	sizeof(struct scull_range),
+	offsetof(struct scull_range, len),
+	sizeof(struct scull_stats),
+	offsetof(struct scull_stats, reserved),
+	offsetof(struct scull_stats, bytes_read),
+	offsetof(struct scull_stats, bytes_written),
+	offsetof(struct scull_stats, open_count),
+	sizeof(struct scull_writer),
+	offsetof(struct scull_writer, uid),
+	sizeof(struct scull_lock_req),
+	offsetof(struct scull_lock_req, len),
+	offsetof(struct scull_lock_req, flags),
+	offsetof(struct scull_lock_req, reserved),
+	sizeof(struct scull_log_entry),
+	offsetof(struct scull_log_entry, offset),
+	offsetof(struct scull_log_entry, len),
+	offsetof(struct scull_log_entry, result),
+	offsetof(struct scull_log_entry, pid),
+	offsetof(struct scull_log_entry, op),
+	sizeof(struct scull_log_req),
+	offsetof(struct scull_log_req, count),
+	sizeof(struct scull_list_req),
+	offsetof(struct scull_list_req, count),
+	sizeof(struct scull_dev_info),
+	offsetof(struct scull_dev_info, size),
+	offsetof(struct scull_dev_info, quantum),
+	offsetof(struct scull_dev_info, qset),
+	sizeof(struct scull_extent),
+	offsetof(struct scull_extent, length),
+	offsetof(struct scull_extent, flags),
+	offsetof(struct scull_extent, reserved),
+	sizeof(struct scull_fiemap),
+	offsetof(struct scull_fiemap, buf),
+	offsetof(struct scull_fiemap, count),
+	offsetof(struct scull_fiemap, mapped),
+	offsetof(struct scull_fiemap, next),
+	sizeof(struct scull_event),
+	offsetof(struct scull_event, len),
+	offsetof(struct scull_event, generation),
+	offsetof(struct scull_event, timestamp_ns),
+	offsetof(struct scull_event, pid),
+	offsetof(struct scull_event, flags),
+	sizeof(struct scull_peek),
+	offsetof(struct scull_peek, buf),
+	offsetof(struct scull_peek, len),
+	sizeof(struct scull_geometry),
+	offsetof(struct scull_geometry, qset),
+	offsetof(struct scull_geometry, flags),
+	offsetof(struct scull_geometry, reserved),
+	sizeof(struct scull_pattern),
+	offsetof(struct scull_pattern, len),
+	offsetof(struct scull_pattern, reserved),
+	sizeof(struct scull_read_ex),
+	offsetof(struct scull_read_ex, buf),
+	offsetof(struct scull_read_ex, len),
+	offsetof(struct scull_read_ex, copied),
+	offsetof(struct scull_read_ex, more),
+	offsetof(struct scull_read_ex, reserved),
+	sizeof(struct scull_sizes),
+	offsetof(struct scull_sizes, qset_entry),
+	offsetof(struct scull_sizes, dirty_word),
+	offsetof(struct scull_sizes, dev_data),
+	sizeof(struct scull_check),
+	offsetof(struct scull_check, item),
+	offsetof(struct scull_check, s_pos),
+	offsetof(struct scull_check, code),
+	offsetof(struct scull_check, reserved),
+	sizeof(struct scull_fill_stats),
+	offsetof(struct scull_fill_stats, reserved),
+	offsetof(struct scull_fill_stats, quanta),
+	offsetof(struct scull_fill_stats, full),
+	offsetof(struct scull_fill_stats, partial),
+	offsetof(struct scull_fill_stats, valid_bytes),
+	offsetof(struct scull_fill_stats, padding_bytes),
+	sizeof(struct scull_concat_members),
+	offsetof(struct scull_concat_members, second),
+	sizeof(struct scull_version),
+	offsetof(struct scull_version, minor),
+	offsetof(struct scull_version, patch),
+	offsetof(struct scull_version, reserved),
+	offsetof(struct scull_version, features),
+#ifdef CONFIG_DEBUG_KERNEL
+	/* debug-only ioctl numbers */
+	SCULL_IOCINJECT,
+	SCULL_IOCINJECTCOPY,
+#endif
+};
+
+const u32 scull_uapi_count = ARRAY_SIZE(scull_uapi_values);
@@ -0,0 +1,966 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * sculltool - command line front end to the scull ioctls.
+ *
+ * Every ioctl in include/uapi/scull.h has a subcommand here, so this file
+ * doubles as an example of calling each one. `sculltool selfcheck` runs a
+ * smoke test against the loaded module.
+ *
+ * Usage: sculltool COMMAND [DEVICE] [ARGS...]; `sculltool help` lists the
+ * commands.
+ */
+
+#include <errno.h>
+#include <fcntl.h>
+#include <inttypes.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/ioctl.h>
+#include <time.h>
+#include <unistd.h>
+
+#include "scull.h"
+
+#define DEFAULT_DEVICE	"/dev/scull"
+#define EVENTS_DEVICE	"/dev/scull.events"
+
+#define ARRAY_SIZE(a) (sizeof(a) / sizeof((a)[0]))
+
+static const char *prog = "sculltool";
+
+static void die(const char *what)
+{
+	fprintf(stderr, "%s: %s: %s\n", prog, what, strerror(errno));
+	exit(1);
+}
+
+static void usage_error(const char *msg)
+{
+	fprintf(stderr, "%s: %s (try `%s help`)\n", prog, msg, prog);
+	exit(2);
+}
+
+static unsigned long long parse_num(const char *s)
+{
+	unsigned long long val;
+	char *end;
+
+	errno = 0;
+	val = strtoull(s, &end, 0);
+	if (errno || !*s || *end)
+		usage_error("expected a number");
+	return val;
+}
+
+static long xioctl(int fd, unsigned long cmd, unsigned long arg, const char *name)
+{
+	long ret = ioctl(fd, cmd, arg);
+
+	if (ret < 0)
+		die(name);
+	return ret;
+}
+
+/* Writes all of buf, failing on a short write. */
+static void write_all(int fd, const void *buf, size_t len, const char *what)
+{
+	const char *p = buf;
+
+	while (len > 0) {
+		ssize_t n = write(fd, p, len);
+
+		if (n < 0) {
+			if (errno == EINTR)
+				continue;
+			die(what);
+		}
+		if (n == 0) {
+			errno = ENOSPC;
+			die(what);
+		}
+		p += n;
+		len -= n;
+	}
+}
+
+static double now_s(void)
+{
+	struct timespec ts;
+
+	clock_gettime(CLOCK_MONOTONIC, &ts);
+	return ts.tv_sec + ts.tv_nsec / 1e9;
+}
+
+/* The FNV-1a checksum SCULL_IOCGRUNNINGCRC keeps. */
+static uint64_t stream_sum(uint64_t sum, const void *buf, size_t len)
+{
+	const unsigned char *p = buf;
+
+	while (len--) {
+		sum ^= *p++;
+		sum *= 0x100000001b3ULL;
+	}
+	return sum;
+}
+
+#define STREAM_SUM_INIT 0xcbf29ce484222325ULL
+
+/* --- Plain value commands --- */
+
+enum value_kind {
+	/* No argument: print the result of get. An argument: pass it to set. */
+	GETSET,
+	/* Issue cmd with the optional argument and print what it returns. */
+	ACTION,
+	/* Copy out a __u64 with get; or copy one in with set. */
+	U64,
+};
+
+struct value_cmd {
+	const char *name;
+	enum value_kind kind;
+	unsigned long get;
+	unsigned long set;
+	const char *args;
+	const char *help;
+};
+
+static const struct value_cmd value_cmds[] = {
+	{ "writeonce", GETSET, SCULL_IOCGWRITEONCE, SCULL_IOCSWRITEONCE, "[0|1]",
+	  "write-once mode; clearing it needs CAP_SYS_ADMIN" },
+	{ "quota", GETSET, 0, SCULL_IOCSUIDQUOTA, "BYTES",
+	  "per-uid quota, 0 = unlimited (CAP_SYS_ADMIN)" },
+	{ "maxnodes", GETSET, SCULL_IOCGMAXNODES, SCULL_IOCSMAXNODES, "[N]",
+	  "qset node limit, 0 = unlimited" },
+	{ "nodes", GETSET, SCULL_IOCGNODES, 0, "",
+	  "qset nodes allocated" },
+	{ "backend", GETSET, SCULL_IOCGBACKEND, 0, "",
+	  "storage backend: qset nodes (0) or one flat buffer (1)" },
+	{ "fill", GETSET, SCULL_IOCGFILL, SCULL_IOCSFILL, "[BYTE]",
+	  "byte that holes read back as" },
+	{ "chunked", GETSET, 0, SCULL_IOCSCHUNKED, "0|1",
+	  "one quantum per read" },
+	{ "appendonly", GETSET, SCULL_IOCGAPPENDONLY, SCULL_IOCSAPPENDONLY, "[0|1]",
+	  "append-only mode" },
+	{ "logging", GETSET, 0, SCULL_IOCSLOGGING, "0|1",
+	  "operation logging" },
+	{ "mode", GETSET, SCULL_IOCGMODE, SCULL_IOCSMODE, "[BITS]",
+	  "SCULL_MODE_READ (1) and SCULL_MODE_WRITE (2) bits" },
+	{ "sentinel", GETSET, SCULL_IOCGSENTINEL, SCULL_IOCSSENTINEL, "[BYTE|256]",
+	  "end-of-data sentinel, 256 = none" },
+	{ "align", GETSET, SCULL_IOCGALIGN, SCULL_IOCSALIGN, "[BYTES]",
+	  "required I/O alignment, 0 = none" },
+	{ "gfp", GETSET, SCULL_IOCGGFP, SCULL_IOCSGFP, "[0|1]",
+	  "allocate with GFP_KERNEL (0) or GFP_ATOMIC (1)" },
+	{ "record", GETSET, SCULL_IOCGRECORD, SCULL_IOCSRECORD, "[BYTES]",
+	  "end reads at multiples of this, 0 = off" },
+	{ "maxopens", GETSET, SCULL_IOCGMAXOPENS, SCULL_IOCSMAXOPENS, "[N]",
+	  "open handle limit, 0 = unlimited" },
+	{ "migration", GETSET, SCULL_IOCGMIGRATE, 0, "",
+	  "percentage of the running migration done" },
+	{ "inject", GETSET, 0, SCULL_IOCINJECT, "0",
+	  "fail the next allocation (debug kernels)" },
+	{ "injectcopy", GETSET, 0, SCULL_IOCINJECTCOPY, "N",
+	  "cut the next N user copies short (debug kernels)" },
+	{ "trim", ACTION, SCULL_IOCRESET, 0, "[FLAGS]",
+	  "empty the device; FLAGS 1 also drops protected ranges" },
+	{ "commit", ACTION, SCULL_IOCCOMMIT, 0, "",
+	  "retain a version of the contents" },
+	{ "rollback", ACTION, SCULL_IOCROLLBACK, 0, "[N]",
+	  "swap in retained version N" },
+	{ "dropcache", ACTION, SCULL_IOCDROPCACHE, 0, "",
+	  "free the storage, keeping the size" },
+	{ "clearlog", ACTION, SCULL_IOCCLEARLOG, 0, "",
+	  "discard the operation log" },
+	{ "fork", ACTION, SCULL_IOCFORK, 0, "[0|1|2]",
+	  "copy into /dev/scull_dynN, printing N; 1 = qset, 2 = flat" },
+	{ "migrate", ACTION, SCULL_IOCMIGRATE, 0, "QUANTUM",
+	  "migrate to QUANTUM-byte quanta in the background" },
+	{ "extend", ACTION, SCULL_IOCEXTEND, 0, "SIZE",
+	  "grow to SIZE bytes, printing the size" },
+	{ "compact", ACTION, SCULL_IOCCOMPACT, 0, "",
+	  "free fill-only quanta, printing the bytes freed" },
+	{ "shutdown", ACTION, SCULL_IOCSHUTDOWN, 0, "",
+	  "refuse all further use until reload (CAP_SYS_ADMIN)" },
+	{ "discardfront", ACTION, SCULL_IOCDISCARDFRONT, 0, "BYTES",
+	  "drop the first BYTES bytes, printing the size" },
+	{ "footprint", U64, SCULL_IOCGFOOTPRINT, 0, "",
+	  "kernel memory held by the data" },
+	{ "gen", U64, SCULL_IOCGGEN, 0, "",
+	  "mutation counter" },
+	{ "created", U64, SCULL_IOCGCREATED, 0, "",
+	  "creation time, monotonic ns" },
+	{ "maxgap", U64, SCULL_IOCGMAXGAP, SCULL_IOCSMAXGAP, "[BYTES]",
+	  "largest hole a write may open" },
+	{ "crc", U64, SCULL_IOCGRUNNINGCRC, 0, "",
+	  "running checksum of the bytes written" },
+};
+
+static int run_value(const struct value_cmd *vc, int fd, int argc, char **argv)
+{
+	unsigned long long arg = argc > 0 ? parse_num(argv[0]) : 0;
+	__u64 val;
+
+	switch (vc->kind) {
+	case GETSET:
+		if (argc > 0 && vc->set) {
+			xioctl(fd, vc->set, arg, vc->name);
+			return 0;
+		}
+		if (argc > 0 || !vc->get)
+			usage_error(argc > 0 ? "no value to set" : "value required");
+		printf("%ld\n", xioctl(fd, vc->get, 0, vc->name));
+		return 0;
+	case ACTION:
+		printf("%ld\n", xioctl(fd, vc->get, arg, vc->name));
+		return 0;
+	case U64:
+		if (argc > 0 && vc->set) {
+			val = arg;
+			xioctl(fd, vc->set, (unsigned long)&val, vc->name);
+			return 0;
+		}
+		if (argc > 0)
+			usage_error("no value to set");
+		xioctl(fd, vc->get, (unsigned long)&val, vc->name);
+		printf("%llu\n", (unsigned long long)val);
+		return 0;
+	}
+	return 1;
+}
+
+/* --- Struct commands --- */
+
+static int cmd_stats(int fd, int argc, char **argv)
+{
+	struct scull_stats st = { .size = sizeof(st) };
+
+	xioctl(fd, SCULL_IOCGSTATS, (unsigned long)&st, "stats");
+	printf("bytes_read: %llu\nbytes_written: %llu\nopen_count: %llu\n",
+	       (unsigned long long)st.bytes_read,
+	       (unsigned long long)st.bytes_written,
+	       (unsigned long long)st.open_count);
+	return 0;
+}
+
+static int cmd_fillstats(int fd, int argc, char **argv)
+{
+	struct scull_fill_stats st = { .size = sizeof(st) };
+
+	xioctl(fd, SCULL_IOCGFILLSTATS, (unsigned long)&st, "fillstats");
+	printf("quanta: %llu\nfull: %llu\npartial: %llu\n"
+	       "valid_bytes: %llu\npadding_bytes: %llu\n",
+	       (unsigned long long)st.quanta, (unsigned long long)st.full,
+	       (unsigned long long)st.partial,
+	       (unsigned long long)st.valid_bytes,
+	       (unsigned long long)st.padding_bytes);
+	return 0;
+}
+
+static int cmd_version(int fd, int argc, char **argv)
+{
+	struct scull_version v;
+
+	xioctl(fd, SCULL_IOCGVERSION, (unsigned long)&v, "version");
+	printf("%u.%u.%u features=%#llx\n", v.major, v.minor, v.patch,
+	       (unsigned long long)v.features);
+	return 0;
+}
+
+static int cmd_geometry(int fd, int argc, char **argv)
+{
+	struct scull_geometry g = { 0 };
+
+	if (argc == 0) {
+		xioctl(fd, SCULL_IOCGGEOMETRY, (unsigned long)&g, "geometry");
+		printf("quantum: %llu\nqset: %llu\n",
+		       (unsigned long long)g.quantum, (unsigned long long)g.qset);
+		return 0;
+	}
+	if (argc < 2)
+		usage_error("QUANTUM and QSET required");
+	g.quantum = parse_num(argv[0]);
+	g.qset = parse_num(argv[1]);
+	if (argc > 2)
+		g.flags = parse_num(argv[2]);
+	xioctl(fd, SCULL_IOCSGEOMETRY, (unsigned long)&g, "geometry");
+	return 0;
+}
+
+static int cmd_requantum(int fd, int argc, char **argv)
+{
+	struct scull_geometry g = { 0 };
+
+	if (argc < 2)
+		usage_error("QUANTUM and QSET required");
+	g.quantum = parse_num(argv[0]);
+	g.qset = parse_num(argv[1]);
+	xioctl(fd, SCULL_IOCREQUANTUM, (unsigned long)&g, "requantum");
+	return 0;
+}
+
+static int cmd_lastwriter(int fd, int argc, char **argv)
+{
+	struct scull_writer w;
+
+	xioctl(fd, SCULL_IOCGLASTWRITER, (unsigned long)&w, "lastwriter");
+	printf("pid: %d\nuid: %u\n", w.pid, w.uid);
+	return 0;
+}
+
+static int cmd_sizes(int fd, int argc, char **argv)
+{
+	struct scull_sizes s;
+
+	xioctl(fd, SCULL_IOCGSIZES, (unsigned long)&s, "sizes");
+	printf("qset: %llu\nqset_entry: %llu\ndirty_word: %llu\ndev_data: %llu\n",
+	       (unsigned long long)s.qset, (unsigned long long)s.qset_entry,
+	       (unsigned long long)s.dirty_word, (unsigned long long)s.dev_data);
+	return 0;
+}
+
+static int cmd_label(int fd, int argc, char **argv)
+{
+	char label[SCULL_LABEL_LEN + 1] = { 0 };
+
+	if (argc > 0) {
+		if (strlen(argv[0]) > SCULL_LABEL_LEN)
+			usage_error("label too long");
+		strncpy(label, argv[0], SCULL_LABEL_LEN);
+		xioctl(fd, SCULL_IOCSLABEL, (unsigned long)label, "label");
+		return 0;
+	}
+	xioctl(fd, SCULL_IOCGLABEL, (unsigned long)label, "label");
+	printf("%s\n", label);
+	return 0;
+}
+
+static int range_cmd(int fd, int argc, char **argv, unsigned long cmd,
+		     const char *name)
+{
+	struct scull_range r;
+
+	if (argc < 2)
+		usage_error("OFFSET and LEN required");
+	r.offset = parse_num(argv[0]);
+	r.len = parse_num(argv[1]);
+	xioctl(fd, cmd, (unsigned long)&r, name);
+	return 0;
+}
+
+static int cmd_protect(int fd, int argc, char **argv)
+{
+	return range_cmd(fd, argc, argv, SCULL_IOCPROTECT, "protect");
+}
+
+static int cmd_unprotect(int fd, int argc, char **argv)
+{
+	return range_cmd(fd, argc, argv, SCULL_IOCUNPROTECT, "unprotect");
+}
+
+static int cmd_peek(int fd, int argc, char **argv)
+{
+	struct scull_peek p;
+	char *buf;
+	long n;
+
+	if (argc < 2)
+		usage_error("OFFSET and LEN required");
+	p.offset = parse_num(argv[0]);
+	p.len = parse_num(argv[1]);
+	buf = malloc(p.len ? p.len : 1);
+	if (!buf)
+		die("malloc");
+	p.buf = (unsigned long)buf;
+	n = xioctl(fd, SCULL_IOCPEEK, (unsigned long)&p, "peek");
+	write_all(STDOUT_FILENO, buf, n, "stdout");
+	free(buf);
+	return 0;
+}
+
+static int cmd_readex(int fd, int argc, char **argv)
+{
+	struct scull_read_ex r = { 0 };
+	char *buf;
+
+	if (argc < 2)
+		usage_error("OFFSET and LEN required");
+	r.offset = parse_num(argv[0]);
+	r.len = parse_num(argv[1]);
+	buf = malloc(r.len ? r.len : 1);
+	if (!buf)
+		die("malloc");
+	r.buf = (unsigned long)buf;
+	xioctl(fd, SCULL_IOCREADEX, (unsigned long)&r, "readex");
+	write_all(STDOUT_FILENO, buf, r.copied, "stdout");
+	fprintf(stderr, "copied %llu, more %u\n",
+		(unsigned long long)r.copied, r.more);
+	free(buf);
+	return 0;
+}
+
+static int cmd_log(int fd, int argc, char **argv)
+{
+	struct scull_log_req req;
+	struct scull_log_entry *e;
+	long n, i;
+
+	req.count = argc > 0 ? parse_num(argv[0]) : 16;
+	e = calloc(req.count ? req.count : 1, sizeof(*e));
+	if (!e)
+		die("calloc");
+	req.buf = (unsigned long)e;
+	n = xioctl(fd, SCULL_IOCGETLOG, (unsigned long)&req, "log");
+	for (i = 0; i < n; i++)
+		printf("%llu pid=%d op=%u offset=%llu len=%llu result=%lld\n",
+		       (unsigned long long)e[i].timestamp_ns, e[i].pid, e[i].op,
+		       (unsigned long long)e[i].offset,
+		       (unsigned long long)e[i].len, (long long)e[i].result);
+	free(e);
+	return 0;
+}
+
+static int cmd_lock(int fd, int argc, char **argv)
+{
+	struct scull_lock_req req = { 0 };
+	char line[16];
+
+	if (argc < 2)
+		usage_error("OFFSET and LEN required");
+	req.offset = parse_num(argv[0]);
+	req.len = parse_num(argv[1]);
+	if (argc > 2)
+		req.flags = parse_num(argv[2]);
+	xioctl(fd, SCULL_IOCLOCK, (unsigned long)&req, "lock");
+	/* The lock belongs to this open file; hold it until told to let go */
+	fprintf(stderr, "locked; press Enter to release\n");
+	if (!fgets(line, sizeof(line), stdin))
+		line[0] = 0;
+	xioctl(fd, SCULL_IOCUNLOCK, (unsigned long)&req, "unlock");
+	return 0;
+}
+
+static int cmd_fiemap(int fd, int argc, char **argv)
+{
+	struct scull_extent ext[64];
+	struct scull_fiemap req = { 0 };
+	long n, i;
+
+	req.start = argc > 0 ? parse_num(argv[0]) : 0;
+	for (;;) {
+		req.buf = (unsigned long)ext;
+		req.count = ARRAY_SIZE(ext);
+		n = xioctl(fd, SCULL_IOCFIEMAP, (unsigned long)&req, "fiemap");
+		for (i = 0; i < n; i++)
+			printf("%llu+%llu%s\n",
+			       (unsigned long long)ext[i].logical_offset,
+			       (unsigned long long)ext[i].length,
+			       ext[i].flags & SCULL_EXTENT_LAST ? " last" : "");
+		if (n == 0 || ext[n - 1].flags & SCULL_EXTENT_LAST)
+			return 0;
+		req.start = req.next;
+	}
+}
+
+/* Runs SCULL_IOCCHECK over the whole chain, returning the failing code. */
+static unsigned int check_device(int fd, struct scull_check *req)
+{
+	memset(req, 0, sizeof(*req));
+	do {
+		if (xioctl(fd, SCULL_IOCCHECK, (unsigned long)req, "check"))
+			return req->code;
+	} while (req->cursor);
+	return 0;
+}
+
+static int cmd_check(int fd, int argc, char **argv)
+{
+	struct scull_check req;
+	unsigned int code = check_device(fd, &req);
+
+	if (code) {
+		printf("violation %u at node %llu, quantum %llu\n", code,
+		       (unsigned long long)req.item,
+		       (unsigned long long)req.s_pos);
+		return 1;
+	}
+	printf("ok\n");
+	return 0;
+}
+
+static int cmd_list(int fd, int argc, char **argv)
+{
+	struct scull_dev_info info[16];
+	struct scull_list_req req = {
+		.buf = (unsigned long)info,
+		.count = ARRAY_SIZE(info),
+	};
+	long n, i;
+
+	n = xioctl(fd, SCULL_IOCLIST, (unsigned long)&req, "list");
+	for (i = 0; i < n; i++)
+		printf("%-16.16s size=%llu quantum=%llu qset=%llu\n",
+		       (const char *)info[i].name,
+		       (unsigned long long)info[i].size,
+		       (unsigned long long)info[i].quantum,
+		       (unsigned long long)info[i].qset);
+	return 0;
+}
+
+static int cmd_waitpattern(int fd, int argc, char **argv)
+{
+	struct scull_pattern p = { 0 };
+
+	if (argc < 1 || strlen(argv[0]) > SCULL_PATTERN_MAX)
+		usage_error("PATTERN of 1 to 16 bytes required");
+	p.len = strlen(argv[0]);
+	memcpy(p.pattern, argv[0], p.len);
+	printf("%ld\n", xioctl(fd, SCULL_P_IOCWAITPATTERN, (unsigned long)&p,
+			       "waitpattern"));
+	return 0;
+}
+
+static int cmd_piperead(int fd, int argc, char **argv)
+{
+	char buf[4096];
+	size_t count;
+	ssize_t n;
+
+	if (argc < 1)
+		usage_error("COUNT required");
+	count = parse_num(argv[0]);
+	if (argc > 1)
+		xioctl(fd, SCULL_P_IOCSDEADLINE, parse_num(argv[1]), "deadline");
+	if (argc > 2)
+		xioctl(fd, SCULL_P_IOCSNORESTART, parse_num(argv[2]), "norestart");
+	while (count > 0) {
+		n = read(fd, buf, count < sizeof(buf) ? count : sizeof(buf));
+		if (n < 0)
+			die("read");
+		if (n == 0)
+			break;
+		write_all(STDOUT_FILENO, buf, n, "stdout");
+		count -= n;
+	}
+	return 0;
+}
+
+static int cmd_members(int fd, int argc, char **argv)
+{
+	struct scull_concat_members m;
+
+	if (argc < 2)
+		usage_error("FIRST and SECOND required");
+	m.first = parse_num(argv[0]);
+	m.second = parse_num(argv[1]);
+	xioctl(fd, SCULL_C_IOCSMEMBERS, (unsigned long)&m, "members");
+	return 0;
+}
+
+/* --- Commands for humans --- */
+
+static void print_value(int fd, const char *name, unsigned long cmd)
+{
+	long val = ioctl(fd, cmd, 0);
+
+	if (val < 0)
+		printf("%s: (%s)\n", name, strerror(errno));
+	else
+		printf("%s: %ld\n", name, val);
+}
+
+static int cmd_info(int fd, int argc, char **argv)
+{
+	struct scull_geometry g;
+	char label[SCULL_LABEL_LEN + 1] = { 0 };
+	__u64 val;
+
+	cmd_version(fd, 0, NULL);
+	/* Extending to 0 never changes anything but reports the size */
+	print_value(fd, "size", SCULL_IOCEXTEND);
+	if (ioctl(fd, SCULL_IOCGGEOMETRY, &g) == 0)
+		printf("quantum: %llu\nqset: %llu\n",
+		       (unsigned long long)g.quantum, (unsigned long long)g.qset);
+	print_value(fd, "backend", SCULL_IOCGBACKEND);
+	print_value(fd, "nodes", SCULL_IOCGNODES);
+	print_value(fd, "maxnodes", SCULL_IOCGMAXNODES);
+	if (ioctl(fd, SCULL_IOCGFOOTPRINT, &val) == 0)
+		printf("footprint: %llu\n", (unsigned long long)val);
+	if (ioctl(fd, SCULL_IOCGGEN, &val) == 0)
+		printf("gen: %llu\n", (unsigned long long)val);
+	print_value(fd, "mode", SCULL_IOCGMODE);
+	print_value(fd, "writeonce", SCULL_IOCGWRITEONCE);
+	print_value(fd, "appendonly", SCULL_IOCGAPPENDONLY);
+	if (ioctl(fd, SCULL_IOCGLABEL, label) == 0)
+		printf("label: %s\n", label);
+	return cmd_stats(fd, 0, NULL);
+}
+
+static int cmd_freeze(int fd, int argc, char **argv)
+{
+	xioctl(fd, SCULL_IOCSWRITEONCE, 1, "freeze");
+	return 0;
+}
+
+static int cmd_thaw(int fd, int argc, char **argv)
+{
+	xioctl(fd, SCULL_IOCSWRITEONCE, 0, "thaw");
+	return 0;
+}
+
+static int open_file(const char *path, int flags)
+{
+	int fd;
+
+	if (!strcmp(path, "-"))
+		return flags == O_RDONLY ? STDIN_FILENO : STDOUT_FILENO;
+	fd = open(path, flags, 0644);
+	if (fd < 0)
+		die(path);
+	return fd;
+}
+
+/* Copies src to dst until end of file, returning the bytes copied. */
+static size_t copy_fd(int src, int dst)
+{
+	char buf[65536];
+	size_t total = 0;
+	ssize_t n;
+
+	while ((n = read(src, buf, sizeof(buf))) != 0) {
+		if (n < 0) {
+			if (errno == EINTR)
+				continue;
+			die("read");
+		}
+		write_all(dst, buf, n, "write");
+		total += n;
+	}
+	return total;
+}
+
+static int cmd_export(int fd, int argc, char **argv)
+{
+	int out;
+
+	if (argc < 1)
+		usage_error("FILE required");
+	out = open_file(argv[0], O_WRONLY | O_CREAT | O_TRUNC);
+	fprintf(stderr, "exported %zu bytes\n", copy_fd(fd, out));
+	return 0;
+}
+
+static int cmd_import(int fd, int argc, char **argv)
+{
+	int in;
+
+	if (argc < 1)
+		usage_error("FILE required");
+	in = open_file(argv[0], O_RDONLY);
+	xioctl(fd, SCULL_IOCRESET, 0, "trim");
+	fprintf(stderr, "imported %zu bytes\n", copy_fd(in, fd));
+	return 0;
+}
+
+static int cmd_bench(int fd, int argc, char **argv)
+{
+	static char buf[65536];
+	size_t mib = argc > 0 ? parse_num(argv[0]) : 64;
+	size_t total = mib << 20, done;
+	double t0, write_s, read_s;
+	__u64 footprint;
+	ssize_t n;
+
+	memset(buf, 0x5a, sizeof(buf));
+	xioctl(fd, SCULL_IOCRESET, 0, "trim");
+
+	t0 = now_s();
+	for (done = 0; done < total; done += n) {
+		n = write(fd, buf, sizeof(buf));
+		if (n <= 0)
+			die("write");
+	}
+	write_s = now_s() - t0;
+	xioctl(fd, SCULL_IOCGFOOTPRINT, (unsigned long)&footprint, "footprint");
+
+	t0 = now_s();
+	for (done = 0; done < total; done += n) {
+		n = pread(fd, buf, sizeof(buf), done);
+		if (n <= 0)
+			die("read");
+	}
+	read_s = now_s() - t0;
+	xioctl(fd, SCULL_IOCRESET, 0, "trim");
+
+	print_value(fd, "backend", SCULL_IOCGBACKEND);
+	printf("write: %.1f MiB/s\nread: %.1f MiB/s\n", mib / write_s, mib / read_s);
+	printf("footprint: %llu bytes for %zu of data (%.2f%%)\n",
+	       (unsigned long long)footprint, total,
+	       100.0 * footprint / total);
+	return 0;
+}
+
+static int cmd_watch(int fd, int argc, char **argv)
+{
+	struct scull_event ev[16];
+	ssize_t n;
+	size_t i;
+
+	while ((n = read(fd, ev, sizeof(ev))) != 0) {
+		if (n < 0) {
+			if (errno == EINTR)
+				continue;
+			die("read");
+		}
+		for (i = 0; i < n / sizeof(ev[0]); i++)
+			printf("gen=%llu pid=%d offset=%llu len=%llu%s\n",
+			       (unsigned long long)ev[i].generation, ev[i].pid,
+			       (unsigned long long)ev[i].offset,
+			       (unsigned long long)ev[i].len,
+			       ev[i].flags & SCULL_EVENT_OVERFLOW ? " (overflow)" : "");
+		fflush(stdout);
+	}
+	return 0;
+}
+
+/* --- selfcheck --- */
+
+static int checks, failures;
+
+#define EXPECT(cond) expect(cond, #cond, __LINE__)
+
+static void expect(int ok, const char *what, int line)
+{
+	checks++;
+	if (!ok) {
+		failures++;
+		fprintf(stderr, "%s: selfcheck line %d: %s failed (errno %s)\n",
+			prog, line, what, strerror(errno));
+	}
+}
+
+static int cmd_selfcheck(int fd, int argc, char **argv)
+{
+	static const char hello[] = "hello, world";
+	struct scull_stats st = { .size = SCULL_STATS_SIZE_VER0 };
+	struct scull_fill_stats fst = { .size = sizeof(fst) };
+	struct scull_version v;
+	struct scull_geometry g;
+	struct scull_peek p;
+	struct scull_read_ex rx = { 0 };
+	struct scull_extent ext[4];
+	struct scull_fiemap fm = { 0 };
+	struct scull_check chk;
+	char buf[64], label[SCULL_LABEL_LEN] = "selfcheck";
+	__u64 crc, gen0, gen1;
+	long backend;
+
+	/* The interface version this tool was built against */
+	EXPECT(ioctl(fd, SCULL_IOCGVERSION, &v) == 0);
+	EXPECT(v.major == SCULL_VERSION_MAJOR);
+
+	/* Write, read back, and the running checksum */
+	EXPECT(ioctl(fd, SCULL_IOCRESET, 0) == 0);
+	EXPECT(ioctl(fd, SCULL_IOCGGEN, &gen0) == 0);
+	EXPECT(write(fd, hello, strlen(hello)) == (ssize_t)strlen(hello));
+	EXPECT(ioctl(fd, SCULL_IOCGGEN, &gen1) == 0 && gen1 > gen0);
+	EXPECT(pread(fd, buf, sizeof(buf), 0) == (ssize_t)strlen(hello));
+	EXPECT(!memcmp(buf, hello, strlen(hello)));
+	EXPECT(ioctl(fd, SCULL_IOCGRUNNINGCRC, &crc) == 0);
+	EXPECT(crc == stream_sum(STREAM_SUM_INIT, hello, strlen(hello)));
+	EXPECT(ioctl(fd, SCULL_IOCEXTEND, 0) == (long)strlen(hello));
+
+	/* Reads that leave the file position alone */
+	p.offset = 7;
+	p.len = 5;
+	p.buf = (unsigned long)buf;
+	EXPECT(ioctl(fd, SCULL_IOCPEEK, &p) == 5 && !memcmp(buf, "world", 5));
+	rx.offset = 0;
+	rx.len = 5;
+	rx.buf = (unsigned long)buf;
+	EXPECT(ioctl(fd, SCULL_IOCREADEX, &rx) == 5 && rx.more == 1);
+
+	/* Sized structs: the first version and the current one both work */
+	EXPECT(ioctl(fd, SCULL_IOCGSTATS, &st) == 0 && st.bytes_written >= strlen(hello));
+	st.size = SCULL_STATS_SIZE_VER0 - 1;
+	EXPECT(ioctl(fd, SCULL_IOCGSTATS, &st) < 0 && errno == EINVAL);
+	EXPECT(ioctl(fd, SCULL_IOCGFILLSTATS, &fst) == 0 && fst.valid_bytes == strlen(hello));
+
+	/* Layout */
+	EXPECT(ioctl(fd, SCULL_IOCGGEOMETRY, &g) == 0 && g.quantum && g.qset);
+	if (v.minor >= 1) {
+		backend = ioctl(fd, SCULL_IOCGBACKEND, 0);
+		EXPECT(backend == SCULL_BACKEND_QSET || backend == SCULL_BACKEND_FLAT);
+	}
+	if (v.features & SCULL_FEAT_FIEMAP) {
+		fm.buf = (unsigned long)ext;
+		fm.count = ARRAY_SIZE(ext);
+		EXPECT(ioctl(fd, SCULL_IOCFIEMAP, &fm) == 1 && ext[0].logical_offset == 0);
+	}
+	EXPECT(check_device(fd, &chk) == 0);
+
+	/* Settings round-trip */
+	EXPECT(ioctl(fd, SCULL_IOCSLABEL, label) == 0);
+	memset(label, 0, sizeof(label));
+	EXPECT(ioctl(fd, SCULL_IOCGLABEL, label) == 0 && !strcmp(label, "selfcheck"));
+	memset(label, 0, sizeof(label));
+	EXPECT(ioctl(fd, SCULL_IOCSLABEL, label) == 0);
+	EXPECT(ioctl(fd, SCULL_IOCSALIGN, 3) < 0 && errno == EINVAL);
+
+	/* Holes read back as the fill byte */
+	EXPECT(ioctl(fd, SCULL_IOCRESET, 0) == 0);
+	EXPECT(ioctl(fd, SCULL_IOCSFILL, 'x') == 0);
+	EXPECT(ioctl(fd, SCULL_IOCEXTEND, 4) == 4);
+	EXPECT(pread(fd, buf, sizeof(buf), 0) == 4 && !memcmp(buf, "xxxx", 4));
+	EXPECT(ioctl(fd, SCULL_IOCRESET, 0) == 0);
+	EXPECT(ioctl(fd, SCULL_IOCSFILL, 0) == 0);
+
+	/* Commands outside the interface */
+	EXPECT(ioctl(fd, _IO('x', 0), 0) < 0 && errno == ENOTTY);
+	EXPECT(ioctl(fd, _IOR(SCULL_IOC_MAGIC, 42, __u32), &g) < 0 && errno == ENOTTY);
+
+	printf("selfcheck: %d of %d checks passed\n", checks - failures, checks);
+	return failures ? 1 : 0;
+}
+
+/* --- Command table --- */
+
+struct command {
+	const char *name;
+	int (*run)(int fd, int argc, char **argv);
+	int flags;
+	const char *device;
+	const char *args;
+	const char *help;
+};
+
+static int cmd_help(int fd, int argc, char **argv);
+
+static const struct command commands[] = {
+	{ "info", cmd_info, O_RDWR, DEFAULT_DEVICE, "",
+	  "summary of the device" },
+	{ "stats", cmd_stats, O_RDONLY, DEFAULT_DEVICE, "",
+	  "I/O statistics" },
+	{ "fillstats", cmd_fillstats, O_RDONLY, DEFAULT_DEVICE, "",
+	  "how full the allocated quanta are" },
+	{ "version", cmd_version, O_RDONLY, DEFAULT_DEVICE, "",
+	  "interface version and features" },
+	{ "geometry", cmd_geometry, O_RDWR, DEFAULT_DEVICE, "[QUANTUM QSET [FLAGS]]",
+	  "quantum and qset size" },
+	{ "requantum", cmd_requantum, O_RDWR, DEFAULT_DEVICE, "QUANTUM QSET",
+	  "re-chunk the contents" },
+	{ "lastwriter", cmd_lastwriter, O_RDONLY, DEFAULT_DEVICE, "",
+	  "process of the last write" },
+	{ "sizes", cmd_sizes, O_RDONLY, DEFAULT_DEVICE, "",
+	  "internal structure sizes" },
+	{ "label", cmd_label, O_RDONLY, DEFAULT_DEVICE, "[TEXT]",
+	  "free-text label" },
+	{ "protect", cmd_protect, O_RDWR, DEFAULT_DEVICE, "OFFSET LEN",
+	  "make a range read-only" },
+	{ "unprotect", cmd_unprotect, O_RDWR, DEFAULT_DEVICE, "OFFSET LEN",
+	  "make a range writable again (CAP_SYS_ADMIN)" },
+	{ "peek", cmd_peek, O_RDONLY, DEFAULT_DEVICE, "OFFSET LEN",
+	  "copy bytes to stdout" },
+	{ "readex", cmd_readex, O_RDONLY, DEFAULT_DEVICE, "OFFSET LEN",
+	  "peek, also reporting whether more data follows" },
+	{ "log", cmd_log, O_RDONLY, DEFAULT_DEVICE, "[COUNT]",
+	  "operation log, newest first" },
+	{ "lock", cmd_lock, O_RDWR, DEFAULT_DEVICE, "OFFSET LEN [FLAGS]",
+	  "hold an advisory lock until Enter" },
+	{ "fiemap", cmd_fiemap, O_RDONLY, DEFAULT_DEVICE, "[START]",
+	  "allocated extents" },
+	{ "check", cmd_check, O_RDONLY, DEFAULT_DEVICE, "",
+	  "check internal consistency" },
+	{ "list", cmd_list, O_RDONLY, DEFAULT_DEVICE, "",
+	  "/dev/scull and the dynamic devices" },
+	{ "waitpattern", cmd_waitpattern, O_RDONLY, "/dev/scullpipe", "PATTERN",
+	  "wait for PATTERN in the pipe" },
+	{ "piperead", cmd_piperead, O_RDONLY, "/dev/scullpipe",
+	  "COUNT [DEADLINE_MS [NORESTART]]", "read from the pipe" },
+	{ "members", cmd_members, O_RDWR, "/dev/scull.concat", "FIRST SECOND",
+	  "select the concatenated devices" },
+	{ "freeze", cmd_freeze, O_RDWR, DEFAULT_DEVICE, "",
+	  "enable write-once mode" },
+	{ "thaw", cmd_thaw, O_RDWR, DEFAULT_DEVICE, "",
+	  "disable write-once mode (CAP_SYS_ADMIN)" },
+	{ "export", cmd_export, O_RDONLY, DEFAULT_DEVICE, "FILE",
+	  "copy the contents to FILE (- for stdout)" },
+	{ "import", cmd_import, O_WRONLY, DEFAULT_DEVICE, "FILE",
+	  "replace the contents with FILE (- for stdin)" },
+	{ "bench", cmd_bench, O_RDWR, DEFAULT_DEVICE, "[MIB]",
+	  "time writing and reading MIB MiB, and the memory used" },
+	{ "watch", cmd_watch, O_RDONLY, EVENTS_DEVICE, "",
+	  "print change events as they happen" },
+	{ "selfcheck", cmd_selfcheck, O_RDWR, DEFAULT_DEVICE, "",
+	  "smoke test; empties the device" },
+	{ "help", cmd_help, -1, NULL, "", "this list" },
+};
+
+static int cmd_help(int fd, int argc, char **argv)
+{
+	size_t i;
+
+	printf("usage: %s COMMAND [DEVICE] [ARGS...]\n\n", prog);
+	for (i = 0; i < ARRAY_SIZE(commands); i++)
+		printf("  %-12s %-32s %s\n", commands[i].name, commands[i].args,
+		       commands[i].help);
+	for (i = 0; i < ARRAY_SIZE(value_cmds); i++)
+		printf("  %-12s %-32s %s\n", value_cmds[i].name, value_cmds[i].args,
+		       value_cmds[i].help);
+	printf("\nDEVICE defaults to %s, or the pipe, concat or events device\n"
+	       "for the commands that only apply there.\n", DEFAULT_DEVICE);
+	return 0;
+}
+
+/* Returns true if s names a device node rather than an argument. */
+static int is_device(const char *s)
+{
+	return !strncmp(s, "/dev/", 5);
+}
+
+int main(int argc, char **argv)
+{
+	const char *name, *device = NULL;
+	size_t i;
+	int fd;
+
+	if (argc < 2) {
+		cmd_help(-1, 0, NULL);
+		return 2;
+	}
+	name = argv[1];
+	argc -= 2;
+	argv += 2;
+	if (argc > 0 && is_device(argv[0])) {
+		device = argv[0];
+		argc--;
+		argv++;
+	}
+
+	for (i = 0; i < ARRAY_SIZE(commands); i++) {
+		const struct command *c = &commands[i];
+
+		if (strcmp(name, c->name))
+			continue;
+		if (c->flags < 0)
+			return c->run(-1, argc, argv);
+		fd = open(device ? device : c->device, c->flags);
+		if (fd < 0)
+			die(device ? device : c->device);
+		return c->run(fd, argc, argv);
+	}
+	for (i = 0; i < ARRAY_SIZE(value_cmds); i++) {
+		const struct value_cmd *vc = &value_cmds[i];
+		int flags = argc > 0 || vc->kind == ACTION ? O_RDWR : O_RDONLY;
+
+		if (strcmp(name, vc->name))
+			continue;
+		fd = open(device ? device : DEFAULT_DEVICE, flags);
+		if (fd < 0)
+			die(device ? device : DEFAULT_DEVICE);
+		return run_value(vc, fd, argc, argv);
+	}
+	usage_error("unknown command");
+	return 2;
+}
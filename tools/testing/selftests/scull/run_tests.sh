@@ -0,0 +1,101 @@
+#!/bin/sh
+# SPDX-License-Identifier: GPL-2.0
+#
+# Runs every scull_test group, loading the module afresh for each one with
+# the parameters that group expects. Output is TAP, with each group's own
+# TAP stream nested under its result line as "# " diagnostics. Exits 4 if
+# the tests cannot run here: not root, or no module to load.
+#
+# SCULL_KO names the module to load, by default the one built in the
+# top-level directory.
+
+KSFT_PASS=0
+KSFT_FAIL=1
+KSFT_SKIP=4
+
+cd "$(dirname "$0")" || exit $KSFT_FAIL
+SCULL_KO=${SCULL_KO:-../../../../scull_rust.ko}
+
+# One "name test params..." line per run, where test is a program and,
+# after a colon, the group it should run. flat repeats basic on the flat
+# backend; shutdown lasts until the module is removed.
+RUNS="basic scull_test:basic
+flat scull_test:basic backend=1
+ioctl scull_test:ioctl
+errors scull_test:errors
+limits scull_test:limits max_nodes=2 quantum=4 qset=2
+appendonly scull_test:appendonly append_only=1
+versions scull_test:versions keep_versions=2
+quota scull_test:quota uid_quota=8000 quantum=4000
+pipe scull_test:pipe
+pipe_processes scull_pipe_test
+shutdown scull_test:shutdown"
+
+skip_all()
+{
+	echo "TAP version 13"
+	echo "1..0 # SKIP $1"
+	exit $KSFT_SKIP
+}
+
+loaded()
+{
+	grep -qs '^scull_rust ' /proc/modules
+}
+
+[ "$(id -u)" -eq 0 ] || skip_all "must be run as root"
+[ -f "$SCULL_KO" ] || skip_all "$SCULL_KO not found, set SCULL_KO"
+[ -x ./scull_test ] && [ -x ./scull_pipe_test ] || skip_all "tests not built, run make"
+if loaded && ! rmmod scull_rust; then
+	skip_all "scull_rust is loaded and in use"
+fi
+
+status=$(mktemp) || exit $KSFT_FAIL
+trap 'rm -f "$status"; loaded && rmmod scull_rust' EXIT
+
+echo "TAP version 13"
+echo "1..$(echo "$RUNS" | wc -l)"
+
+n=0
+failed=0
+skipped=0
+while read -r name test params; do
+	n=$((n + 1))
+	backend=qset
+	case " $params " in
+	*" backend=1 "*) backend=flat ;;
+	esac
+
+	# shellcheck disable=SC2086
+	if ! insmod "$SCULL_KO" $params; then
+		echo "not ok $n $name # insmod $params failed"
+		failed=$((failed + 1))
+		continue
+	fi
+	# Give udev a moment to create the device nodes
+	for _ in 1 2 3 4 5 6 7 8 9 10; do
+		[ -c /dev/scull ] && [ -c /dev/scullpipe ] && break
+		sleep 0.1
+	done
+
+	case $test in
+	*:*) set -- "./${test%%:*}" "${test#*:}" ;;
+	*) set -- "./$test" ;;
+	esac
+	{ SCULL_BACKEND=$backend "$@" 2>&1; echo $? > "$status"; } |
+		sed 's/^/# /'
+	rc=$(cat "$status")
+	rmmod scull_rust
+
+	case $rc in
+	"$KSFT_PASS") echo "ok $n $name" ;;
+	"$KSFT_SKIP") echo "ok $n $name # SKIP"; skipped=$((skipped + 1)) ;;
+	*) echo "not ok $n $name"; failed=$((failed + 1)) ;;
+	esac
+done <<EOF
+$RUNS
+EOF
+
+[ $failed -eq 0 ] || exit $KSFT_FAIL
+[ $skipped -lt $n ] || exit $KSFT_SKIP
+exit $KSFT_PASS
@@ -0,0 +1,476 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * Multi-process selftests for /dev/scullpipe, run by run_tests.sh with the
+ * module freshly loaded.
+ *
+ * Each test forks readers and writers that block in the driver, and the
+ * parent checks who is still waiting and what each child saw. Children
+ * report through their exit status, and every wait for a child has a
+ * timeout, so a lost wakeup fails the test instead of hanging the run.
+ *
+ * The pipe's ring is fixed at PIPE_RING bytes, with no watermarks, and the
+ * driver has no poll hook; the poll test reports that as a skip.
+ */
+
+#define _GNU_SOURCE
+#include <fcntl.h>
+#include <poll.h>
+#include <signal.h>
+#include <stdlib.h>
+#include <sys/ioctl.h>
+#include <sys/wait.h>
+#include <time.h>
+#include <unistd.h>
+
+#include "scull.h"
+#include "tap.h"
+
+#define PIPE_DEVICE	"/dev/scullpipe"
+#define PIPE_RING	4000
+
+/* How long a child may take to do something it has been unblocked for */
+#define CHILD_TIMEOUT_MS	5000
+/* How long to give a child to get into a blocking wait */
+#define SETTLE_MS		200
+
+static void sleep_ms(int ms)
+{
+	struct timespec ts = { .tv_sec = ms / 1000, .tv_nsec = (ms % 1000) * 1000000L };
+
+	nanosleep(&ts, NULL);
+}
+
+static long now_ms(void)
+{
+	struct timespec ts;
+
+	clock_gettime(CLOCK_MONOTONIC, &ts);
+	return ts.tv_sec * 1000 + ts.tv_nsec / 1000000;
+}
+
+/* Empties the ring, so each test starts from nothing buffered. */
+static int drain(void)
+{
+	char buf[PIPE_RING];
+	int fd = open(PIPE_DEVICE, O_RDONLY | O_NONBLOCK);
+
+	if (fd < 0)
+		return -1;
+	while (read(fd, buf, sizeof(buf)) > 0)
+		;
+	close(fd);
+	return errno == EAGAIN ? 0 : -1;
+}
+
+/* Forks a child that runs fn(arg) and exits with its result. */
+static pid_t spawn(int (*fn)(long), long arg)
+{
+	pid_t pid = fork();
+
+	if (pid == 0) {
+		/* Never outlive the test, whatever the parent does */
+		alarm(30);
+		_exit(fn(arg));
+	}
+	return pid;
+}
+
+/* Returns true if pid is still running, i.e. blocked in the driver. */
+static int running(pid_t pid)
+{
+	return waitpid(pid, NULL, WNOHANG) == 0;
+}
+
+/*
+ * Waits up to CHILD_TIMEOUT_MS for pid and returns its exit status, or -1
+ * after killing it if it did not exit in time or died from a signal.
+ */
+static int reap(pid_t pid)
+{
+	long deadline = now_ms() + CHILD_TIMEOUT_MS;
+	int status;
+
+	while (waitpid(pid, &status, WNOHANG) == 0) {
+		if (now_ms() > deadline) {
+			tap_diag("child %d did not finish", pid);
+			kill(pid, SIGKILL);
+			waitpid(pid, NULL, 0);
+			return -1;
+		}
+		sleep_ms(10);
+	}
+	return WIFEXITED(status) ? WEXITSTATUS(status) : -1;
+}
+
+/* Child: reads once and checks it got the len bytes "hello" starts with. */
+static int read_hello(long len)
+{
+	char buf[16];
+	int fd = open(PIPE_DEVICE, O_RDONLY);
+
+	if (fd < 0)
+		return 2;
+	return read(fd, buf, sizeof(buf)) == len && !memcmp(buf, "hello", len) ? 0 : 1;
+}
+
+/* Child: writes len bytes, which must all go through in one call. */
+static int write_bytes(long len)
+{
+	static char buf[PIPE_RING];
+	int fd = open(PIPE_DEVICE, O_WRONLY);
+
+	if (fd < 0)
+		return 2;
+	return write(fd, buf, len) == len ? 0 : 1;
+}
+
+static enum tap_result blocking_read(void)
+{
+	pid_t reader;
+	int fd;
+
+	CHECK(drain() == 0);
+	fd = open(PIPE_DEVICE, O_WRONLY);
+	CHECK(fd >= 0);
+	reader = spawn(read_hello, 5);
+	CHECK(reader > 0);
+	sleep_ms(SETTLE_MS);
+	/* Nothing buffered, so the reader waits */
+	CHECK(running(reader));
+	CHECK(write(fd, "hello", 5) == 5);
+	CHECK(reap(reader) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result blocking_write(void)
+{
+	static char buf[PIPE_RING];
+	pid_t writer;
+	int fd;
+
+	CHECK(drain() == 0);
+	fd = open(PIPE_DEVICE, O_RDWR);
+	CHECK(fd >= 0);
+	CHECK(write(fd, buf, PIPE_RING) == PIPE_RING);
+	writer = spawn(write_bytes, 100);
+	CHECK(writer > 0);
+	sleep_ms(SETTLE_MS);
+	/* The ring is full, so the writer waits for space */
+	CHECK(running(writer));
+	CHECK(read(fd, buf, 100) == 100);
+	CHECK(reap(writer) == 0);
+	CHECK(read(fd, buf, sizeof(buf)) == PIPE_RING);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result nonblocking(void)
+{
+	static char buf[PIPE_RING + 1000];
+	int rd, wr;
+
+	CHECK(drain() == 0);
+	rd = open(PIPE_DEVICE, O_RDONLY | O_NONBLOCK);
+	wr = open(PIPE_DEVICE, O_WRONLY | O_NONBLOCK);
+	CHECK(rd >= 0 && wr >= 0);
+	CHECK(read(rd, buf, 1) < 0 && errno == EAGAIN);
+	/* A write bigger than the ring goes in partly */
+	CHECK(write(wr, buf, sizeof(buf)) == PIPE_RING);
+	CHECK(write(wr, buf, 1) < 0 && errno == EAGAIN);
+	CHECK(read(rd, buf, sizeof(buf)) == PIPE_RING);
+	CHECK(read(rd, buf, 1) < 0 && errno == EAGAIN);
+	close(wr);
+	close(rd);
+	return TAP_PASS;
+}
+
+static enum tap_result producer_consumer(void)
+{
+	static const long rounds = 50;
+	char buf[PIPE_RING];
+	long got = 0, i;
+	pid_t writer;
+	int fd;
+
+	CHECK(drain() == 0);
+	fd = open(PIPE_DEVICE, O_RDONLY);
+	CHECK(fd >= 0);
+	writer = fork();
+	CHECK(writer >= 0);
+	if (writer == 0) {
+		/* Rounds of a full ring, each byte its index mod 251 */
+		int out = open(PIPE_DEVICE, O_WRONLY);
+		long sent = 0;
+
+		alarm(30);
+		if (out < 0)
+			_exit(2);
+		while (sent < rounds * PIPE_RING) {
+			ssize_t n;
+
+			for (i = 0; i < PIPE_RING; i++)
+				buf[i] = (sent + i) % 251;
+			n = write(out, buf, PIPE_RING);
+			if (n <= 0)
+				_exit(1);
+			sent += n;
+		}
+		_exit(0);
+	}
+
+	/*
+	 * Count rather than read to end of file: a read that starts after the
+	 * writer has gone waits for a new one.
+	 */
+	while (got < rounds * PIPE_RING) {
+		ssize_t n = read(fd, buf, sizeof(buf));
+
+		CHECK(n > 0);
+		for (i = 0; i < n; i++)
+			CHECK(buf[i] == (char)((got + i) % 251));
+		got += n;
+	}
+	CHECK(reap(writer) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+/* Child: writes "abc", then waits to be killed while holding the pipe open. */
+static int write_and_hang(long unused)
+{
+	int fd = open(PIPE_DEVICE, O_WRONLY);
+
+	if (fd < 0 || write(fd, "abc", 3) != 3)
+		return 2;
+	pause();
+	return 1;
+}
+
+/* Child: reads "abc", then expects end of file once the writer is gone. */
+static int read_until_eof(long unused)
+{
+	char buf[8];
+	int fd = open(PIPE_DEVICE, O_RDONLY);
+
+	if (fd < 0)
+		return 2;
+	if (read(fd, buf, sizeof(buf)) != 3 || memcmp(buf, "abc", 3))
+		return 1;
+	return read(fd, buf, sizeof(buf)) == 0 ? 0 : 1;
+}
+
+static enum tap_result eof_on_writer_killed(void)
+{
+	pid_t writer, reader;
+
+	/*
+	 * MiscDevice has no flush hook, so end of file comes from release.
+	 * A killed writer's files are closed on exit, which must wake a
+	 * reader waiting for more.
+	 */
+	CHECK(drain() == 0);
+	writer = spawn(write_and_hang, 0);
+	CHECK(writer > 0);
+	reader = spawn(read_until_eof, 0);
+	CHECK(reader > 0);
+	sleep_ms(SETTLE_MS);
+	CHECK(running(reader));
+	kill(writer, SIGKILL);
+	CHECK(waitpid(writer, NULL, 0) == writer);
+	CHECK(reap(reader) == 0);
+	return TAP_PASS;
+}
+
+static enum tap_result deadline(void)
+{
+	char buf[16];
+	long start, took;
+	int rd, wr;
+
+	CHECK(drain() == 0);
+	rd = open(PIPE_DEVICE, O_RDONLY);
+	wr = open(PIPE_DEVICE, O_WRONLY);
+	CHECK(rd >= 0 && wr >= 0);
+	CHECK(ioctl(rd, SCULL_P_IOCSDEADLINE, 300) == 0);
+
+	/* Nothing arrives: the read gives up at the deadline */
+	start = now_ms();
+	CHECK(read(rd, buf, sizeof(buf)) == 0);
+	took = now_ms() - start;
+	CHECK(took >= 250 && took < CHILD_TIMEOUT_MS);
+
+	/* Some data: the read keeps collecting until the deadline */
+	CHECK(write(wr, "ab", 2) == 2);
+	start = now_ms();
+	CHECK(read(rd, buf, sizeof(buf)) == 2);
+	CHECK(now_ms() - start >= 250);
+
+	/* A full buffer returns at once */
+	CHECK(write(wr, "0123456789abcdef", 16) == 16);
+	start = now_ms();
+	CHECK(read(rd, buf, sizeof(buf)) == 16);
+	CHECK(now_ms() - start < 250);
+	close(wr);
+	close(rd);
+	return TAP_PASS;
+}
+
+static void set_pattern(struct scull_pattern *p, const char *s)
+{
+	memset(p, 0, sizeof(*p));
+	p->len = strlen(s);
+	memcpy(p->pattern, s, p->len);
+}
+
+/* Child: waits for "\n" and expects it arg bytes in. */
+static int wait_newline(long offset)
+{
+	struct scull_pattern p;
+	int fd = open(PIPE_DEVICE, O_RDONLY);
+
+	if (fd < 0)
+		return 2;
+	set_pattern(&p, "\n");
+	return ioctl(fd, SCULL_P_IOCWAITPATTERN, &p) == offset ? 0 : 1;
+}
+
+static enum tap_result wait_pattern(void)
+{
+	static char buf[PIPE_RING];
+	struct scull_pattern p;
+	pid_t waiter;
+	int fd;
+
+	CHECK(drain() == 0);
+	fd = open(PIPE_DEVICE, O_RDWR);
+	CHECK(fd >= 0);
+	waiter = spawn(wait_newline, 5);
+	CHECK(waiter > 0);
+	CHECK(write(fd, "abc", 3) == 3);
+	sleep_ms(SETTLE_MS);
+	/* Rescanned after each write, and still waiting */
+	CHECK(running(waiter));
+	CHECK(write(fd, "de\nfg", 5) == 5);
+	CHECK(reap(waiter) == 0);
+	/* Waiting consumes nothing */
+	CHECK(read(fd, buf, sizeof(buf)) == 8 && !memcmp(buf, "abcde\nfg", 8));
+
+	set_pattern(&p, "");
+	CHECK(ioctl(fd, SCULL_P_IOCWAITPATTERN, &p) < 0 && errno == EINVAL);
+	set_pattern(&p, "end");
+	CHECK(ioctl(fd, SCULL_P_IOCSDEADLINE, 100) == 0);
+	CHECK(ioctl(fd, SCULL_P_IOCWAITPATTERN, &p) < 0 && errno == ETIMEDOUT);
+	CHECK(ioctl(fd, SCULL_P_IOCSDEADLINE, 0) == 0);
+
+	/* A full ring without a match can never match */
+	memset(buf, 'x', sizeof(buf));
+	CHECK(write(fd, buf, PIPE_RING) == PIPE_RING);
+	CHECK(ioctl(fd, SCULL_P_IOCWAITPATTERN, &p) < 0 && errno == ENOBUFS);
+	close(fd);
+
+	CHECK(drain() == 0);
+	fd = open(PIPE_DEVICE, O_RDONLY | O_NONBLOCK);
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, SCULL_P_IOCWAITPATTERN, &p) < 0 && errno == EAGAIN);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result poll_readiness(void)
+{
+	struct pollfd pfd;
+
+	CHECK(drain() == 0);
+	pfd.fd = open(PIPE_DEVICE, O_RDONLY | O_NONBLOCK);
+	CHECK(pfd.fd >= 0);
+	pfd.events = POLLIN;
+	CHECK(poll(&pfd, 1, 0) >= 0);
+	close(pfd.fd);
+	/* Without a poll hook the VFS reports every file ready */
+	if (pfd.revents & POLLIN)
+		SKIP("the driver has no poll hook");
+	return TAP_PASS;
+}
+
+/* Child: blocks in a read, and expects ENODEV from the shutdown. */
+static int read_until_shutdown(long unused)
+{
+	char buf[8];
+	int fd = open(PIPE_DEVICE, O_RDONLY);
+
+	if (fd < 0)
+		return 2;
+	return read(fd, buf, sizeof(buf)) < 0 && errno == ENODEV ? 0 : 1;
+}
+
+/* Child: blocks waiting for a pattern, and expects ENODEV. */
+static int wait_until_shutdown(long unused)
+{
+	struct scull_pattern p;
+	int fd = open(PIPE_DEVICE, O_RDONLY);
+
+	if (fd < 0)
+		return 2;
+	set_pattern(&p, "never");
+	return ioctl(fd, SCULL_P_IOCWAITPATTERN, &p) < 0 && errno == ENODEV ? 0 : 1;
+}
+
+/* Must run last: the pipe stays shut down until the module is reloaded. */
+static enum tap_result shutdown_wakes_waiters(void)
+{
+	char buf[8];
+	pid_t reader, waiter;
+	int fd;
+
+	/*
+	 * A reader and a pattern waiter both wait on an empty ring. A writer
+	 * would need a full one, but blocked writers are woken the same way.
+	 */
+	CHECK(drain() == 0);
+	fd = open(PIPE_DEVICE, O_RDWR);
+	CHECK(fd >= 0);
+	reader = spawn(read_until_shutdown, 0);
+	CHECK(reader > 0);
+	waiter = spawn(wait_until_shutdown, 0);
+	CHECK(waiter > 0);
+	sleep_ms(SETTLE_MS);
+	CHECK(running(reader) && running(waiter));
+
+	CHECK(ioctl(fd, SCULL_IOCSHUTDOWN, 0) == 0);
+	CHECK(reap(reader) == 0);
+	CHECK(reap(waiter) == 0);
+	/* Handles still open fail at once; new opens are refused */
+	CHECK(read(fd, buf, sizeof(buf)) < 0 && errno == ENODEV);
+	CHECK(write(fd, "x", 1) < 0 && errno == ENODEV);
+	CHECK(open(PIPE_DEVICE, O_RDONLY) < 0 && errno == ESHUTDOWN);
+	close(fd);
+	return TAP_PASS;
+}
+
+static const struct tap_test tests[] = {
+	{ "blocking_read", blocking_read },
+	{ "blocking_write", blocking_write },
+	{ "nonblocking", nonblocking },
+	{ "producer_consumer", producer_consumer },
+	{ "eof_on_writer_killed", eof_on_writer_killed },
+	{ "deadline", deadline },
+	{ "wait_pattern", wait_pattern },
+	{ "poll", poll_readiness },
+	{ "shutdown_wakes_waiters", shutdown_wakes_waiters },
+};
+
+int main(int argc, char **argv)
+{
+	int fd = open(PIPE_DEVICE, O_RDONLY | O_NONBLOCK);
+
+	if (fd < 0) {
+		printf("1..0 # SKIP %s: %s\n", PIPE_DEVICE, strerror(errno));
+		return KSFT_SKIP;
+	}
+	close(fd);
+
+	/* A lost wakeup in the parent itself should fail the run, not hang it */
+	alarm(120);
+	return tap_run(tests, ARRAY_SIZE(tests));
+}
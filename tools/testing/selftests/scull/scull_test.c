@@ -0,0 +1,1042 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * Selftests for /dev/scull, run by run_tests.sh with the module loaded.
+ *
+ * Usage: scull_test GROUP. Each group expects the module parameters
+ * run_tests.sh loads it with for that group; see the table at the end.
+ * Tests that need a feature the module was built without, per
+ * SCULL_IOCGVERSION, are skipped.
+ */
+
+#define _GNU_SOURCE
+#include <fcntl.h>
+#include <linux/capability.h>
+#include <signal.h>
+#include <stdint.h>
+#include <stdlib.h>
+#include <sys/ioctl.h>
+#include <sys/syscall.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include "scull.h"
+#include "tap.h"
+
+#define DEVICE		"/dev/scull"
+#define PIPE_DEVICE	"/dev/scullpipe"
+
+/* A user address nothing is mapped at */
+#define BAD_PTR		((unsigned long)8)
+
+/* BAD_PTR as a buffer, hidden from the compiler's bounds checks */
+static void *volatile bad_buf = (void *)BAD_PTR;
+
+static struct scull_version version;
+
+/* Opens /dev/scull with flags and empties it. */
+static int open_empty(int flags)
+{
+	int fd = open(DEVICE, flags);
+
+	if (fd < 0)
+		return -1;
+	if (ioctl(fd, SCULL_IOCRESET, SCULL_RESET_FORCE) < 0) {
+		close(fd);
+		return -1;
+	}
+	return fd;
+}
+
+static int has_feature(__u64 feature)
+{
+	return (version.features & feature) != 0;
+}
+
+/* --- basic: I/O through the file interface --- */
+
+static enum tap_result round_trip(void)
+{
+	static const char hello[] = "hello, world";
+	char buf[64];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, hello, sizeof(hello)) == sizeof(hello));
+	CHECK(pread(fd, buf, sizeof(buf), 0) == sizeof(hello));
+	CHECK(!memcmp(buf, hello, sizeof(hello)));
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result persistence(void)
+{
+	char buf[8];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, "kept", 4) == 4);
+	close(fd);
+
+	fd = open(DEVICE, O_RDONLY);
+	CHECK(fd >= 0);
+	CHECK(read(fd, buf, sizeof(buf)) == 4);
+	CHECK(!memcmp(buf, "kept", 4));
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result multi_quantum(void)
+{
+	static char in[10000], out[10000];
+	struct scull_geometry g;
+	size_t i;
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, SCULL_IOCGGEOMETRY, &g) == 0);
+	CHECK(g.quantum < sizeof(in));
+	for (i = 0; i < sizeof(in); i++)
+		in[i] = i * 7;
+
+	/* One call each way, across quantum boundaries */
+	CHECK(write(fd, in, sizeof(in)) == sizeof(in));
+	CHECK(pread(fd, out, sizeof(out), 0) == sizeof(out));
+	CHECK(!memcmp(in, out, sizeof(in)));
+	/* Starting mid-quantum, the read stops at the end of the data */
+	CHECK(pread(fd, out, sizeof(out), g.quantum - 1) == (ssize_t)(sizeof(in) - g.quantum + 1));
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result sparse_read(void)
+{
+	char buf[8192];
+	int fd = open_empty(O_RDWR);
+	int i;
+
+	CHECK(fd >= 0);
+	CHECK(pwrite(fd, "x", 1, 8000) == 1);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 8001);
+	for (i = 0; i < 8000; i++)
+		CHECK(buf[i] == 0);
+	CHECK(buf[8000] == 'x');
+
+	/* The fill byte can only change while the device is empty */
+	CHECK(ioctl(fd, SCULL_IOCSFILL, '.') < 0 && errno == EBUSY);
+	CHECK(ioctl(fd, SCULL_IOCRESET, 0) == 0);
+	CHECK(ioctl(fd, SCULL_IOCSFILL, '.') == 0);
+	CHECK(ioctl(fd, SCULL_IOCEXTEND, 8) == 8);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 8 && !memcmp(buf, "........", 8));
+	CHECK(ioctl(fd, SCULL_IOCRESET, 0) == 0);
+	CHECK(ioctl(fd, SCULL_IOCSFILL, 0) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result file_position(void)
+{
+	char buf[8];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, "abcdef", 6) == 6);
+	/* The position is past the data: end of file */
+	CHECK(read(fd, buf, sizeof(buf)) == 0);
+	CHECK(pread(fd, buf, 3, 2) == 3 && !memcmp(buf, "cde", 3));
+	/* pread left the position alone */
+	CHECK(write(fd, "g", 1) == 1);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 7 && !memcmp(buf, "abcdefg", 7));
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result two_handles(void)
+{
+	char buf[8];
+	int a = open_empty(O_RDWR);
+	int b = open(DEVICE, O_RDWR);
+
+	/* Each open has its own position into the shared data */
+	CHECK(a >= 0 && b >= 0);
+	CHECK(write(a, "0123456789", 10) == 10);
+	CHECK(read(b, buf, 3) == 3 && !memcmp(buf, "012", 3));
+	CHECK(read(a, buf, sizeof(buf)) == 0);
+	CHECK(read(b, buf, 3) == 3 && !memcmp(buf, "345", 3));
+	/* Writing through b moves only b */
+	CHECK(write(b, "xy", 2) == 2);
+	CHECK(read(b, buf, 3) == 2 && !memcmp(buf, "89", 2));
+	CHECK(write(a, "!", 1) == 1);
+	CHECK(pread(a, buf, 8, 3) == 8 && !memcmp(buf, "345xy89!", 8));
+	close(b);
+	close(a);
+	return TAP_PASS;
+}
+
+static enum tap_result llseek(void)
+{
+	char buf[4];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, "abcdef", 6) == 6);
+	if (lseek(fd, 2, SEEK_SET) < 0 && errno == ESPIPE) {
+		close(fd);
+		SKIP("the driver has no llseek");
+	}
+	CHECK(read(fd, buf, 2) == 2 && !memcmp(buf, "cd", 2));
+	CHECK(lseek(fd, -1, SEEK_CUR) == 3);
+	CHECK(read(fd, buf, 1) == 1 && buf[0] == 'd');
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result o_append(void)
+{
+	char buf[8];
+	int fd = open_empty(O_RDWR);
+	int a, b;
+
+	CHECK(fd >= 0);
+	a = open(DEVICE, O_WRONLY | O_APPEND);
+	b = open(DEVICE, O_WRONLY | O_APPEND);
+	CHECK(a >= 0 && b >= 0);
+	/* Each append lands at the end, wherever the other left it */
+	CHECK(write(a, "ab", 2) == 2);
+	CHECK(write(b, "cd", 2) == 2);
+	CHECK(write(a, "ef", 2) == 2);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 6 && !memcmp(buf, "abcdef", 6));
+	close(a);
+	close(b);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result o_trunc(void)
+{
+	char buf[8];
+	int fd = open_empty(O_RDWR);
+	int t;
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, "data", 4) == 4);
+	/* The VFS truncates only regular files; the contents survive */
+	t = open(DEVICE, O_WRONLY | O_TRUNC);
+	CHECK(t >= 0);
+	close(t);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 4);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result o_nonblock(void)
+{
+	char buf[8];
+	int fd = open_empty(O_RDWR | O_NONBLOCK);
+
+	/* Nothing on /dev/scull ever waits, so nothing fails with EAGAIN */
+	CHECK(fd >= 0);
+	CHECK(read(fd, buf, sizeof(buf)) == 0);
+	CHECK(write(fd, "x", 1) == 1);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 1);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result backend(void)
+{
+	const char *want = getenv("SCULL_BACKEND");
+	long got;
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	if (version.minor < 1) {
+		close(fd);
+		SKIP("SCULL_IOCGBACKEND needs interface 1.1");
+	}
+	got = ioctl(fd, SCULL_IOCGBACKEND, 0);
+	CHECK(got == (want && !strcmp(want, "flat") ? SCULL_BACKEND_FLAT : SCULL_BACKEND_QSET));
+	close(fd);
+	return TAP_PASS;
+}
+
+/* --- ioctl: every command's normal behaviour --- */
+
+static enum tap_result version_info(void)
+{
+	CHECK(version.major == SCULL_VERSION_MAJOR);
+	return TAP_PASS;
+}
+
+static enum tap_result getters(void)
+{
+	static const unsigned long value_cmds[] = {
+		SCULL_IOCGWRITEONCE, SCULL_IOCGFILL, SCULL_IOCGAPPENDONLY,
+		SCULL_IOCGMODE, SCULL_IOCGSENTINEL, SCULL_IOCGALIGN,
+		SCULL_IOCGMAXNODES, SCULL_IOCGNODES, SCULL_IOCGGFP,
+		SCULL_IOCGRECORD, SCULL_IOCGMAXOPENS,
+	};
+	struct scull_stats st = { .size = sizeof(st) };
+	struct scull_fill_stats fst = { .size = sizeof(fst) };
+	struct scull_writer w;
+	struct scull_geometry g;
+	struct scull_sizes sz;
+	char label[SCULL_LABEL_LEN];
+	__u64 val;
+	size_t i;
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	for (i = 0; i < ARRAY_SIZE(value_cmds); i++)
+		CHECK(ioctl(fd, value_cmds[i], 0) >= 0);
+	CHECK(ioctl(fd, SCULL_IOCGSTATS, &st) == 0 && st.open_count >= 1);
+	CHECK(ioctl(fd, SCULL_IOCGFILLSTATS, &fst) == 0 && fst.quanta == 0);
+	CHECK(ioctl(fd, SCULL_IOCGLASTWRITER, &w) == 0);
+	CHECK(ioctl(fd, SCULL_IOCGFOOTPRINT, &val) == 0);
+	CHECK(ioctl(fd, SCULL_IOCGGEN, &val) == 0);
+	CHECK(ioctl(fd, SCULL_IOCGGEOMETRY, &g) == 0 && g.quantum && g.qset);
+	CHECK(ioctl(fd, SCULL_IOCGSIZES, &sz) == 0 && sz.qset);
+	CHECK(ioctl(fd, SCULL_IOCGCREATED, &val) == 0 && val);
+	CHECK(ioctl(fd, SCULL_IOCGMAXGAP, &val) == 0);
+	CHECK(ioctl(fd, SCULL_IOCGRUNNINGCRC, &val) == 0);
+	CHECK(ioctl(fd, SCULL_IOCGLABEL, label) == 0);
+	if (has_feature(SCULL_FEAT_MIGRATE))
+		CHECK(ioctl(fd, SCULL_IOCGMIGRATE, 0) == 100);
+	close(fd);
+	return TAP_PASS;
+}
+
+/* Sets cmd to val, checks get returns it, then sets it back to restore. */
+static int round_trip_value(int fd, unsigned long set, unsigned long get,
+			    unsigned long val, unsigned long restore)
+{
+	if (ioctl(fd, set, val) < 0 || ioctl(fd, get, 0) != (long)val)
+		return 0;
+	return ioctl(fd, set, restore) == 0;
+}
+
+static enum tap_result setters(void)
+{
+	struct scull_geometry g = { .quantum = 512, .qset = 8 }, old;
+	char label[SCULL_LABEL_LEN] = "selftest", back[SCULL_LABEL_LEN];
+	__u64 gap = 4096, val;
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(round_trip_value(fd, SCULL_IOCSFILL, SCULL_IOCGFILL, 'z', 0));
+	CHECK(round_trip_value(fd, SCULL_IOCSSENTINEL, SCULL_IOCGSENTINEL, '\n',
+			       SCULL_SENTINEL_NONE));
+	CHECK(round_trip_value(fd, SCULL_IOCSALIGN, SCULL_IOCGALIGN, 8, 0));
+	CHECK(round_trip_value(fd, SCULL_IOCSRECORD, SCULL_IOCGRECORD, 16, 0));
+	CHECK(round_trip_value(fd, SCULL_IOCSMAXNODES, SCULL_IOCGMAXNODES, 5, 0));
+	CHECK(round_trip_value(fd, SCULL_IOCSGFP, SCULL_IOCGGFP, SCULL_GFP_ATOMIC,
+			       SCULL_GFP_KERNEL));
+	CHECK(round_trip_value(fd, SCULL_IOCSMAXOPENS, SCULL_IOCGMAXOPENS, 9, 0));
+	CHECK(round_trip_value(fd, SCULL_IOCSMODE, SCULL_IOCGMODE, SCULL_MODE_READ,
+			       SCULL_MODE_READ | SCULL_MODE_WRITE));
+	CHECK(round_trip_value(fd, SCULL_IOCSAPPENDONLY, SCULL_IOCGAPPENDONLY, 1, 0));
+	CHECK(round_trip_value(fd, SCULL_IOCSWRITEONCE, SCULL_IOCGWRITEONCE, 1, 0));
+	CHECK(ioctl(fd, SCULL_IOCSCHUNKED, 0) == 0);
+	CHECK(ioctl(fd, SCULL_IOCSUIDQUOTA, 0) == 0);
+	CHECK(ioctl(fd, SCULL_IOCSLOGGING, 1) == 0 || !has_feature(SCULL_FEAT_LOG));
+
+	CHECK(ioctl(fd, SCULL_IOCSMAXGAP, &gap) == 0);
+	CHECK(ioctl(fd, SCULL_IOCGMAXGAP, &val) == 0 && val == gap);
+	gap = SCULL_GAP_UNLIMITED;
+	CHECK(ioctl(fd, SCULL_IOCSMAXGAP, &gap) == 0);
+
+	CHECK(ioctl(fd, SCULL_IOCSLABEL, label) == 0);
+	CHECK(ioctl(fd, SCULL_IOCGLABEL, back) == 0 && !strcmp(back, "selftest"));
+	memset(label, 0, sizeof(label));
+	CHECK(ioctl(fd, SCULL_IOCSLABEL, label) == 0);
+
+	CHECK(ioctl(fd, SCULL_IOCGGEOMETRY, &old) == 0);
+	CHECK(ioctl(fd, SCULL_IOCSGEOMETRY, &g) == 0);
+	CHECK(ioctl(fd, SCULL_IOCGGEOMETRY, &g) == 0 && g.quantum == 512 && g.qset == 8);
+	CHECK(ioctl(fd, SCULL_IOCSGEOMETRY, &old) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result geometry_busy(void)
+{
+	struct scull_geometry g, old;
+	char buf[8];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, SCULL_IOCGGEOMETRY, &old) == 0);
+	CHECK(write(fd, "data", 4) == 4);
+	g = old;
+	g.quantum = old.quantum / 2;
+	CHECK(ioctl(fd, SCULL_IOCSGEOMETRY, &g) < 0 && errno == EBUSY);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 4 && !memcmp(buf, "data", 4));
+
+	/* REQUANTUM keeps the data */
+	CHECK(ioctl(fd, SCULL_IOCREQUANTUM, &g) == 0);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 4 && !memcmp(buf, "data", 4));
+
+	/* FORCE empties the device */
+	old.flags = SCULL_GEOMETRY_FORCE;
+	CHECK(ioctl(fd, SCULL_IOCSGEOMETRY, &old) == 0);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result protect(void)
+{
+	struct scull_range r = { .offset = 4, .len = 4 };
+	char buf[16];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, "0123456789ab", 12) == 12);
+	CHECK(ioctl(fd, SCULL_IOCPROTECT, &r) == 0);
+	CHECK(pwrite(fd, "xx", 2, 2) == 2);
+	CHECK(pwrite(fd, "yy", 2, 8) == 2);
+	CHECK(pwrite(fd, "zz", 2, 3) < 0 && errno == EPERM);
+	CHECK(pwrite(fd, "zz", 2, 7) < 0 && errno == EPERM);
+	CHECK(ioctl(fd, SCULL_IOCRESET, 0) < 0 && errno == EPERM);
+	CHECK(ioctl(fd, SCULL_IOCUNPROTECT, &r) == 0);
+	CHECK(pwrite(fd, "4567", 4, 4) == 4);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 12 && !memcmp(buf, "01xx4567yyab", 12));
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result write_once(void)
+{
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, SCULL_IOCSWRITEONCE, 1) == 0);
+	CHECK(write(fd, "once", 4) == 4);
+	CHECK(pwrite(fd, "x", 1, 0) < 0 && errno == EPERM);
+	CHECK(pwrite(fd, "more", 4, 4) == 4);
+	CHECK(ioctl(fd, SCULL_IOCSWRITEONCE, 0) == 0);
+	CHECK(pwrite(fd, "x", 1, 0) == 1);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result range_locks(void)
+{
+	struct scull_lock_req req = {
+		.offset = 0, .len = 10, .flags = SCULL_LOCK_EXCLUSIVE,
+	};
+	int fd = open_empty(O_RDWR);
+	int other = open(DEVICE, O_RDWR);
+
+	CHECK(fd >= 0 && other >= 0);
+	CHECK(ioctl(fd, SCULL_IOCLOCK, &req) == 0);
+	req.flags |= SCULL_LOCK_NONBLOCK;
+	req.offset = 5;
+	CHECK(ioctl(other, SCULL_IOCLOCK, &req) < 0 && errno == EAGAIN);
+	req.offset = 10;
+	CHECK(ioctl(other, SCULL_IOCLOCK, &req) == 0);
+	CHECK(ioctl(other, SCULL_IOCUNLOCK, &req) == 0);
+	/* Closing drops the holder's locks */
+	close(fd);
+	req.offset = 0;
+	CHECK(ioctl(other, SCULL_IOCLOCK, &req) == 0);
+	close(other);
+	return TAP_PASS;
+}
+
+static enum tap_result reshaping(void)
+{
+	struct scull_geometry g;
+	struct scull_check chk = { 0 };
+	char buf[16];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, "0123456789", 10) == 10);
+	CHECK(ioctl(fd, SCULL_IOCGGEOMETRY, &g) == 0);
+	g.quantum = 3;
+	g.qset = 2;
+	CHECK(ioctl(fd, SCULL_IOCREQUANTUM, &g) == 0);
+	CHECK(ioctl(fd, SCULL_IOCGNODES, 0) == 2);
+	CHECK(ioctl(fd, SCULL_IOCDISCARDFRONT, 4) == 6);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 6 && !memcmp(buf, "456789", 6));
+	CHECK(ioctl(fd, SCULL_IOCCOMPACT, 0) >= 0);
+	CHECK(ioctl(fd, SCULL_IOCCHECK, &chk) == 0 && chk.code == 0);
+	/* Dropping the data keeps the size; it reads back as the fill byte */
+	CHECK(ioctl(fd, SCULL_IOCDROPCACHE, 0) == 0);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 6 && !memcmp(buf, "\0\0\0\0\0\0", 6));
+	g.flags = SCULL_GEOMETRY_FORCE;
+	g.quantum = 4000;
+	g.qset = 1000;
+	CHECK(ioctl(fd, SCULL_IOCSGEOMETRY, &g) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result peek(void)
+{
+	char buf[16];
+	struct scull_peek p = { .offset = 5, .buf = (unsigned long)buf, .len = sizeof(buf) };
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(pwrite(fd, "abcdefgh", 8, 0) == 8);
+	CHECK(read(fd, buf, 2) == 2);
+	/* Stops at the end of the data */
+	CHECK(ioctl(fd, SCULL_IOCPEEK, &p) == 3 && !memcmp(buf, "fgh", 3));
+	p.offset = 8;
+	CHECK(ioctl(fd, SCULL_IOCPEEK, &p) == 0);
+	/* and leaves the file position where read() left it */
+	CHECK(read(fd, buf, 2) == 2 && !memcmp(buf, "cd", 2));
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result read_ex(void)
+{
+	struct scull_read_ex rx = { 0 };
+	char buf[8];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, "abcdefgh", 8) == 8);
+	rx.offset = 2;
+	rx.len = 4;
+	rx.buf = (unsigned long)buf;
+	CHECK(ioctl(fd, SCULL_IOCREADEX, &rx) == 4);
+	CHECK(rx.copied == 4 && rx.more == 1 && !memcmp(buf, "cdef", 4));
+	rx.len = 8;
+	CHECK(ioctl(fd, SCULL_IOCREADEX, &rx) == 6 && rx.more == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result extent_map(void)
+{
+	struct scull_extent ext[4];
+	struct scull_fiemap fm = { 0 };
+	int fd;
+
+	if (!has_feature(SCULL_FEAT_FIEMAP))
+		SKIP("built without SCULL_IOCFIEMAP");
+	fd = open_empty(O_RDWR);
+	CHECK(fd >= 0);
+	CHECK(pwrite(fd, "x", 1, 0) == 1);
+	CHECK(pwrite(fd, "y", 1, 100000) == 1);
+	fm.buf = (unsigned long)ext;
+	fm.count = ARRAY_SIZE(ext);
+	CHECK(ioctl(fd, SCULL_IOCFIEMAP, &fm) == 2);
+	CHECK(ext[0].logical_offset == 0 && ext[1].logical_offset > 0);
+	CHECK(ext[1].flags & SCULL_EXTENT_LAST);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result op_log(void)
+{
+	struct scull_log_entry entries[4];
+	struct scull_log_req req = {
+		.buf = (unsigned long)entries, .count = ARRAY_SIZE(entries),
+	};
+	int fd, i, n;
+
+	if (!has_feature(SCULL_FEAT_LOG))
+		SKIP("built without the operation log");
+	fd = open_empty(O_RDWR);
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, SCULL_IOCSLOGGING, 1) == 0);
+	CHECK(ioctl(fd, SCULL_IOCCLEARLOG, 0) == 0);
+	CHECK(write(fd, "abc", 3) == 3);
+	n = ioctl(fd, SCULL_IOCGETLOG, &req);
+	CHECK(n >= 1);
+	/* Newest first, and ioctls are logged too */
+	for (i = 0; i < n; i++)
+		if (entries[i].op == SCULL_LOG_WRITE)
+			break;
+	CHECK(i < n && entries[i].result == 3 && entries[i].pid == getpid());
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result fork_device(void)
+{
+	char name[32], buf[8];
+	long n;
+	int fd, copy;
+
+	if (!has_feature(SCULL_FEAT_FORK))
+		SKIP("built without SCULL_IOCFORK");
+	fd = open_empty(O_RDWR);
+	CHECK(fd >= 0);
+	CHECK(write(fd, "forked", 6) == 6);
+	n = ioctl(fd, SCULL_IOCFORK, SCULL_FORK_SAME);
+	CHECK(n >= 0);
+	snprintf(name, sizeof(name), "/dev/scull_dyn%ld", n);
+	copy = open(name, O_RDONLY);
+	CHECK(copy >= 0);
+	CHECK(read(copy, buf, sizeof(buf)) == 6 && !memcmp(buf, "forked", 6));
+	close(copy);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result migrate(void)
+{
+	char buf[16];
+	int fd, tries;
+
+	if (!has_feature(SCULL_FEAT_MIGRATE))
+		SKIP("built without SCULL_IOCMIGRATE");
+	fd = open_empty(O_RDWR);
+	CHECK(fd >= 0);
+	CHECK(write(fd, "migrating", 9) == 9);
+	CHECK(ioctl(fd, SCULL_IOCMIGRATE, 2) == 0);
+	for (tries = 0; ioctl(fd, SCULL_IOCGMIGRATE, 0) != 100; tries++) {
+		CHECK(tries < 1000);
+		usleep(1000);
+	}
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 9 && !memcmp(buf, "migrating", 9));
+	CHECK(ioctl(fd, SCULL_IOCGNODES, 0) == 1);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result list_devices(void)
+{
+	struct scull_dev_info info[16];
+	struct scull_list_req req = {
+		.buf = (unsigned long)info, .count = ARRAY_SIZE(info),
+	};
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, SCULL_IOCLIST, &req) >= 1);
+	CHECK(!strcmp((char *)info[0].name, "scull"));
+	close(fd);
+	return TAP_PASS;
+}
+
+/* --- errors: the failure paths of the interface --- */
+
+static enum tap_result bad_pointers(void)
+{
+	struct scull_peek p = { .offset = 0, .len = 4, .buf = BAD_PTR };
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, "data", 4) == 4);
+	CHECK(ioctl(fd, SCULL_IOCGGEOMETRY, BAD_PTR) < 0 && errno == EFAULT);
+	CHECK(ioctl(fd, SCULL_IOCSGEOMETRY, BAD_PTR) < 0 && errno == EFAULT);
+	CHECK(ioctl(fd, SCULL_IOCPEEK, &p) < 0 && errno == EFAULT);
+	CHECK(pread(fd, bad_buf, 4, 0) < 0 && errno == EFAULT);
+	CHECK(pwrite(fd, bad_buf, 4, 0) < 0 && errno == EFAULT);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result bad_commands(void)
+{
+	struct scull_geometry g;
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, _IO('x', 0), 0) < 0 && errno == ENOTTY);
+	CHECK(ioctl(fd, _IO(SCULL_IOC_MAGIC, 200), 0) < 0 && errno == ENOTTY);
+	/* The right number with the wrong size */
+	CHECK(ioctl(fd, _IOR(SCULL_IOC_MAGIC, 42, __u32), &g) < 0 && errno == ENOTTY);
+	/* Pipe commands on the plain device */
+	CHECK(ioctl(fd, SCULL_P_IOCSDEADLINE, 10) < 0 && errno == ENOTTY);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result bad_arguments(void)
+{
+	struct scull_geometry g = { .quantum = 0, .qset = 1 };
+	struct scull_stats st = { .size = SCULL_STATS_SIZE_VER0 - 1 };
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, SCULL_IOCSFILL, 256) < 0 && errno == EINVAL);
+	CHECK(ioctl(fd, SCULL_IOCSALIGN, 3) < 0 && errno == EINVAL);
+	CHECK(ioctl(fd, SCULL_IOCSMODE, 1 << 8) < 0 && errno == EINVAL);
+	CHECK(ioctl(fd, SCULL_IOCSGEOMETRY, &g) < 0 && errno == EINVAL);
+	CHECK(ioctl(fd, SCULL_IOCGSTATS, &st) < 0 && errno == EINVAL);
+	close(fd);
+	return TAP_PASS;
+}
+
+/* Drops CAP_SYS_ADMIN from this process's effective set. */
+static int drop_sys_admin(void)
+{
+	struct __user_cap_header_struct hdr = { .version = _LINUX_CAPABILITY_VERSION_3 };
+	struct __user_cap_data_struct data[_LINUX_CAPABILITY_U32S_3];
+
+	if (syscall(SYS_capget, &hdr, data))
+		return -1;
+	data[CAP_TO_INDEX(CAP_SYS_ADMIN)].effective &= ~CAP_TO_MASK(CAP_SYS_ADMIN);
+	return syscall(SYS_capset, &hdr, data);
+}
+
+static enum tap_result unprivileged_setters(void)
+{
+	static const unsigned long cmds[] = {
+		SCULL_IOCSMAXNODES, SCULL_IOCSUIDQUOTA, SCULL_IOCSMODE,
+		SCULL_IOCSAPPENDONLY, SCULL_IOCSMAXOPENS, SCULL_IOCSHUTDOWN,
+	};
+	struct scull_range r = { .offset = 0, .len = 4 };
+	int fd = open_empty(O_RDWR);
+	int status;
+	size_t i;
+	pid_t pid;
+
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, SCULL_IOCPROTECT, &r) == 0);
+	CHECK(ioctl(fd, SCULL_IOCSWRITEONCE, 1) == 0);
+
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		if (drop_sys_admin())
+			_exit(2);
+		for (i = 0; i < ARRAY_SIZE(cmds); i++)
+			if (ioctl(fd, cmds[i], 1) == 0 || errno != EPERM)
+				_exit(1);
+		if (ioctl(fd, SCULL_IOCSWRITEONCE, 0) == 0 || errno != EPERM)
+			_exit(1);
+		if (ioctl(fd, SCULL_IOCUNPROTECT, &r) == 0 || errno != EPERM)
+			_exit(1);
+		/* Turning protections on needs no privilege */
+		if (ioctl(fd, SCULL_IOCPROTECT, &r) < 0)
+			_exit(1);
+		_exit(0);
+	}
+	CHECK(waitpid(pid, &status, 0) == pid);
+	CHECK(WIFEXITED(status) && WEXITSTATUS(status) == 0);
+
+	CHECK(ioctl(fd, SCULL_IOCGWRITEONCE, 0) == 1);
+	CHECK(ioctl(fd, SCULL_IOCSWRITEONCE, 0) == 0);
+	CHECK(ioctl(fd, SCULL_IOCUNPROTECT, &r) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result max_opens(void)
+{
+	int fd = open_empty(O_RDWR);
+	int second;
+
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, SCULL_IOCSMAXOPENS, 1) == 0);
+	CHECK(open(DEVICE, O_RDONLY) < 0 && errno == EBUSY);
+	CHECK(ioctl(fd, SCULL_IOCSMAXOPENS, 2) == 0);
+	second = open(DEVICE, O_RDONLY);
+	CHECK(second >= 0);
+	close(second);
+	CHECK(ioctl(fd, SCULL_IOCSMAXOPENS, 0) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result read_only_mode(void)
+{
+	char buf[4];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, "ro", 2) == 2);
+	CHECK(ioctl(fd, SCULL_IOCSMODE, SCULL_MODE_READ) == 0);
+	CHECK(pwrite(fd, "x", 1, 0) < 0 && errno == EACCES);
+	CHECK(ioctl(fd, SCULL_IOCRESET, 0) < 0 && errno == EACCES);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 2);
+	CHECK(ioctl(fd, SCULL_IOCSMODE, SCULL_MODE_READ | SCULL_MODE_WRITE) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+/* --- limits: loaded with max_nodes=2 quantum=4 qset=2 --- */
+
+static enum tap_result node_limit(void)
+{
+	char buf[16];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, SCULL_IOCGMAXNODES, 0) == 2);
+	/* Two nodes of 8 bytes, then writes are cut short at the end */
+	CHECK(write(fd, "0123456789", 10) == 10);
+	CHECK(pwrite(fd, "efgh", 4, 14) == 2);
+	CHECK(pwrite(fd, "x", 1, 16) < 0 && errno == EFBIG);
+	CHECK(ioctl(fd, SCULL_IOCGNODES, 0) == 2);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 16);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result chunked_reads(void)
+{
+	char buf[16];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, "0123456789", 10) == 10);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 10);
+	CHECK(ioctl(fd, SCULL_IOCSCHUNKED, 1) == 0);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 4);
+	CHECK(pread(fd, buf, sizeof(buf), 6) == 2);
+	CHECK(ioctl(fd, SCULL_IOCSCHUNKED, 0) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+static enum tap_result max_gap(void)
+{
+	__u64 gap = 8;
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(write(fd, "abcd", 4) == 4);
+	CHECK(ioctl(fd, SCULL_IOCSMAXGAP, &gap) == 0);
+	CHECK(pwrite(fd, "x", 1, 13) < 0 && errno == EINVAL);
+	CHECK(pwrite(fd, "y", 1, 12) == 1);
+	gap = SCULL_GAP_UNLIMITED;
+	CHECK(ioctl(fd, SCULL_IOCSMAXGAP, &gap) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+/* --- appendonly: loaded with append_only=1 --- */
+
+static enum tap_result append_only(void)
+{
+	char buf[16];
+	int fd = open(DEVICE, O_RDWR);
+	int app = open(DEVICE, O_WRONLY | O_APPEND);
+
+	/* The module was just loaded, so the device starts out empty */
+	CHECK(fd >= 0 && app >= 0);
+	CHECK(ioctl(fd, SCULL_IOCGAPPENDONLY, 0) == 1);
+	CHECK(write(app, "abc", 3) == 3);
+	/* Nothing can be removed or rewritten */
+	CHECK(ioctl(fd, SCULL_IOCRESET, 0) < 0 && errno == EPERM);
+	CHECK(pwrite(fd, "x", 1, 0) < 0 && errno == EPERM);
+	CHECK(pwrite(fd, "x", 1, 4) < 0 && errno == EPERM);
+	/* but writing exactly at the end is an append */
+	CHECK(pwrite(fd, "def", 3, 3) == 3);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 6 && !memcmp(buf, "abcdef", 6));
+	close(app);
+	close(fd);
+	return TAP_PASS;
+}
+
+/* --- versions: loaded with keep_versions=2 --- */
+
+static enum tap_result commit_rollback(void)
+{
+	char buf[8];
+	int fd;
+
+	if (!has_feature(SCULL_FEAT_VERSIONS))
+		SKIP("built or loaded without versions");
+	fd = open_empty(O_RDWR);
+	CHECK(fd >= 0);
+	CHECK(write(fd, "v1", 2) == 2);
+	CHECK(ioctl(fd, SCULL_IOCCOMMIT, 0) == 0);
+	CHECK(pwrite(fd, "v2!", 3, 0) == 3);
+	CHECK(ioctl(fd, SCULL_IOCROLLBACK, 0) == 0);
+	CHECK(pread(fd, buf, sizeof(buf), 0) == 2 && !memcmp(buf, "v1", 2));
+	close(fd);
+	return TAP_PASS;
+}
+
+/* --- quota: loaded with uid_quota=8000 quantum=4000 --- */
+
+static enum tap_result uid_quota(void)
+{
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(pwrite(fd, "a", 1, 0) == 1);
+	CHECK(pwrite(fd, "b", 1, 4000) == 1);
+	CHECK(pwrite(fd, "c", 1, 8000) < 0 && errno == EDQUOT);
+	/* Emptying the device gives the quota back */
+	CHECK(ioctl(fd, SCULL_IOCRESET, 0) == 0);
+	CHECK(pwrite(fd, "c", 1, 8000) == 1);
+	close(fd);
+	return TAP_PASS;
+}
+
+/* --- pipe: a producer and a consumer on /dev/scullpipe --- */
+
+static enum tap_result producer_consumer(void)
+{
+	static char buf[20000];
+	size_t got = 0, i;
+	int status, fd;
+	pid_t pid;
+
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		/* Five times the ring, so the producer has to wait for space */
+		int out = open(PIPE_DEVICE, O_WRONLY);
+
+		if (out < 0)
+			_exit(1);
+		for (i = 0; i < sizeof(buf); i++)
+			buf[i] = i % 251;
+		for (i = 0; i < sizeof(buf); ) {
+			ssize_t n = write(out, buf + i, sizeof(buf) - i);
+
+			if (n <= 0)
+				_exit(1);
+			i += n;
+		}
+		_exit(0);
+	}
+
+	fd = open(PIPE_DEVICE, O_RDONLY);
+	CHECK(fd >= 0);
+	memset(buf, 0, sizeof(buf));
+	while (got < sizeof(buf)) {
+		ssize_t n = read(fd, buf + got, sizeof(buf) - got);
+
+		CHECK(n > 0);
+		got += n;
+	}
+	for (i = 0; i < sizeof(buf); i++)
+		CHECK(buf[i] == (char)(i % 251));
+	CHECK(waitpid(pid, &status, 0) == pid);
+	CHECK(WIFEXITED(status) && WEXITSTATUS(status) == 0);
+	close(fd);
+	return TAP_PASS;
+}
+
+/* --- shutdown: must run last, since it lasts until the module reloads --- */
+
+static enum tap_result shutdown_device(void)
+{
+	char buf[4];
+	int fd = open_empty(O_RDWR);
+
+	CHECK(fd >= 0);
+	CHECK(ioctl(fd, SCULL_IOCSHUTDOWN, 0) == 0);
+	CHECK(read(fd, buf, sizeof(buf)) < 0 && errno == ENODEV);
+	CHECK(write(fd, "x", 1) < 0 && errno == ENODEV);
+	CHECK(ioctl(fd, SCULL_IOCGMODE, 0) < 0 && errno == ENODEV);
+	CHECK(open(DEVICE, O_RDWR) < 0 && errno == ESHUTDOWN);
+	close(fd);
+	return TAP_PASS;
+}
+
+static const struct tap_test basic_tests[] = {
+	{ "round_trip", round_trip },
+	{ "persistence", persistence },
+	{ "multi_quantum", multi_quantum },
+	{ "sparse_read", sparse_read },
+	{ "file_position", file_position },
+	{ "two_handles", two_handles },
+	{ "llseek", llseek },
+	{ "o_append", o_append },
+	{ "o_trunc", o_trunc },
+	{ "o_nonblock", o_nonblock },
+	{ "backend", backend },
+};
+
+static const struct tap_test ioctl_tests[] = {
+	{ "version", version_info },
+	{ "getters", getters },
+	{ "setters", setters },
+	{ "geometry_busy", geometry_busy },
+	{ "protect", protect },
+	{ "write_once", write_once },
+	{ "range_locks", range_locks },
+	{ "reshaping", reshaping },
+	{ "peek", peek },
+	{ "read_ex", read_ex },
+	{ "extent_map", extent_map },
+	{ "op_log", op_log },
+	{ "fork_device", fork_device },
+	{ "migrate", migrate },
+	{ "list_devices", list_devices },
+};
+
+static const struct tap_test error_tests[] = {
+	{ "bad_pointers", bad_pointers },
+	{ "bad_commands", bad_commands },
+	{ "bad_arguments", bad_arguments },
+	{ "unprivileged_setters", unprivileged_setters },
+	{ "max_opens", max_opens },
+	{ "read_only_mode", read_only_mode },
+};
+
+static const struct tap_test limit_tests[] = {
+	{ "node_limit", node_limit },
+	{ "chunked_reads", chunked_reads },
+	{ "max_gap", max_gap },
+};
+
+static const struct tap_test append_only_tests[] = {
+	{ "append_only", append_only },
+};
+
+static const struct tap_test version_tests[] = {
+	{ "commit_rollback", commit_rollback },
+};
+
+static const struct tap_test quota_tests[] = {
+	{ "uid_quota", uid_quota },
+};
+
+static const struct tap_test pipe_tests[] = {
+	{ "producer_consumer", producer_consumer },
+};
+
+static const struct tap_test shutdown_tests[] = {
+	{ "shutdown", shutdown_device },
+};
+
+#define GROUP(name, tests) { name, tests, ARRAY_SIZE(tests) }
+
+static const struct group {
+	const char *name;
+	const struct tap_test *tests;
+	int count;
+} groups[] = {
+	GROUP("basic", basic_tests),
+	GROUP("ioctl", ioctl_tests),
+	GROUP("errors", error_tests),
+	GROUP("limits", limit_tests),
+	GROUP("appendonly", append_only_tests),
+	GROUP("versions", version_tests),
+	GROUP("quota", quota_tests),
+	GROUP("pipe", pipe_tests),
+	GROUP("shutdown", shutdown_tests),
+};
+
+int main(int argc, char **argv)
+{
+	size_t i;
+	int fd;
+
+	if (argc != 2) {
+		fprintf(stderr, "usage: %s GROUP\n", argv[0]);
+		return KSFT_FAIL;
+	}
+
+	fd = open(DEVICE, O_RDONLY);
+	if (fd < 0 || ioctl(fd, SCULL_IOCGVERSION, &version) < 0) {
+		printf("1..0 # SKIP %s: %s\n", DEVICE, strerror(errno));
+		return KSFT_SKIP;
+	}
+	close(fd);
+
+	/* A lost wakeup should fail the run, not hang it */
+	alarm(120);
+
+	for (i = 0; i < ARRAY_SIZE(groups); i++)
+		if (!strcmp(argv[1], groups[i].name))
+			return tap_run(groups[i].tests, groups[i].count);
+
+	fprintf(stderr, "%s: unknown group %s\n", argv[0], argv[1]);
+	return KSFT_FAIL;
+}
@@ -0,0 +1,107 @@
+/* SPDX-License-Identifier: GPL-2.0 */
+/*
+ * Minimal TAP output for the scull selftests, following the kselftest
+ * conventions: one "ok"/"not ok" line per test, diagnostics prefixed with
+ * "# ", and exit code 4 when everything was skipped.
+ *
+ * A test function returns TAP_PASS, TAP_FAIL or TAP_SKIP. CHECK() fails
+ * the test at the first false condition, logging it with errno.
+ */
+#ifndef SCULL_TAP_H
+#define SCULL_TAP_H
+
+#include <errno.h>
+#include <stdarg.h>
+#include <stdio.h>
+#include <string.h>
+
+#define KSFT_PASS	0
+#define KSFT_FAIL	1
+#define KSFT_SKIP	4
+
+enum tap_result { TAP_PASS, TAP_FAIL, TAP_SKIP };
+
+struct tap_test {
+	const char *name;
+	enum tap_result (*fn)(void);
+};
+
+static const char *tap_skip_reason;
+static int tap_num, tap_failed, tap_skipped;
+
+#define ARRAY_SIZE(a) (sizeof(a) / sizeof((a)[0]))
+
+static void tap_diag(const char *fmt, ...)
+{
+	va_list ap;
+
+	printf("# ");
+	va_start(ap, fmt);
+	vprintf(fmt, ap);
+	va_end(ap);
+	printf("\n");
+	fflush(stdout);
+}
+
+#define CHECK(cond)							\
+	do {								\
+		if (!(cond)) {						\
+			tap_diag("%s:%d: %s failed (errno %s)",		\
+				 __func__, __LINE__, #cond,		\
+				 strerror(errno));			\
+			return TAP_FAIL;				\
+		}							\
+	} while (0)
+
+/* Skips the current test, giving why. */
+#define SKIP(why)							\
+	do {								\
+		tap_skip_reason = (why);				\
+		return TAP_SKIP;					\
+	} while (0)
+
+static void tap_plan(int count)
+{
+	printf("TAP version 13\n1..%d\n", count);
+	fflush(stdout);
+}
+
+static void tap_report(const char *name, enum tap_result result)
+{
+	tap_num++;
+	switch (result) {
+	case TAP_PASS:
+		printf("ok %d %s\n", tap_num, name);
+		break;
+	case TAP_SKIP:
+		tap_skipped++;
+		printf("ok %d %s # SKIP %s\n", tap_num, name,
+		       tap_skip_reason ? tap_skip_reason : "");
+		break;
+	default:
+		tap_failed++;
+		printf("not ok %d %s\n", tap_num, name);
+		break;
+	}
+	fflush(stdout);
+	tap_skip_reason = NULL;
+}
+
+/* Runs every test in the table and returns the process exit code. */
+static int tap_run(const struct tap_test *tests, int count)
+{
+	int i;
+
+	tap_plan(count);
+	for (i = 0; i < count; i++) {
+		errno = 0;
+		tap_report(tests[i].name, tests[i].fn());
+	}
+	tap_diag("%d passed, %d failed, %d skipped", count - tap_failed - tap_skipped,
+		 tap_failed, tap_skipped);
+	if (tap_failed)
+		return KSFT_FAIL;
+	return tap_skipped == count ? KSFT_SKIP : KSFT_PASS;
+}
+
+#endif /* SCULL_TAP_H */